@@ -0,0 +1,121 @@
+// Code/Data Logger
+//
+// Files are in the Mesen CDL format: "CDLv2", the CRC32 of the ROM (little
+// endian), then one flag byte per ROM byte. The code, data, jump target and
+// subroutine flags are Mesen's; graphics uses a bit Mesen has no meaning for
+// on the Game Boy.
+#![allow(dead_code)]
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+
+const MAGIC: &[u8; 5] = b"CDLv2";
+
+// Run as an instruction, opcode or operand
+pub const CODE: u8 = 0x01;
+// Read by the CPU or DMA
+pub const DATA: u8 = 0x02;
+// Reached by a jump
+pub const JUMP_TARGET: u8 = 0x04;
+// Reached by a CALL or RST
+pub const SUB_ENTRY_POINT: u8 = 0x08;
+// Copied to the VRAM tiles
+pub const GRAPHICS: u8 = 0x40;
+
+#[derive(Clone, Debug, Default)]
+pub struct Cdl {
+    crc: u32,
+    flags: Vec<u8>,
+}
+
+// CRC-32 (IEEE), as used by Mesen to match a CDL file with its ROM
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFF_u32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+// Bytes of a bank with each flag
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BankStats {
+    pub code: usize,
+    pub data: usize,
+    pub graphics: usize,
+    // Any flag
+    pub logged: usize,
+    pub size: usize,
+}
+
+impl Cdl {
+    pub fn new(rom: &[u8]) -> Cdl {
+        Cdl { crc: crc32(rom), flags: vec![0; rom.len()] }
+    }
+
+    // Go on with an existing file, which must be for the same ROM
+    pub fn load(path: &str, rom: &[u8]) -> io::Result<Cdl> {
+        let invalid = |text: String| io::Error::new(io::ErrorKind::InvalidData, text);
+        let mut data = vec![];
+        File::open(path)?.read_to_end(&mut data)?;
+        let header = MAGIC.len() + 4;
+        if data.len() < header || &data[..MAGIC.len()] != &MAGIC[..] {
+            return Err(invalid(String::from("not a CDLv2 file")));
+        }
+        let mut cdl = Cdl::new(rom);
+        let crc = data[5] as u32 | (data[6] as u32) << 8 | (data[7] as u32) << 16 | (data[8] as u32) << 24;
+        if crc != cdl.crc {
+            return Err(invalid(format!("CRC32 {:08X}, the ROM has {:08X}", crc, cdl.crc)));
+        }
+        if data.len() - header != rom.len() {
+            return Err(invalid(format!("{} bytes, the ROM has {}", data.len() - header, rom.len())));
+        }
+        cdl.flags.copy_from_slice(&data[header..]);
+        Ok(cdl)
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let mut f = File::create(path)?;
+        f.write_all(MAGIC)?;
+        f.write_all(&[self.crc as u8, (self.crc >> 8) as u8, (self.crc >> 16) as u8, (self.crc >> 24) as u8])?;
+        f.write_all(&self.flags)
+    }
+
+    pub fn mark(&mut self, offset: usize, flag: u8) {
+        if let Some(f) = self.flags.get_mut(offset) {
+            *f |= flag;
+        }
+    }
+
+    pub fn get(&self, offset: usize) -> u8 {
+        self.flags.get(offset).cloned().unwrap_or(0)
+    }
+
+    // Coverage of each 16KB bank
+    pub fn stats(&self) -> Vec<BankStats> {
+        self.flags.chunks(0x4000).map(|bank| {
+            let mut s = BankStats { size: bank.len(), ..Default::default() };
+            for &f in bank {
+                if f & CODE != 0 { s.code += 1; }
+                if f & DATA != 0 { s.data += 1; }
+                if f & GRAPHICS != 0 { s.graphics += 1; }
+                if f != 0 { s.logged += 1; }
+            }
+            s
+        }).collect()
+    }
+
+    pub fn format_stats(&self) -> String {
+        let percent = |n: usize, size: usize| n as f64 * 100.0 / size as f64;
+        let mut out = String::from("Bank   Code   Data   Graphics  Logged\n");
+        for (i, s) in self.stats().iter().enumerate() {
+            out += &format!("{:02X}   {:5.1}% {:5.1}%  {:5.1}%    {:5.1}%\n", i,
+                            percent(s.code, s.size), percent(s.data, s.size),
+                            percent(s.graphics, s.size), percent(s.logged, s.size));
+        }
+        out
+    }
+}
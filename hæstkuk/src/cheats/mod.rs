@@ -0,0 +1,192 @@
+// Cheat codes:
+//
+// GameShark  01VVAAAA     writes VV at AAAA (byte swapped) every frame.
+//                         8x/9x instead of 01 selects the CGB WRAM or SRAM bank x.
+// Game Genie ABC-DEF-GHI  returns AB instead of the ROM byte at address FCDE
+//                         (F xored with F), only if it was GI (xored with BA,
+//                         rotated left by 2). ABC-DEF patches it whatever it was.
+//
+// Cheat files hold one code per line, with an optional name. A '!' before
+// the code loads it disabled:
+//
+// ; comment
+// 010138CD Infinite money
+// !00A-17B-C49 Walk through walls
+#![allow(dead_code)]
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Code {
+    // RAM write, in the given bank or the mapped one
+    GameShark { bank: Option<u16>, addr: u16, value: u8 },
+    // ROM read patch
+    GameGenie { addr: u16, value: u8, compare: Option<u8> },
+}
+
+impl Code {
+    pub fn parse(s: &str) -> Result<Code, String> {
+        let digits: String = s.chars().filter(|&c| c != '-').collect();
+        let invalid = || format!("invalid cheat code '{}'", s);
+        if !digits.chars().all(|c| c.is_digit(16)) {
+            return Err(invalid());
+        }
+        let hex = |i: usize, n: usize| u16::from_str_radix(&digits[i..i+n], 16).unwrap();
+        match digits.len() {
+            8 if !s.contains('-') => {
+                let bank = match hex(0, 2) {
+                    0x00 | 0x01 => None,
+                    t @ 0x80..=0x87 | t @ 0x90..=0x97 => Some(t & 0x07),
+                    _ => return Err(format!("unknown GameShark code type in '{}'", s)),
+                };
+                let addr = hex(6, 2) << 8 | hex(4, 2);
+                if addr < 0x8000 {
+                    return Err(format!("GameShark code '{}' writes to ROM", s));
+                }
+                Ok(Code::GameShark { bank: bank, addr: addr, value: hex(2, 2) as u8 })
+            },
+            6 | 9 => {
+                let addr = (hex(5, 1) ^ 0xF) << 12 | hex(2, 1) << 8 | hex(3, 1) << 4 | hex(4, 1);
+                if addr >= 0x8000 {
+                    return Err(format!("Game Genie code '{}' patches {:04X}, outside of the ROM", s, addr));
+                }
+                let compare = if digits.len() == 9 {
+                    Some((hex(6, 1) << 4 | hex(8, 1)) as u8)
+                } else {
+                    None
+                };
+                Ok(Code::GameGenie {
+                    addr: addr,
+                    value: hex(0, 2) as u8,
+                    compare: compare.map(|c| c.rotate_right(2) ^ 0xBA),
+                })
+            },
+            _ => Err(invalid()),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Cheat {
+    pub code: Code,
+    // As typed, and its name from the cheat file
+    pub text: String,
+    pub name: String,
+    pub enabled: bool,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Cheats {
+    cheats: Vec<Cheat>,
+    // Switch for all of them at once
+    disabled: bool,
+}
+
+impl Cheats {
+    pub fn new() -> Cheats {
+        Default::default()
+    }
+
+    // Invalid lines are reported and skipped
+    pub fn load(path: &str) -> io::Result<Cheats> {
+        let mut cheats = Cheats::new();
+        let file = BufReader::new(File::open(path)?);
+        for (n, line) in file.lines().enumerate() {
+            let line = line?;
+            let line = match line.find(';') {
+                Some(i) => &line[..i],
+                None => &line[..],
+            };
+            let mut parts = line.trim().splitn(2, char::is_whitespace);
+            let code = match parts.next() {
+                Some(c) if !c.is_empty() => c,
+                _ => continue,
+            };
+            let name = parts.next().unwrap_or("").trim();
+            let (code, enabled) = if code.starts_with('!') { (&code[1..], false) } else { (code, true) };
+            match cheats.add(code, name) {
+                Ok(i) => cheats.cheats[i].enabled = enabled,
+                Err(e) => println!("{}:{}: {}", path, n + 1, e),
+            }
+        }
+        Ok(cheats)
+    }
+
+    // Enabled, returns its index
+    pub fn add(&mut self, code: &str, name: &str) -> Result<usize, String> {
+        self.cheats.push(Cheat {
+            code: Code::parse(code)?,
+            text: code.to_uppercase(),
+            name: name.to_string(),
+            enabled: true,
+        });
+        Ok(self.cheats.len() - 1)
+    }
+
+    pub fn remove(&mut self, i: usize) -> Option<Cheat> {
+        if i < self.cheats.len() { Some(self.cheats.remove(i)) } else { None }
+    }
+
+    // Returns the new state, None without such a cheat
+    pub fn toggle(&mut self, i: usize) -> Option<bool> {
+        self.cheats.get_mut(i).map(|c| {
+            c.enabled = !c.enabled;
+            c.enabled
+        })
+    }
+
+    pub fn is_active(&self) -> bool {
+        !self.disabled
+    }
+    pub fn toggle_active(&mut self) -> bool {
+        self.disabled = !self.disabled;
+        !self.disabled
+    }
+
+    pub fn len(&self) -> usize {
+        self.cheats.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.cheats.is_empty()
+    }
+    pub fn iter(&self) -> ::std::slice::Iter<'_, Cheat> {
+        self.cheats.iter()
+    }
+
+    fn enabled<'a>(&'a self) -> impl Iterator<Item = &'a Code> + 'a {
+        let active = !self.disabled;
+        self.cheats.iter().filter(move |c| active && c.enabled).map(|c| &c.code)
+    }
+
+    // Game Genie: the value the CPU reads at a ROM address instead of v
+    pub fn patch(&self, addr: u16, v: u8) -> u8 {
+        for code in self.enabled() {
+            if let Code::GameGenie { addr: a, value, compare } = *code {
+                if a == addr && compare.map_or(true, |c| c == v) {
+                    return value;
+                }
+            }
+        }
+        v
+    }
+
+    // GameShark: the (bank, address, value) writes to do this frame
+    pub fn writes(&self) -> Vec<(Option<u16>, u16, u8)> {
+        self.enabled().filter_map(|code| match *code {
+            Code::GameShark { bank, addr, value } => Some((bank, addr, value)),
+            _ => None,
+        }).collect()
+    }
+
+    pub fn format(&self) -> String {
+        let mut out = String::new();
+        for (i, c) in self.cheats.iter().enumerate() {
+            out += &format!("{:3} {} {:12} {}\n", i, if c.enabled { "on " } else { "off" }, c.text, c.name);
+        }
+        if self.disabled {
+            out += "All cheats are switched off (F10)\n";
+        }
+        out
+    }
+}
@@ -0,0 +1,69 @@
+// Configuration file
+//
+// Plain text, one `key = value` per line grouped in `[sections]`.
+// Lines starting with '#' or ';' are comments.
+#![allow(dead_code)]
+use std::io;
+use std::fs::File;
+use std::io::Read;
+
+pub const DEFAULT_PATH: &str = "./haestkuk.cfg";
+
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    // (section, key, value), in file order
+    entries: Vec<(String, String, String)>,
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            entries: vec![],
+        }
+    }
+
+    pub fn load(path: &str) -> io::Result<Config> {
+        let mut f = File::open(path)?;
+        let mut text = String::new();
+        f.read_to_string(&mut text)?;
+        Ok(Config::parse(&text))
+    }
+
+    pub fn parse(text: &str) -> Config {
+        let mut config = Config::new();
+        let mut section = String::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                section = line[1..line.len()-1].trim().to_lowercase();
+                continue;
+            }
+            match line.find('=') {
+                Some(i) => {
+                    let key   = line[..i].trim().to_lowercase();
+                    let value = line[i+1..].trim().to_string();
+                    config.entries.push((section.clone(), key, value));
+                },
+                None => println!("Config line {} ignored: '{}'", n+1, line),
+            }
+        }
+        config
+    }
+
+    // Last value of a key, so later lines override earlier ones
+    pub fn get(&self, section: &str, key: &str) -> Option<&str> {
+        self.entries.iter().rev()
+            .find(|e| e.0 == section && e.1 == key)
+            .map(|e| e.2.as_str())
+    }
+
+    pub fn section(&self, section: &str) -> Vec<(String, String)> {
+        self.entries.iter()
+            .filter(|e| e.0 == section)
+            .map(|e| (e.1.clone(), e.2.clone()))
+            .collect()
+    }
+}
@@ -0,0 +1,623 @@
+// Debugger: breakpoints, watchpoints, stepping and a command interpreter.
+//
+// check() is called before each instruction and tells when to stop, the
+// frontend then feeds command() with lines until it asks to resume.
+#![allow(dead_code)]
+use std::marker::PhantomData;
+use lr35902::Cpu;
+use mem::{Watch, WatchHit};
+use disasm;
+use search::{RamSearch, Filter};
+
+// Address, optionally in a given bank, written "4A20" or "03:4A20"
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Address {
+    pub bank: Option<u16>,
+    pub addr: u16,
+}
+
+impl Address {
+    pub fn parse(s: &str) -> Result<Address, String> {
+        match s.find(':') {
+            Some(i) => Ok(Address { bank: Some(parse_hex(&s[..i])?), addr: parse_hex(&s[i+1..])? }),
+            None    => Ok(Address { bank: None, addr: parse_hex(s)? }),
+        }
+    }
+
+    pub fn matches(&self, cpu: &Cpu, addr: u16) -> bool {
+        self.addr == addr && self.bank.map_or(true, |b| b == cpu.mem.get_bank(addr))
+    }
+
+    pub fn to_string(&self) -> String {
+        match self.bank {
+            Some(b) => format!("{:02X}:{:04X}", b, self.addr),
+            None    => format!("{:04X}", self.addr),
+        }
+    }
+}
+
+// Hex number, with an optional "$" or "0x" prefix
+pub fn parse_hex(s: &str) -> Result<u16, String> {
+    let hex = s.trim_start_matches('$').trim_start_matches("0x").trim_start_matches("0X");
+    u16::from_str_radix(hex, 16).map_err(|_| format!("invalid number '{}'", s))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compare {
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+// Register compared to a value, like "a==3f" or "hl>=c000"
+#[derive(Clone, Debug)]
+pub struct Condition {
+    pub reg: String,
+    pub compare: Compare,
+    pub value: u16,
+}
+
+impl Compare {
+    pub fn symbol(&self) -> &'static str {
+        match *self {
+            Compare::Equal        => "==",
+            Compare::NotEqual     => "!=",
+            Compare::Less         => "<",
+            Compare::LessEqual    => "<=",
+            Compare::Greater      => ">",
+            Compare::GreaterEqual => ">=",
+        }
+    }
+}
+
+impl Condition {
+    pub fn parse(s: &str) -> Result<Condition, String> {
+        let ops = [("==", Compare::Equal), ("!=", Compare::NotEqual), ("<=", Compare::LessEqual),
+                   (">=", Compare::GreaterEqual), ("<", Compare::Less), (">", Compare::Greater),
+                   ("=", Compare::Equal)];
+        for &(op, compare) in ops.iter() {
+            if let Some(i) = s.find(op) {
+                return Ok(Condition {
+                    reg: s[..i].trim().to_uppercase(),
+                    compare: compare,
+                    value: parse_hex(s[i+op.len()..].trim())?,
+                });
+            }
+        }
+        Err(format!("invalid condition '{}'", s))
+    }
+
+    pub fn eval(&self, cpu: &Cpu) -> bool {
+        let v = match cpu.get_reg(&self.reg) {
+            Some(v) => v,
+            None => return false,
+        };
+        match self.compare {
+            Compare::Equal        => v == self.value,
+            Compare::NotEqual     => v != self.value,
+            Compare::Less         => v <  self.value,
+            Compare::LessEqual    => v <= self.value,
+            Compare::Greater      => v >  self.value,
+            Compare::GreaterEqual => v >= self.value,
+        }
+    }
+}
+
+// Breakpoint on PC, a condition, or both
+#[derive(Clone, Debug)]
+pub struct Breakpoint {
+    pub addr: Option<Address>,
+    pub condition: Option<Condition>,
+}
+
+// Watchpoint on an address range, execute ones being checked against PC
+#[derive(Clone, Copy, Debug)]
+pub struct Watchpoint {
+    pub bank: Option<u16>,
+    pub start: u16,
+    pub end: u16,
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mode {
+    Run,
+    Break,
+    // Instructions left to run, the resumed one included
+    Step(u32),
+    // Run until PC is there with SP at least this one
+    RunTo(Address, u16),
+    // Run until the call stack is shallower than this, once the current frame returned
+    StepOut(usize),
+}
+
+// Why the emulation stopped
+#[derive(Clone, Copy, Debug)]
+pub enum Stop {
+    // Break requested
+    Break,
+    // End of a step, step over, step out or run until
+    Step,
+    Breakpoint(usize),
+    Watch(WatchHit),
+    // Watchpoint number, bank and PC
+    Execute(usize, u16, u16),
+}
+
+impl Stop {
+    pub fn describe(&self) -> String {
+        match *self {
+            Stop::Break         => String::from("Break"),
+            Stop::Step          => String::new(),
+            Stop::Breakpoint(n) => format!("Breakpoint {}", n),
+            Stop::Watch(hit)    => format!("Watchpoint: {} {:02X} at {:02X}:{:04X}",
+                                           if hit.write { "write" } else { "read" }, hit.value, hit.bank, hit.addr),
+            Stop::Execute(n, bank, pc) => format!("Watchpoint {}: execute at {:02X}:{:04X}", n, bank, pc),
+        }
+    }
+}
+
+pub enum Action {
+    Prompt,
+    Resume,
+    Quit,
+}
+
+pub struct Debugger<'a> {
+    breakpoints: Vec<Option<Breakpoint>>,
+    watchpoints: Vec<Option<Watchpoint>>,
+    mode: Mode,
+    // Don't stop again on the instruction we resume from
+    resume_pc: Option<u16>,
+    last_command: String,
+    search: Option<RamSearch>,
+    phantom: PhantomData<&'a u8>,
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new() -> Debugger<'a> {
+        Debugger {
+            breakpoints: vec![],
+            watchpoints: vec![],
+            mode: Mode::Run,
+            resume_pc: None,
+            last_command: String::new(),
+            search: None,
+            phantom: PhantomData,
+        }
+    }
+
+    // Stop before the next instruction
+    pub fn request_break(&mut self) {
+        self.mode = Mode::Break;
+    }
+
+    pub fn add_breakpoint(&mut self, bp: Breakpoint) -> usize {
+        self.breakpoints.push(Some(bp));
+        self.breakpoints.len() - 1
+    }
+    pub fn add_watchpoint(&mut self, cpu: &mut Cpu<'a>, wp: Watchpoint) -> usize {
+        self.watchpoints.push(Some(wp));
+        self.sync_watches(cpu);
+        1000 + self.watchpoints.len() - 1
+    }
+    // Watchpoints are numbered from 1000
+    pub fn delete(&mut self, cpu: &mut Cpu<'a>, n: usize) -> Result<(), String> {
+        if n < self.breakpoints.len() && self.breakpoints[n].is_some() {
+            self.breakpoints[n] = None;
+            return Ok(());
+        }
+        let w = n.wrapping_sub(1000);
+        if w < self.watchpoints.len() && self.watchpoints[w].is_some() {
+            self.watchpoints[w] = None;
+            self.sync_watches(cpu);
+            return Ok(());
+        }
+        Err(format!("no breakpoint or watchpoint {}", n))
+    }
+
+    // Read and write watchpoints are checked by the memory controller
+    fn sync_watches(&mut self, cpu: &mut Cpu<'a>) {
+        let watches = self.watchpoints.iter().filter_map(|w| *w)
+            .filter(|w| w.read || w.write)
+            .map(|w| Watch { bank: w.bank, start: w.start, end: w.end, read: w.read, write: w.write })
+            .collect();
+        cpu.mem.set_watches(watches);
+    }
+
+    // Called before each instruction, returns why the emulation must stop
+    pub fn check(&mut self, cpu: &mut Cpu<'a>) -> Option<Stop> {
+        let pc = cpu.get_pc();
+        let resuming = self.resume_pc.take() == Some(pc);
+
+        if let Some(hit) = cpu.mem.take_watch_hit() {
+            self.mode = Mode::Break;
+            return Some(Stop::Watch(hit));
+        }
+
+        match self.mode {
+            Mode::Run => {},
+            Mode::Break => return Some(Stop::Break),
+            Mode::Step(n) if n <= 1 => {
+                self.mode = Mode::Break;
+                return Some(Stop::Step);
+            },
+            Mode::Step(n) => self.mode = Mode::Step(n-1),
+            Mode::RunTo(addr, sp) => {
+                if addr.matches(cpu, pc) && cpu.get_sp() >= sp {
+                    self.mode = Mode::Break;
+                    return Some(Stop::Step);
+                }
+            },
+            Mode::StepOut(depth) => {
+                if cpu.get_call_stack().len() < depth {
+                    self.mode = Mode::Break;
+                    return Some(Stop::Step);
+                }
+            },
+        }
+        if resuming {
+            return None;
+        }
+
+        for (i, bp) in self.breakpoints.iter().enumerate() {
+            if let Some(ref bp) = *bp {
+                let at = bp.addr.map_or(true, |a| a.matches(cpu, pc));
+                if at && bp.condition.as_ref().map_or(true, |c| c.eval(cpu)) {
+                    self.mode = Mode::Break;
+                    return Some(Stop::Breakpoint(i));
+                }
+            }
+        }
+        let bank = cpu.mem.get_bank(pc);
+        for (i, wp) in self.watchpoints.iter().enumerate() {
+            if let Some(ref wp) = *wp {
+                if wp.execute && pc >= wp.start && pc <= wp.end && wp.bank.map_or(true, |b| b == bank) {
+                    self.mode = Mode::Break;
+                    return Some(Stop::Execute(1000+i, bank, pc));
+                }
+            }
+        }
+        None
+    }
+
+    // Resume the emulation
+    pub fn run(&mut self, cpu: &Cpu<'a>) -> Action {
+        self.resume(cpu, Mode::Run)
+    }
+    pub fn step(&mut self, cpu: &Cpu<'a>, n: u32) -> Action {
+        self.resume(cpu, Mode::Step(n))
+    }
+
+    fn resume(&mut self, cpu: &Cpu<'a>, mode: Mode) -> Action {
+        self.mode = mode;
+        self.resume_pc = Some(cpu.get_pc());
+        Action::Resume
+    }
+
+    // Instruction at addr and its length
+    pub fn disassemble(cpu: &mut Cpu<'a>, bank: Option<u16>, addr: u16) -> (String, u16) {
+        let bytes: Vec<u8> = (0..3).map(|i| cpu.mem.peek_bank8(bank, addr.wrapping_add(i))).collect();
+        let instr = disasm::decode(&bytes);
+        let mut text = instr.format(addr);
+
+        // Jump and call targets, in the same bank when in the same area
+        if let Some(target) = instr.target(addr) {
+            let same_area = (target & 0xC000) == (addr & 0xC000);
+            let bank = Debugger::symbol_bank(cpu, if same_area { bank } else { None }, target);
+            if let Some(label) = cpu.get_symbols().describe(bank, target) {
+                text = format!("{} <{}>", text, label);
+            }
+        }
+        (text, instr.len)
+    }
+
+    // "label:" line when there's one at addr
+    fn print_label(cpu: &Cpu<'a>, bank: Option<u16>, addr: u16) {
+        if let Some(label) = cpu.get_symbols().label(Debugger::symbol_bank(cpu, bank, addr), addr) {
+            println!("{}:", label);
+        }
+    }
+
+    pub fn print_location(cpu: &mut Cpu<'a>) {
+        let pc = cpu.get_pc();
+        Debugger::print_label(cpu, None, pc);
+        let (text, len) = Debugger::disassemble(cpu, None, pc);
+        let bytes: Vec<String> = (0..len).map(|i| format!("{:02X}", cpu.mem.peek8(pc.wrapping_add(i)))).collect();
+        println!("{:02X}:{:04X}  {:<9} {}", cpu.mem.get_bank(pc), pc, bytes.join(" "), text);
+    }
+
+    pub fn print_registers(cpu: &mut Cpu<'a>) {
+        let f = cpu.get_reg("F").unwrap();
+        let flag = |bit: u16, c: char| if f & (1<<bit) != 0 { c } else { '-' };
+        println!("AF={:04X} BC={:04X} DE={:04X} HL={:04X} SP={:04X} PC={:04X} {}{}{}{} IME={}{}",
+                 cpu.get_reg("AF").unwrap(), cpu.get_reg("BC").unwrap(), cpu.get_reg("DE").unwrap(),
+                 cpu.get_reg("HL").unwrap(), cpu.get_reg("SP").unwrap(), cpu.get_reg("PC").unwrap(),
+                 flag(7, 'Z'), flag(6, 'N'), flag(5, 'H'), flag(4, 'C'),
+                 cpu.interrupts_enabled() as u8, if cpu.is_halted() { " HALTED" } else { "" });
+    }
+
+    // Register name or hex number
+    fn parse_value(cpu: &Cpu<'a>, s: &str) -> Result<u16, String> {
+        match cpu.get_reg(s) {
+            Some(v) => Ok(v),
+            None    => parse_hex(s),
+        }
+    }
+    // Register name, label or address
+    fn parse_address(cpu: &Cpu<'a>, s: &str) -> Result<Address, String> {
+        if let Some(v) = cpu.get_reg(s) {
+            return Ok(Address { bank: None, addr: v });
+        }
+        if let Some((bank, addr)) = cpu.get_symbols().lookup(s) {
+            let banked = cpu.mem.get_bank_count(addr) > 1;
+            return Ok(Address { bank: if banked { Some(bank) } else { None }, addr: addr });
+        }
+        Address::parse(s)
+    }
+
+    // Bank of addr for the symbols, the given one or the mapped one
+    fn symbol_bank(cpu: &Cpu<'a>, bank: Option<u16>, addr: u16) -> Option<u16> {
+        if cpu.mem.get_bank_count(addr) > 1 { bank.or(Some(cpu.mem.get_bank(addr))) } else { None }
+    }
+
+    // Run a command line, an empty one repeating the last command
+    pub fn command(&mut self, cpu: &mut Cpu<'a>, line: &str) -> Result<Action, String> {
+        let line = if line.trim().is_empty() { self.last_command.clone() } else { line.trim().to_string() };
+        self.last_command = line.clone();
+        let args: Vec<&str> = line.split_whitespace().collect();
+        if args.is_empty() {
+            return Ok(Action::Prompt);
+        }
+        let arg = |i: usize| -> Result<&str, String> {
+            args.get(i).cloned().ok_or_else(|| format!("{}: missing argument", args[0]))
+        };
+
+        match args[0] {
+            "h" | "help" | "?" => println!("{}", HELP),
+            "c" | "continue" => return Ok(self.run(cpu)),
+            "s" | "step" => {
+                let n = match args.get(1) { Some(n) => n.parse().map_err(|_| format!("invalid count '{}'", n))?, None => 1 };
+                return Ok(self.step(cpu, n));
+            },
+            "n" | "next" => {
+                let pc = cpu.get_pc();
+                let bytes: Vec<u8> = (0..3).map(|i| cpu.mem.peek8(pc.wrapping_add(i))).collect();
+                let instr = disasm::decode(&bytes);
+                if instr.is_call() {
+                    let to = Address { bank: None, addr: pc.wrapping_add(instr.len) };
+                    let sp = cpu.get_sp();
+                    return Ok(self.resume(cpu, Mode::RunTo(to, sp)));
+                }
+                return Ok(self.step(cpu, 1));
+            },
+            "o" | "out" | "finish" => {
+                let depth = cpu.get_call_stack().len();
+                if depth == 0 {
+                    return Err(String::from("out: no known caller, use until"));
+                }
+                return Ok(self.resume(cpu, Mode::StepOut(depth)));
+            },
+            "u" | "until" => {
+                let to = Debugger::parse_address(cpu, arg(1)?)?;
+                return Ok(self.resume(cpu, Mode::RunTo(to, 0)));
+            },
+            "b" | "break" => {
+                if args.len() == 1 {
+                    self.list(cpu);
+                    return Ok(Action::Prompt);
+                }
+                // b <addr> [if <cond>] or b if <cond>
+                let (addr, cond) = if args[1] == "if" {
+                    (None, Some(args[2..].join("")))
+                } else if args.get(2) == Some(&"if") {
+                    (Some(Debugger::parse_address(cpu, args[1])?), Some(args[3..].join("")))
+                } else {
+                    (Some(Debugger::parse_address(cpu, args[1])?), None)
+                };
+                let condition = match cond {
+                    Some(c) => Some(Condition::parse(&c)?),
+                    None => None,
+                };
+                let n = self.add_breakpoint(Breakpoint { addr: addr, condition: condition });
+                println!("Breakpoint {}", n);
+            },
+            "w" | "watch" => {
+                // w <start>[-<end>|+<len>] [r|w|rw|x]
+                let range = arg(1)?;
+                let (start, end) = if let Some(i) = range.find('-') {
+                    let start = Address::parse(&range[..i])?;
+                    (start, parse_hex(&range[i+1..])?)
+                } else if let Some(i) = range.find('+') {
+                    let start = Address::parse(&range[..i])?;
+                    let end = start.addr.checked_add(parse_hex(&range[i+1..])?.max(1) - 1)
+                        .ok_or_else(|| format!("invalid range '{}', past FFFF", range))?;
+                    (start, end)
+                } else {
+                    let start = Address::parse(range)?;
+                    (start, start.addr)
+                };
+                if end < start.addr {
+                    return Err(format!("invalid range '{}', the end is before the start", range));
+                }
+                let kind = args.get(2).cloned().unwrap_or("w");
+                if !["r", "w", "rw", "x"].contains(&kind) {
+                    return Err(format!("invalid watchpoint kind '{}'", kind));
+                }
+                let wp = Watchpoint {
+                    bank: start.bank,
+                    start: start.addr,
+                    end: end,
+                    read: kind.contains('r'),
+                    write: kind.contains('w'),
+                    execute: kind == "x",
+                };
+                let n = self.add_watchpoint(cpu, wp);
+                println!("Watchpoint {}", n);
+            },
+            "d" | "delete" => {
+                let n = arg(1)?.parse().map_err(|_| format!("invalid number '{}'", args[1]))?;
+                self.delete(cpu, n)?;
+            },
+            "r" | "regs" => {
+                if args.len() == 3 {
+                    let v = Debugger::parse_value(cpu, args[2])?;
+                    if !cpu.set_reg(args[1], v) {
+                        return Err(format!("unknown register '{}'", args[1]));
+                    }
+                }
+                Debugger::print_registers(cpu);
+            },
+            "bt" | "backtrace" => {
+                for l in cpu.backtrace() {
+                    println!("{}", l);
+                }
+            },
+            "ch" | "cheat" => {
+                // ch, ch <n> to toggle one, ch + <code> [name], ch - <n>
+                match args.get(1).cloned() {
+                    None => {},
+                    Some("+") => {
+                        let name = args.iter().skip(3).cloned().collect::<Vec<&str>>().join(" ");
+                        let n = cpu.mem.get_cheats_mut().add(arg(2)?, &name)?;
+                        println!("Cheat {}", n);
+                    },
+                    Some("-") => {
+                        let n = arg(2)?.parse().map_err(|_| format!("invalid number '{}'", args[2]))?;
+                        cpu.mem.get_cheats_mut().remove(n).ok_or_else(|| format!("no cheat {}", n))?;
+                    },
+                    Some(n) => {
+                        let n = n.parse().map_err(|_| format!("invalid number '{}'", n))?;
+                        cpu.mem.get_cheats_mut().toggle(n).ok_or_else(|| format!("no cheat {}", n))?;
+                    },
+                }
+                print!("{}", cpu.mem.get_cheats().format());
+            },
+            "sr" | "search" => {
+                // sr new [8|16] [s], sr <eq|ne|inc|dec|value>, sr list [n]
+                let mut max = 20;
+                match args.get(1).cloned() {
+                    None => {},
+                    Some("new") => {
+                        let word = args.contains(&"16");
+                        let signed = args.contains(&"s");
+                        self.search = Some(RamSearch::new(&mut cpu.mem, word, signed));
+                    },
+                    Some("list") => {
+                        max = match args.get(2) { Some(n) => n.parse().map_err(|_| format!("invalid count '{}'", n))?, None => usize::max_value() };
+                    },
+                    Some(f) => {
+                        let filter = Filter::parse(f)?;
+                        let search = self.search.as_mut().ok_or("no search, start one with sr new")?;
+                        search.filter(&mut cpu.mem, filter);
+                    },
+                }
+                let search = self.search.as_ref().ok_or("no search, start one with sr new")?;
+                print!("{}", search.format(&mut cpu.mem, max));
+            },
+            "m" | "mem" => {
+                let start = Debugger::parse_address(cpu, arg(1)?)?;
+                let len = match args.get(2) { Some(l) => parse_hex(l)?, None => 0x40 };
+                let mut line = String::new();
+                for i in 0..len {
+                    let addr = start.addr.wrapping_add(i);
+                    if i%16 == 0 {
+                        if !line.is_empty() {
+                            println!("{}", line);
+                        }
+                        let bank = start.bank.unwrap_or(cpu.mem.get_bank(addr));
+                        line = format!("{:02X}:{:04X} ", bank, addr);
+                    }
+                    line += &format!(" {:02X}", cpu.mem.peek_bank8(start.bank, addr));
+                }
+                println!("{}", line);
+            },
+            "p" | "poke" => {
+                let start = Debugger::parse_address(cpu, arg(1)?)?;
+                arg(2)?;
+                // Nothing is written unless all the bytes are valid
+                let mut bytes = vec![];
+                for v in args[2..].iter() {
+                    let b = parse_hex(v).ok().filter(|&b| b <= 0xFF).ok_or_else(|| format!("invalid byte '{}'", v))?;
+                    bytes.push(b as u8);
+                }
+                for (i, &v) in bytes.iter().enumerate() {
+                    cpu.mem.poke_bank8(start.bank, start.addr.wrapping_add(i as u16), v);
+                }
+            },
+            "x" | "dis" => {
+                let start = match args.get(1) {
+                    Some(a) => Debugger::parse_address(cpu, a)?,
+                    None => Address { bank: None, addr: cpu.get_pc() },
+                };
+                let count = match args.get(2) { Some(n) => n.parse().map_err(|_| format!("invalid count '{}'", n))?, None => 10 };
+                let mut addr = start.addr;
+                for _ in 0..count {
+                    Debugger::print_label(cpu, start.bank, addr);
+                    let (text, len) = Debugger::disassemble(cpu, start.bank, addr);
+                    let bank = start.bank.unwrap_or(cpu.mem.get_bank(addr));
+                    println!("{:02X}:{:04X}  {}", bank, addr, text);
+                    addr = addr.wrapping_add(len);
+                }
+            },
+            "q" | "quit" => return Ok(Action::Quit),
+            _ => return Err(format!("unknown command '{}', try help", args[0])),
+        }
+        Ok(Action::Prompt)
+    }
+
+    fn list(&self, cpu: &Cpu<'a>) {
+        for (i, bp) in self.breakpoints.iter().enumerate() {
+            if let Some(ref bp) = *bp {
+                let at = bp.addr.map_or(String::from("any"), |a| a.to_string());
+                let label = bp.addr.and_then(|a| cpu.get_symbols().describe(Debugger::symbol_bank(cpu, a.bank, a.addr), a.addr));
+                let at = match label {
+                    Some(l) => format!("{} <{}>", at, l),
+                    None => at,
+                };
+                match bp.condition {
+                    Some(ref c) => println!("{:4}  break {} if {}{}{:X}", i, at, c.reg, c.compare.symbol(), c.value),
+                    None        => println!("{:4}  break {}", i, at),
+                }
+            }
+        }
+        for (i, wp) in self.watchpoints.iter().enumerate() {
+            if let Some(ref wp) = *wp {
+                let kind = if wp.execute { "x" } else if wp.read && wp.write { "rw" } else if wp.read { "r" } else { "w" };
+                let bank = wp.bank.map_or(String::new(), |b| format!("{:02X}:", b));
+                println!("{:4}  watch {}{:04X}-{:04X} {}", 1000+i, bank, wp.start, wp.end, kind);
+            }
+        }
+    }
+}
+
+const HELP: &str = "\
+Addresses are hex, optionally with a bank (03:4A20), a label or a register name
+  c                         continue
+  s [n]                     step n instructions
+  n                         step over calls
+  o                         step out of the current function
+  u <addr>                  run until PC reaches addr
+  b                         list breakpoints and watchpoints
+  b <addr> [if <cond>]      break at addr, optionally when cond is true (a==3f, hl>=c000)
+  b if <cond>               break anywhere when cond is true
+  w <addr>[-end|+len] [r|w|rw|x]  watch reads, writes (default) or execution
+  d <n>                     delete breakpoint or watchpoint n
+  r [<reg> <value>]         show registers, or set one
+  bt                        backtrace
+  ch [n]                    list cheats, or toggle cheat n
+  ch + <code> [name]        add a GameShark or Game Genie code
+  ch - <n>                  remove cheat n
+  m <addr> [len]            show memory
+  sr new [8|16] [s]         start a RAM search, on bytes or words, unsigned or signed
+  sr <eq|ne|inc|dec|value>  keep the values equal, changed, increased or decreased
+                            since the last search, or equal to value
+  sr [list [n]]             list the candidates, 20 or n of them
+  p <addr> <byte>...        write memory, ROM included
+  x [addr] [n]              disassemble n instructions
+  q                         quit";
@@ -0,0 +1,346 @@
+// LR35902 disassembler
+//
+// Instructions are decoded from their bytes alone, without any CPU or
+// memory state. Opcodes are split in fields: xx yyy zzz, yyy being pp q.
+#![allow(dead_code)]
+use std::collections::{BTreeMap, BTreeSet};
+use symbols::Symbols;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operand {
+    // A, HL, SP...
+    Register(&'static str),
+    // (HL), (BC), (HL+), (C)...
+    Indirect(&'static str),
+    Imm8(u8),
+    // Also the target of JP and CALL
+    Imm16(u16),
+    // (a16)
+    Address(u16),
+    // (a8), in 0xFF00-0xFFFF
+    High(u8),
+    // JR offset
+    Relative(i8),
+    // ADD SP, r8
+    Signed(i8),
+    // LD HL, SP+r8
+    SpOffset(i8),
+    Condition(&'static str),
+    Bit(u8),
+    // RST target
+    Vector(u8),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Instruction {
+    pub mnemonic: &'static str,
+    pub operands: Vec<Operand>,
+    // In bytes, the CB prefix included
+    pub len: u16,
+}
+
+const R: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+const RP: [&str; 4] = ["BC", "DE", "HL", "SP"];
+const RP2: [&str; 4] = ["BC", "DE", "HL", "AF"];
+const CC: [&str; 4] = ["NZ", "Z", "NC", "C"];
+const ALU: [&str; 8] = ["ADD", "ADC", "SUB", "SBC", "AND", "XOR", "OR", "CP"];
+const ROT: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SWAP", "SRL"];
+const MISC: [&str; 8] = ["RLCA", "RRCA", "RLA", "RRA", "DAA", "CPL", "SCF", "CCF"];
+
+fn r(i: u8) -> Operand {
+    if i == 6 { Operand::Indirect("HL") } else { Operand::Register(R[i as usize]) }
+}
+
+// Decode the instruction at the start of bytes, missing bytes being 0
+pub fn decode(bytes: &[u8]) -> Instruction {
+    let byte = |i: usize| bytes.get(i).cloned().unwrap_or(0);
+    let op = byte(0);
+    let d8 = byte(1);
+    let d16 = ((byte(2) as u16) << 8) | byte(1) as u16;
+    let (x, y, z) = (op >> 6, (op >> 3) & 7, op & 7);
+    let (p, q) = ((y >> 1) as usize, y & 1);
+
+    use self::Operand::*;
+    let (mnemonic, operands): (&'static str, Vec<Operand>) = match (x, z) {
+        (0, 0) => match y {
+            0 => ("NOP", vec![]),
+            1 => ("LD", vec![Address(d16), Register("SP")]),
+            2 => ("STOP", vec![]),
+            3 => ("JR", vec![Relative(d8 as i8)]),
+            _ => ("JR", vec![Condition(CC[y as usize - 4]), Relative(d8 as i8)]),
+        },
+        (0, 1) if q == 0 => ("LD", vec![Register(RP[p]), Imm16(d16)]),
+        (0, 1) => ("ADD", vec![Register("HL"), Register(RP[p])]),
+        (0, 2) => {
+            let mem = Indirect(["BC", "DE", "HL+", "HL-"][p]);
+            if q == 0 { ("LD", vec![mem, Register("A")]) } else { ("LD", vec![Register("A"), mem]) }
+        },
+        (0, 3) => (if q == 0 { "INC" } else { "DEC" }, vec![Register(RP[p])]),
+        (0, 4) => ("INC", vec![r(y)]),
+        (0, 5) => ("DEC", vec![r(y)]),
+        (0, 6) => ("LD", vec![r(y), Imm8(d8)]),
+        (0, _) => (MISC[y as usize], vec![]),
+        (1, _) if y == 6 && z == 6 => ("HALT", vec![]),
+        (1, _) => ("LD", vec![r(y), r(z)]),
+        (2, _) => alu(y, r(z)),
+        (3, 0) => match y {
+            0..=3 => ("RET", vec![Condition(CC[y as usize])]),
+            4 => ("LDH", vec![High(d8), Register("A")]),
+            5 => ("ADD", vec![Register("SP"), Signed(d8 as i8)]),
+            6 => ("LDH", vec![Register("A"), High(d8)]),
+            _ => ("LD", vec![Register("HL"), SpOffset(d8 as i8)]),
+        },
+        (3, 1) if q == 0 => ("POP", vec![Register(RP2[p])]),
+        (3, 1) => match p {
+            0 => ("RET", vec![]),
+            1 => ("RETI", vec![]),
+            2 => ("JP", vec![Register("HL")]),
+            _ => ("LD", vec![Register("SP"), Register("HL")]),
+        },
+        (3, 2) => match y {
+            0..=3 => ("JP", vec![Condition(CC[y as usize]), Imm16(d16)]),
+            4 => ("LD", vec![Indirect("C"), Register("A")]),
+            5 => ("LD", vec![Address(d16), Register("A")]),
+            6 => ("LD", vec![Register("A"), Indirect("C")]),
+            _ => ("LD", vec![Register("A"), Address(d16)]),
+        },
+        (3, 3) => match y {
+            0 => ("JP", vec![Imm16(d16)]),
+            1 => return decode_cb(byte(1)),
+            6 => ("DI", vec![]),
+            7 => ("EI", vec![]),
+            _ => ("DB", vec![Imm8(op)]),
+        },
+        (3, 4) if y < 4 => ("CALL", vec![Condition(CC[y as usize]), Imm16(d16)]),
+        (3, 5) if q == 0 => ("PUSH", vec![Register(RP2[p])]),
+        (3, 5) if p == 0 => ("CALL", vec![Imm16(d16)]),
+        (3, 6) => alu(y, Imm8(d8)),
+        (3, 7) => ("RST", vec![Vector(y * 8)]),
+        _ => ("DB", vec![Imm8(op)]),
+    };
+
+    let mut len = 1;
+    for o in operands.iter() {
+        len += match *o {
+            Imm16(_) | Address(_) => 2,
+            Imm8(_) | High(_) | Relative(_) | Signed(_) | SpOffset(_) if mnemonic != "DB" => 1,
+            _ => 0,
+        };
+    }
+    // STOP is followed by a padding byte
+    if mnemonic == "STOP" {
+        len = 2;
+    }
+    Instruction { mnemonic: mnemonic, operands: operands, len: len }
+}
+
+// ADD, ADC and SBC name A, the others don't
+fn alu(y: u8, operand: Operand) -> (&'static str, Vec<Operand>) {
+    match y {
+        0 | 1 | 3 => (ALU[y as usize], vec![Operand::Register("A"), operand]),
+        _ => (ALU[y as usize], vec![operand]),
+    }
+}
+
+fn decode_cb(op: u8) -> Instruction {
+    let (x, y, z) = (op >> 6, (op >> 3) & 7, op & 7);
+    let (mnemonic, operands) = match x {
+        0 => (ROT[y as usize], vec![r(z)]),
+        1 => ("BIT", vec![Operand::Bit(y), r(z)]),
+        2 => ("RES", vec![Operand::Bit(y), r(z)]),
+        _ => ("SET", vec![Operand::Bit(y), r(z)]),
+    };
+    Instruction { mnemonic: mnemonic, operands: operands, len: 2 }
+}
+
+fn signed(v: i8) -> String {
+    if v < 0 { format!("-${:02X}", -(v as i16)) } else { format!("${:02X}", v) }
+}
+
+impl Instruction {
+    // Text of the instruction at addr, JR offsets being shown as their target
+    pub fn format(&self, addr: u16) -> String {
+        let operands: Vec<String> = self.operands.iter().map(|o| match *o {
+            Operand::Register(r)  => r.to_string(),
+            Operand::Indirect(r)  => format!("({})", r),
+            Operand::Imm8(v)      => format!("${:02X}", v),
+            Operand::Imm16(v)     => format!("${:04X}", v),
+            Operand::Address(v)   => format!("(${:04X})", v),
+            Operand::High(v)      => format!("($FF{:02X})", v),
+            Operand::Relative(_)  => format!("${:04X}", self.target(addr).unwrap()),
+            Operand::Signed(v)    => signed(v),
+            Operand::SpOffset(v)  => format!("SP{}{}", if v < 0 { "" } else { "+" }, signed(v)),
+            Operand::Condition(c) => c.to_string(),
+            Operand::Bit(b)       => b.to_string(),
+            Operand::Vector(v)    => format!("${:02X}", v),
+        }).collect();
+        if operands.is_empty() {
+            self.mnemonic.to_string()
+        } else {
+            format!("{} {}", self.mnemonic, operands.join(", "))
+        }
+    }
+
+    // Destination of a jump, call or RST
+    pub fn target(&self, addr: u16) -> Option<u16> {
+        match self.mnemonic {
+            "JP" | "CALL" | "JR" | "RST" => {},
+            _ => return None,
+        }
+        match self.operands.last() {
+            Some(&Operand::Imm16(v))   => Some(v),
+            Some(&Operand::Relative(v)) => Some(addr.wrapping_add(self.len).wrapping_add(v as u16)),
+            Some(&Operand::Vector(v))   => Some(v as u16),
+            _ => None,
+        }
+    }
+
+    pub fn is_conditional(&self) -> bool {
+        self.operands.iter().any(|o| match *o { Operand::Condition(_) => true, _ => false })
+    }
+
+    pub fn is_call(&self) -> bool {
+        self.mnemonic == "CALL" || self.mnemonic == "RST"
+    }
+
+    // Execution doesn't go on with the next instruction
+    pub fn ends_flow(&self) -> bool {
+        match self.mnemonic {
+            "JP" | "JR" | "RET" => !self.is_conditional(),
+            "RETI" | "DB" => true,
+            _ => false,
+        }
+    }
+}
+
+// Entry point, RST and interrupt vectors
+const ENTRY_POINTS: [u16; 14] = [0x0100, 0x00, 0x08, 0x10, 0x18, 0x20, 0x28, 0x30, 0x38,
+                                 0x40, 0x48, 0x50, 0x58, 0x60];
+
+// Offset in the ROM of addr in bank
+fn rom_offset(bank: u16, addr: u16) -> usize {
+    if addr < 0x4000 { addr as usize } else { bank as usize * 0x4000 + (addr as usize - 0x4000) }
+}
+
+// Disassemble a whole ROM bank by bank. Code is followed from the entry
+// points, the vectors and the ROM symbols, the rest is shown as data.
+// Code in bank 0 jumps to the ROMX bank selected last on its path, by
+// "LD A, n" then "LD (2000-3FFF), A".
+pub fn disassemble_rom(rom: &[u8], symbols: &Symbols) -> String {
+    let banks = ((rom.len() + 0x3FFF) / 0x4000).max(2) as u16;
+    // Code found, per ROM offset: instruction start and its ROMX bank
+    let mut code: BTreeMap<usize, u16> = BTreeMap::new();
+    let mut covered: BTreeSet<usize> = BTreeSet::new();
+    let mut labels = Symbols::new();
+    let mut todo: Vec<(u16, u16, u16)> = vec![];
+
+    for &addr in ENTRY_POINTS.iter() {
+        todo.push((0, addr, 1));
+    }
+    for (bank, addr, _) in symbols.iter() {
+        if addr < 0x8000 && bank < banks {
+            todo.push((if addr < 0x4000 { 0 } else { bank }, addr, if addr < 0x4000 { 1 } else { bank }));
+        }
+    }
+
+    while let Some((bank, mut addr, mut romx)) = todo.pop() {
+        let mut last_a: Option<u8> = None;
+        loop {
+            let offset = rom_offset(bank, addr);
+            if addr >= 0x8000 || (bank == 0 && addr >= 0x4000) || offset >= rom.len() || covered.contains(&offset) {
+                break;
+            }
+            let instr = decode(&rom[offset..(offset+3).min(rom.len())]);
+            if offset + instr.len as usize > rom.len() {
+                break;
+            }
+            code.insert(offset, romx);
+            for i in 0..instr.len as usize {
+                covered.insert(offset + i);
+            }
+
+            // ROM bank switches
+            match (instr.mnemonic, instr.operands.get(0), instr.operands.get(1)) {
+                ("LD", Some(&Operand::Register("A")), Some(&Operand::Imm8(v))) => last_a = Some(v),
+                ("XOR", Some(&Operand::Register("A")), None) => last_a = Some(0),
+                ("LD", Some(&Operand::Address(0x2000..=0x3FFF)), Some(&Operand::Register("A"))) => {
+                    if let Some(v) = last_a {
+                        romx = (v as u16).max(1) % banks;
+                    }
+                },
+                (_, Some(&Operand::Register("A")), _) => last_a = None,
+                _ => {},
+            }
+
+            if let Some(target) = instr.target(addr) {
+                if target < 0x8000 {
+                    let target_bank = if target < 0x4000 { 0 } else if bank == 0 { romx } else { bank };
+                    let name = format!("{}_{:03X}_{:04X}", if instr.is_call() { "Call" } else { "Jump" }, target_bank, target);
+                    if symbols.label(Some(target_bank), target).is_none() && labels.label(Some(target_bank), target).is_none() {
+                        labels.add(target_bank, target, &name);
+                    }
+                    todo.push((target_bank, target, romx));
+                }
+            }
+            if instr.ends_flow() {
+                break;
+            }
+            addr = addr.wrapping_add(instr.len);
+        }
+    }
+
+    let mut out = String::new();
+    for bank in 0..banks {
+        let (start, end) = if bank == 0 { (0x0000, 0x4000) } else { (0x4000, 0x8000) };
+        out += &format!("\n; ROM bank {:02X}\n", bank);
+        let mut addr: u32 = start;
+        let mut data: Vec<u8> = vec![];
+        let mut data_addr = addr as u16;
+        while addr < end {
+            let a = addr as u16;
+            let offset = rom_offset(bank, a);
+            if offset >= rom.len() {
+                break;
+            }
+            let label = symbols.label(Some(bank), a).or(labels.label(Some(bank), a)).map(|l| l.to_string());
+            let is_code = code.contains_key(&offset);
+            if !data.is_empty() && (is_code || label.is_some() || data.len() == 16) {
+                out += &format_data(bank, data_addr, &data);
+                data.clear();
+            }
+            if let Some(label) = label {
+                out += &format!("{}:\n", label);
+            }
+            if !is_code {
+                if data.is_empty() {
+                    data_addr = a;
+                }
+                data.push(rom[offset]);
+                addr += 1;
+                continue;
+            }
+
+            let instr = decode(&rom[offset..(offset+3).min(rom.len())]);
+            let bytes: Vec<String> = rom[offset..offset + instr.len as usize].iter().map(|b| format!("{:02X}", b)).collect();
+            let mut text = instr.format(a);
+            if let Some(target) = instr.target(a) {
+                let romx = code[&offset];
+                let target_bank = if target < 0x4000 { 0 } else if bank == 0 { romx } else { bank };
+                if let Some(l) = symbols.label(Some(target_bank), target).or(labels.label(Some(target_bank), target)) {
+                    text = format!("{} <{}>", text, l);
+                }
+            }
+            out += &format!("{:02X}:{:04X}  {:<9} {}\n", bank, a, bytes.join(" "), text);
+            addr += instr.len as u32;
+        }
+        if !data.is_empty() {
+            out += &format_data(bank, data_addr, &data);
+        }
+    }
+    out
+}
+
+fn format_data(bank: u16, addr: u16, data: &[u8]) -> String {
+    let bytes: Vec<String> = data.iter().map(|b| format!("${:02X}", b)).collect();
+    format!("{:02X}:{:04X}  DB {}\n", bank, addr, bytes.join(","))
+}
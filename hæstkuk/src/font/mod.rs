@@ -0,0 +1,54 @@
+// Tiny 3x5 font for the debug windows
+#![allow(dead_code)]
+
+// Character cell, glyphs have one empty column and row around them
+pub const WIDTH:  usize = 4;
+pub const HEIGHT: usize = 6;
+
+// Rows of 3 bits, the high one being the left pixel
+const GLYPHS: [(char, [u8; 5]); 59] = [
+    ('0', [7, 5, 5, 5, 7]), ('1', [2, 6, 2, 2, 7]), ('2', [7, 1, 7, 4, 7]), ('3', [7, 1, 3, 1, 7]),
+    ('4', [5, 5, 7, 1, 1]), ('5', [7, 4, 7, 1, 7]), ('6', [7, 4, 7, 5, 7]), ('7', [7, 1, 1, 2, 2]),
+    ('8', [7, 5, 7, 5, 7]), ('9', [7, 5, 7, 1, 7]),
+    ('A', [2, 5, 7, 5, 5]), ('B', [6, 5, 6, 5, 6]), ('C', [3, 4, 4, 4, 3]), ('D', [6, 5, 5, 5, 6]),
+    ('E', [7, 4, 6, 4, 7]), ('F', [7, 4, 6, 4, 4]), ('G', [3, 4, 5, 5, 3]), ('H', [5, 5, 7, 5, 5]),
+    ('I', [7, 2, 2, 2, 7]), ('J', [1, 1, 1, 5, 2]), ('K', [5, 5, 6, 5, 5]), ('L', [4, 4, 4, 4, 7]),
+    ('M', [5, 7, 7, 5, 5]), ('N', [6, 5, 5, 5, 5]), ('O', [2, 5, 5, 5, 2]), ('P', [6, 5, 6, 4, 4]),
+    ('Q', [2, 5, 5, 6, 3]), ('R', [6, 5, 6, 5, 5]), ('S', [3, 4, 2, 1, 6]), ('T', [7, 2, 2, 2, 2]),
+    ('U', [5, 5, 5, 5, 7]), ('V', [5, 5, 5, 5, 2]), ('W', [5, 5, 7, 7, 5]), ('X', [5, 5, 2, 5, 5]),
+    ('Y', [5, 5, 2, 2, 2]), ('Z', [7, 1, 2, 4, 7]),
+    (' ', [0, 0, 0, 0, 0]), ('.', [0, 0, 0, 0, 2]), (',', [0, 0, 0, 2, 4]), (':', [0, 2, 0, 2, 0]),
+    (';', [0, 2, 0, 2, 4]), ('-', [0, 0, 7, 0, 0]), ('+', [0, 2, 7, 2, 0]), ('=', [0, 7, 0, 7, 0]),
+    ('_', [0, 0, 0, 0, 7]), ('/', [1, 1, 2, 4, 4]), ('(', [1, 2, 2, 2, 1]), (')', [4, 2, 2, 2, 4]),
+    ('[', [3, 2, 2, 2, 3]), (']', [6, 2, 2, 2, 6]), ('<', [1, 2, 4, 2, 1]), ('>', [4, 2, 1, 2, 4]),
+    ('!', [2, 2, 2, 0, 2]), ('?', [6, 1, 2, 0, 2]), ('*', [0, 5, 2, 5, 0]), ('#', [5, 7, 5, 7, 5]),
+    ('\'', [2, 2, 0, 0, 0]), ('"', [5, 5, 0, 0, 0]), ('$', [3, 6, 2, 3, 6]),
+];
+
+// Unknown characters are drawn as a filled block
+pub fn glyph(c: char) -> [u8; 5] {
+    let c = c.to_ascii_uppercase();
+    GLYPHS.iter().find(|g| g.0 == c).map_or([7, 7, 7, 7, 7], |g| g.1)
+}
+
+pub fn has_glyph(c: char) -> bool {
+    let c = c.to_ascii_uppercase();
+    GLYPHS.iter().any(|g| g.0 == c)
+}
+
+// Draw text into a 0xRRGGBB buffer of the given width, clipped to it
+pub fn draw_text(buf: &mut [u32], width: usize, x: usize, y: usize, text: &str, color: u32) {
+    let height = buf.len() / width;
+    for (i, c) in text.chars().enumerate() {
+        let g = glyph(c);
+        for (gy, row) in g.iter().enumerate() {
+            for gx in 0..3 {
+                let px = x + i*WIDTH + gx;
+                let py = y + gy;
+                if row & (4>>gx) != 0 && px < width && py < height {
+                    buf[px + py*width] = color;
+                }
+            }
+        }
+    }
+}
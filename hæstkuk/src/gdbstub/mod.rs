@@ -0,0 +1,417 @@
+// GDB remote serial protocol server
+//
+// The stub runs on top of the debugger: breakpoints, watchpoints and steps
+// are the debugger ones, the stub only translates the packets.
+// Registers are AF BC DE HL SP PC, 16 bits little endian. Memory addresses
+// above 0xFFFF select a bank, 0x34A20 being 03:4A20.
+#![allow(dead_code)]
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use lr35902::Cpu;
+use debugger::{Debugger, Action, Stop, Address, Breakpoint, Watchpoint};
+
+const REGISTERS: [&str; 6] = ["AF", "BC", "DE", "HL", "SP", "PC"];
+
+// Largest packet accepted or sent, as advertised to the client
+const PACKET_SIZE: u32 = 0x4000;
+
+const TARGET_XML: &str = "<?xml version=\"1.0\"?>\
+<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+<target version=\"1.0\"><feature name=\"org.gnu.gdb.lr35902.cpu\">\
+<reg name=\"af\" bitsize=\"16\" type=\"int\"/>\
+<reg name=\"bc\" bitsize=\"16\" type=\"int\"/>\
+<reg name=\"de\" bitsize=\"16\" type=\"int\"/>\
+<reg name=\"hl\" bitsize=\"16\" type=\"int\"/>\
+<reg name=\"sp\" bitsize=\"16\" type=\"data_ptr\"/>\
+<reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\"/>\
+</feature></target>";
+
+// Breakpoint or watchpoint set by the client: Z type, address, length and debugger number
+struct Point {
+    kind: u8,
+    addr: u32,
+    len: u32,
+    id: usize,
+}
+
+pub struct GdbStub {
+    listener: TcpListener,
+    stream: Option<TcpStream>,
+    points: Vec<Point>,
+    // A continue or step waits for its stop reply
+    running: bool,
+    last_stop: String,
+}
+
+impl GdbStub {
+    // Listen on localhost
+    pub fn listen(port: u16) -> Result<GdbStub, String> {
+        let listener = TcpListener::bind(("127.0.0.1", port)).map_err(|e| format!("GDB port {}: {}", port, e))?;
+        listener.set_nonblocking(true).map_err(|e| e.to_string())?;
+        println!("Waiting for GDB on port {}", port);
+        Ok(GdbStub {
+            listener: listener,
+            stream: None,
+            points: vec![],
+            running: false,
+            last_stop: String::from("S05"),
+        })
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    // Called while running: accept a client, or break on its Ctrl-C
+    pub fn poll<'a>(&mut self, debugger: &mut Debugger<'a>, cpu: &mut Cpu<'a>) {
+        if self.stream.is_none() {
+            if let Ok((stream, addr)) = self.listener.accept() {
+                println!("GDB connected from {}", addr);
+                stream.set_nodelay(true).ok();
+                self.stream = Some(stream);
+                self.running = false;
+                self.last_stop = String::from("S05");
+                debugger.request_break();
+            }
+            return;
+        }
+        let mut byte = [0u8; 1];
+        let closed = {
+            let stream = self.stream.as_mut().unwrap();
+            stream.set_nonblocking(true).ok();
+            let r = stream.read(&mut byte);
+            stream.set_nonblocking(false).ok();
+            match r {
+                Ok(0) => true,
+                Ok(_) => {
+                    if byte[0] == 0x03 {
+                        debugger.request_break();
+                    }
+                    false
+                },
+                Err(ref e) if e.kind() == ::std::io::ErrorKind::WouldBlock => false,
+                Err(_) => true,
+            }
+        };
+        if closed {
+            self.disconnect(debugger, cpu);
+        }
+    }
+
+    // Forget the client and its breakpoints
+    fn disconnect<'a>(&mut self, debugger: &mut Debugger<'a>, cpu: &mut Cpu<'a>) {
+        println!("GDB disconnected");
+        self.stream = None;
+        for p in self.points.drain(..) {
+            debugger.delete(cpu, p.id).ok();
+        }
+    }
+
+    // The emulation stopped, serve the client until it resumes it
+    pub fn stopped<'a>(&mut self, debugger: &mut Debugger<'a>, cpu: &mut Cpu<'a>, stop: &Stop) -> Action {
+        // Stopped on connection, the client asks why with "?"
+        if self.running {
+            self.running = false;
+            self.last_stop = match *stop {
+                Stop::Watch(hit) => {
+                    let addr = ((hit.bank as u32) << 16) | hit.addr as u32;
+                    format!("T05{}:{:x};", if hit.write { "watch" } else { "rwatch" }, addr)
+                },
+                // Ctrl-C
+                Stop::Break => String::from("S02"),
+                _           => String::from("S05"),
+            };
+            let reply = self.last_stop.clone();
+            if self.send(&reply).is_err() {
+                self.disconnect(debugger, cpu);
+                return debugger.run(cpu);
+            }
+        }
+        loop {
+            let packet = match self.receive() {
+                Some(p) => p,
+                None => {
+                    self.disconnect(debugger, cpu);
+                    return debugger.run(cpu);
+                },
+            };
+            let (reply, action) = self.handle(debugger, cpu, &packet);
+            if let Some(reply) = reply {
+                if self.send(&reply).is_err() {
+                    self.disconnect(debugger, cpu);
+                    return debugger.run(cpu);
+                }
+            }
+            match action {
+                Action::Prompt => {},
+                Action::Resume => {
+                    self.running = true;
+                    return Action::Resume;
+                },
+                Action::Quit => return Action::Quit,
+            }
+        }
+    }
+
+    // Reply to a packet, None for no reply
+    fn handle<'a>(&mut self, debugger: &mut Debugger<'a>, cpu: &mut Cpu<'a>, packet: &str) -> (Option<String>, Action) {
+        let ok = Some(String::from("OK"));
+        let (cmd, args) = if packet.is_empty() { ("", "") } else { packet.split_at(1) };
+        match cmd {
+            "?" => return (Some(self.last_stop.clone()), Action::Prompt),
+            "g" => {
+                let regs: String = REGISTERS.iter().map(|r| hex16(cpu.get_reg(r).unwrap())).collect();
+                return (Some(regs), Action::Prompt);
+            },
+            "G" => {
+                for (i, r) in REGISTERS.iter().enumerate() {
+                    if let Some(v) = args.get(i*4..i*4+4).and_then(parse_hex16) {
+                        cpu.set_reg(r, v);
+                    }
+                }
+                return (ok, Action::Prompt);
+            },
+            "p" => {
+                let reply = usize::from_str_radix(args, 16).ok()
+                    .and_then(|n| REGISTERS.get(n))
+                    .map_or(String::from("E01"), |r| hex16(cpu.get_reg(r).unwrap()));
+                return (Some(reply), Action::Prompt);
+            },
+            "P" => {
+                let mut parts = args.splitn(2, '=');
+                let reg = parts.next().and_then(|n| usize::from_str_radix(n, 16).ok()).and_then(|n| REGISTERS.get(n));
+                let v = parts.next().and_then(parse_hex16);
+                return match (reg, v) {
+                    (Some(r), Some(v)) => { cpu.set_reg(r, v); (ok, Action::Prompt) },
+                    _ => (Some(String::from("E01")), Action::Prompt),
+                };
+            },
+            "m" => {
+                let reply = match memory_range(cpu, args) {
+                    Some((addr, len)) => (0..len).map(|i| {
+                        let (bank, a) = split_address(addr + i);
+                        format!("{:02x}", cpu.mem.peek_bank8(bank, a))
+                    }).collect(),
+                    None => String::from("E01"),
+                };
+                return (Some(reply), Action::Prompt);
+            },
+            "M" => {
+                let mut parts = args.splitn(2, ':');
+                let range = parts.next().and_then(|r| memory_range(cpu, r));
+                let data = parts.next().unwrap_or("");
+                return match range {
+                    Some((addr, len)) if data.len() >= len as usize*2
+                        && data.bytes().take(len as usize*2).all(|b| b.is_ascii_hexdigit()) => {
+                        for i in 0..len {
+                            let i = i as usize;
+                            let v = u8::from_str_radix(&data[i*2..i*2+2], 16).unwrap_or(0);
+                            let (bank, a) = split_address(addr + i as u32);
+                            cpu.mem.poke_bank8(bank, a, v);
+                        }
+                        (ok, Action::Prompt)
+                    },
+                    _ => (Some(String::from("E01")), Action::Prompt),
+                };
+            },
+            // Resuming at another address isn't supported
+            "c" => return (None, debugger.run(cpu)),
+            "s" => return (None, debugger.step(cpu, 1)),
+            "Z" | "z" => {
+                let reply = match self.point(debugger, cpu, cmd == "Z", args) {
+                    Some(true) => "OK",
+                    Some(false) => "E01",
+                    None => "",
+                };
+                return (Some(String::from(reply)), Action::Prompt);
+            },
+            "k" => return (None, Action::Quit),
+            "D" => {
+                let _ = self.send("OK");
+                self.disconnect(debugger, cpu);
+                return (None, debugger.run(cpu));
+            },
+            "H" => return (ok, Action::Prompt),
+            "T" => return (ok, Action::Prompt),
+            _ => {},
+        }
+        let reply = if packet.starts_with("qSupported") {
+            format!("PacketSize={:x};qXfer:features:read+", PACKET_SIZE)
+        } else if packet.starts_with("qXfer:features:read:target.xml:") {
+            let range = parse_range(&packet["qXfer:features:read:target.xml:".len()..]);
+            match range {
+                Some((offset, len)) => {
+                    let offset = (offset as usize).min(TARGET_XML.len());
+                    let end = (offset + len as usize).min(TARGET_XML.len());
+                    format!("{}{}", if end == TARGET_XML.len() { "l" } else { "m" }, &TARGET_XML[offset..end])
+                },
+                None => String::from("E01"),
+            }
+        } else if packet == "qAttached" {
+            String::from("1")
+        } else if packet == "qC" {
+            String::from("QC1")
+        } else if packet == "qfThreadInfo" {
+            String::from("m1")
+        } else if packet == "qsThreadInfo" {
+            String::from("l")
+        } else {
+            // Not supported
+            String::new()
+        };
+        (Some(reply), Action::Prompt)
+    }
+
+    // Z/z packets: type 0 and 1 are breakpoints, 2 write, 3 read and 4 access
+    // watchpoints. None when the type isn't supported.
+    fn point<'a>(&mut self, debugger: &mut Debugger<'a>, cpu: &mut Cpu<'a>, insert: bool, args: &str) -> Option<bool> {
+        let mut parts = args.split(',');
+        let kind = parts.next().and_then(|k| k.parse::<u8>().ok())?;
+        let addr = parts.next().and_then(|a| u32::from_str_radix(a, 16).ok());
+        let len = parts.next().and_then(|l| u32::from_str_radix(l, 16).ok()).unwrap_or(1).max(1);
+        if kind > 4 {
+            return None;
+        }
+        // The whole address space at most
+        if len > 0x10000 {
+            return Some(false);
+        }
+        let addr = match addr {
+            Some(a) => a,
+            None => return Some(false),
+        };
+        if !insert {
+            return match self.points.iter().position(|p| p.kind == kind && p.addr == addr && p.len == len) {
+                Some(i) => {
+                    let p = self.points.remove(i);
+                    debugger.delete(cpu, p.id).ok();
+                    Some(true)
+                },
+                None => Some(false),
+            };
+        }
+        let (bank, start) = split_address(addr);
+        let id = if kind < 2 {
+            debugger.add_breakpoint(Breakpoint { addr: Some(Address { bank: bank, addr: start }), condition: None })
+        } else {
+            debugger.add_watchpoint(cpu, Watchpoint {
+                bank: bank,
+                start: start,
+                end: start.wrapping_add((len - 1) as u16),
+                read: kind != 2,
+                write: kind != 3,
+                execute: false,
+            })
+        };
+        self.points.push(Point { kind: kind, addr: addr, len: len, id: id });
+        Some(true)
+    }
+
+    // Next packet, acknowledged, None when the client is gone
+    fn receive(&mut self) -> Option<String> {
+        let stream = self.stream.as_mut()?;
+        let mut byte = [0u8; 1];
+        loop {
+            // Skip acks and Ctrl-C until the start of a packet
+            loop {
+                if stream.read(&mut byte).ok()? == 0 {
+                    return None;
+                }
+                if byte[0] == b'$' {
+                    break;
+                }
+            }
+            let mut data = vec![];
+            loop {
+                if stream.read(&mut byte).ok()? == 0 {
+                    return None;
+                }
+                if byte[0] == b'#' {
+                    break;
+                }
+                data.push(byte[0]);
+            }
+            let mut checksum = [0u8; 2];
+            stream.read_exact(&mut checksum).ok()?;
+            let expected = u8::from_str_radix(&String::from_utf8_lossy(&checksum), 16).ok();
+            if expected == Some(sum(&data)) {
+                stream.write_all(b"+").ok()?;
+                return Some(unescape(&data));
+            }
+            stream.write_all(b"-").ok()?;
+        }
+    }
+
+    fn send(&mut self, data: &str) -> Result<(), ()> {
+        let stream = self.stream.as_mut().ok_or(())?;
+        let packet = format!("${}#{:02x}", data, sum(data.as_bytes()));
+        stream.write_all(packet.as_bytes()).map_err(|_| ())?;
+        // Wait for the ack
+        let mut byte = [0u8; 1];
+        loop {
+            match stream.read(&mut byte) {
+                Ok(0) | Err(_) => return Err(()),
+                Ok(_) if byte[0] == b'+' => return Ok(()),
+                Ok(_) if byte[0] == b'-' => stream.write_all(packet.as_bytes()).map_err(|_| ())?,
+                Ok(_) => {},
+            }
+        }
+    }
+}
+
+fn sum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |s, b| s.wrapping_add(*b))
+}
+
+// "}" escapes the next byte, xored with 0x20
+fn unescape(data: &[u8]) -> String {
+    let mut out = vec![];
+    let mut escape = false;
+    for &b in data {
+        if escape {
+            out.push(b ^ 0x20);
+            escape = false;
+        } else if b == b'}' {
+            escape = true;
+        } else {
+            out.push(b);
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn hex16(v: u16) -> String {
+    format!("{:02x}{:02x}", v & 0xFF, v >> 8)
+}
+
+// 16 bits little endian
+fn parse_hex16(s: &str) -> Option<u16> {
+    let v = u16::from_str_radix(s.get(0..4)?, 16).ok()?;
+    Some(v.swap_bytes())
+}
+
+// "addr,len"
+fn parse_range(s: &str) -> Option<(u32, u32)> {
+    let mut parts = s.splitn(2, ',');
+    let addr = u32::from_str_radix(parts.next()?, 16).ok()?;
+    let len = u32::from_str_radix(parts.next()?, 16).ok()?;
+    Some((addr, len))
+}
+
+// m/M range, None if it goes past the last bank or doesn't fit in a packet
+// as hex digits
+fn memory_range(cpu: &Cpu, s: &str) -> Option<(u32, u32)> {
+    let (addr, len) = parse_range(s)?;
+    let end = addr.checked_add(len)?;
+    let banks = cpu.mem.get_bank_count(0x4000) as u32;
+    if len > PACKET_SIZE/2 || (len > 0 && (end - 1) >> 16 >= banks) {
+        return None;
+    }
+    Some((addr, len))
+}
+
+// Bank in the upper bits
+fn split_address(addr: u32) -> (Option<u16>, u16) {
+    let bank = addr >> 16;
+    (if bank == 0 { None } else { Some(bank as u16) }, addr as u16)
+}
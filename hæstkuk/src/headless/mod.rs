@@ -0,0 +1,133 @@
+// Headless frontend, runs a ROM without any window for tests and batch runs
+#![allow(dead_code)]
+use std::io::Write;
+use lr35902::Cpu;
+use render::Render;
+use movie;
+
+// Exit codes of a headless run
+pub const EXIT_PASSED: i32  = 0;
+pub const EXIT_FAILED: i32  = 1;
+pub const EXIT_TIMEOUT: i32 = 124;
+
+// Default frame limit, about a minute of emulated time
+pub const DEFAULT_FRAMES: u64 = 3600;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    Passed,
+    Failed,
+    Timeout,
+}
+
+impl Outcome {
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            Outcome::Passed  => EXIT_PASSED,
+            Outcome::Failed  => EXIT_FAILED,
+            Outcome::Timeout => EXIT_TIMEOUT,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    // Frames to run before giving up
+    pub frames: u64,
+    // Stop with success when the serial output contains this text
+    pub pass_serial: Option<String>,
+    // Stop with failure when the serial output contains this text
+    pub fail_serial: Option<String>,
+    // Stop with success when PC reaches this address
+    pub pass_pc: Option<u16>,
+    // Stop with success when the CPU jumps to itself forever, like test ROMs do when done
+    pub pass_loop: bool,
+    // Where to save the last frame
+    pub png: Option<String>,
+    // Copy the serial output to stdout
+    pub print_serial: bool,
+}
+
+impl Options {
+    pub fn new() -> Options {
+        Options {
+            frames: DEFAULT_FRAMES,
+            pass_serial: None,
+            fail_serial: None,
+            pass_pc: None,
+            pass_loop: false,
+            png: None,
+            print_serial: true,
+        }
+    }
+
+    fn has_condition(&self) -> bool {
+        self.pass_serial.is_some() || self.fail_serial.is_some() || self.pass_pc.is_some() || self.pass_loop
+    }
+}
+
+// Run until a condition is met or the frame limit is reached.
+// Without any condition, reaching the frame limit is a success.
+pub fn run<'a>(cpu: &mut Cpu<'a>, render: &mut Render<'a>, options: &Options) -> Outcome {
+    let mut serial: Vec<u8> = vec![];
+    let start = render.get_frame_count();
+
+    let outcome = loop {
+        let pc = cpu.get_pc();
+        ::step(cpu, render);
+
+        let out = cpu.mem.take_serial();
+        if !out.is_empty() {
+            if options.print_serial {
+                let stdout = ::std::io::stdout();
+                let mut lock = stdout.lock();
+                lock.write_all(&out).unwrap();
+                lock.flush().unwrap();
+            }
+            serial.extend(out);
+            let text = String::from_utf8_lossy(&serial);
+            if let Some(ref fail) = options.fail_serial {
+                if text.contains(fail.as_str()) {
+                    break Outcome::Failed;
+                }
+            }
+            if let Some(ref pass) = options.pass_serial {
+                if text.contains(pass.as_str()) {
+                    break Outcome::Passed;
+                }
+            }
+        }
+        // A finished movie playback decides, unless a script or a condition does
+        let movie = cpu.get_movie().map(|m| (m.get_mode(), m.get_desyncs().is_empty()));
+        if let Some((movie::Mode::Finished, in_sync)) = movie {
+            if !in_sync {
+                break Outcome::Failed;
+            }
+            if !options.has_condition() && cpu.get_script_quit().is_none() {
+                break Outcome::Passed;
+            }
+        }
+        match cpu.get_script_quit() {
+            Some(true)  => break Outcome::Passed,
+            Some(false) => break Outcome::Failed,
+            None => {},
+        }
+        if options.pass_pc == Some(cpu.get_pc()) {
+            break Outcome::Passed;
+        }
+        // A running CPU that stays on the same PC is jumping to itself
+        if options.pass_loop && pc == cpu.get_pc() && !cpu.is_halted() && !cpu.mem.is_bootrom_enabled() {
+            break Outcome::Passed;
+        }
+        if render.get_frame_count() - start >= options.frames {
+            break if options.has_condition() { Outcome::Timeout } else { Outcome::Passed };
+        }
+    };
+
+    if let Some(ref path) = options.png {
+        if let Err(e) = render.save_png(path) {
+            println!("Can't save {}: {}", path, e);
+        }
+    }
+    outcome
+}
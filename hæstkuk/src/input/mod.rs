@@ -0,0 +1,243 @@
+// Joypad input from the keyboard and gamepads
+//
+// Bindings come from the [keys] and [gamepad] sections of the configuration,
+// several keys or gamepad buttons per Game Boy button, separated by commas.
+// Keys are named as in minifb (A, Key1 or 1, Space, Enter, Up, LeftShift,
+// NumPad0...), gamepad buttons as in gilrs (South, East, North, West, Select,
+// Start, LeftTrigger, DPadUp...). The D-pad and the left stick always move.
+//
+// [keys]
+// a = A, Z
+// turbo_a = S
+// turbo_rate = 10
+//
+// [gamepad]
+// a = East
+// deadzone = 0.5
+//
+// Gamepads need the "gamepad" feature, on by default.
+#![allow(dead_code)]
+#[cfg(feature = "gamepad")]
+extern crate gilrs;
+use minifb::{Key, Window};
+#[cfg(feature = "gamepad")]
+use self::gilrs::{Axis, Button, Gilrs};
+use config::Config;
+
+// Game Boy buttons in the Joypad::get_buttons bit order, then the turbo ones
+const BUTTONS: [&str; 10] = ["a", "b", "select", "start", "right", "left", "up", "down", "turbo_a", "turbo_b"];
+const TURBO_A: usize = 8;
+const TURBO_B: usize = 9;
+
+const KEYS: [Key; 106] = [
+    Key::Key0, Key::Key1, Key::Key2, Key::Key3, Key::Key4, Key::Key5, Key::Key6, Key::Key7, Key::Key8, Key::Key9,
+    Key::A, Key::B, Key::C, Key::D, Key::E, Key::F, Key::G, Key::H, Key::I, Key::J, Key::K, Key::L, Key::M,
+    Key::N, Key::O, Key::P, Key::Q, Key::R, Key::S, Key::T, Key::U, Key::V, Key::W, Key::X, Key::Y, Key::Z,
+    Key::F1, Key::F2, Key::F3, Key::F4, Key::F5, Key::F6, Key::F7, Key::F8, Key::F9, Key::F10, Key::F11,
+    Key::F12, Key::F13, Key::F14, Key::F15,
+    Key::Down, Key::Left, Key::Right, Key::Up, Key::Apostrophe, Key::Backquote, Key::Backslash, Key::Comma,
+    Key::Equal, Key::LeftBracket, Key::Minus, Key::Period, Key::RightBracket, Key::Semicolon, Key::Slash,
+    Key::Backspace, Key::Delete, Key::End, Key::Enter, Key::Escape, Key::Home, Key::Insert, Key::Menu,
+    Key::PageDown, Key::PageUp, Key::Pause, Key::Space, Key::Tab, Key::NumLock, Key::CapsLock, Key::ScrollLock,
+    Key::LeftShift, Key::RightShift, Key::LeftCtrl, Key::RightCtrl,
+    Key::NumPad0, Key::NumPad1, Key::NumPad2, Key::NumPad3, Key::NumPad4, Key::NumPad5, Key::NumPad6,
+    Key::NumPad7, Key::NumPad8, Key::NumPad9, Key::NumPadDot, Key::NumPadSlash, Key::NumPadAsterisk,
+    Key::NumPadMinus, Key::NumPadPlus, Key::NumPadEnter, Key::LeftAlt, Key::RightAlt, Key::LeftSuper,
+    Key::RightSuper,
+];
+
+#[cfg(feature = "gamepad")]
+const PAD_BUTTONS: [Button; 19] = [
+    Button::South, Button::East, Button::North, Button::West, Button::C, Button::Z,
+    Button::LeftTrigger, Button::LeftTrigger2, Button::RightTrigger, Button::RightTrigger2,
+    Button::Select, Button::Start, Button::Mode, Button::LeftThumb, Button::RightThumb,
+    Button::DPadUp, Button::DPadDown, Button::DPadLeft, Button::DPadRight,
+];
+
+fn parse_key(name: &str) -> Option<Key> {
+    let name = if name.len() == 1 && name.as_bytes()[0].is_ascii_digit() { format!("Key{}", name) } else { name.to_string() };
+    KEYS.iter().find(|k| format!("{:?}", k).eq_ignore_ascii_case(&name)).cloned()
+}
+
+#[cfg(feature = "gamepad")]
+fn parse_pad_button(name: &str) -> Option<Button> {
+    PAD_BUTTONS.iter().find(|b| format!("{:?}", b).eq_ignore_ascii_case(name)).cloned()
+}
+
+// "A, Z" to a list, with the unknown names
+fn parse_list<T, F: Fn(&str) -> Option<T>>(value: &str, parse: F) -> Result<Vec<T>, String> {
+    value.split(',').map(|n| n.trim()).filter(|n| !n.is_empty())
+        .map(|n| parse(n).ok_or_else(|| format!("unknown key or button '{}'", n)))
+        .collect()
+}
+
+pub struct Input {
+    keys: Vec<Vec<Key>>,
+    #[cfg(feature = "gamepad")]
+    pad_buttons: Vec<Vec<Button>>,
+    // Stick position needed to press a direction
+    deadzone: f32,
+    // Turbo presses per second
+    turbo_rate: u32,
+    // None without gamepad support
+    #[cfg(feature = "gamepad")]
+    gilrs: Option<Gilrs>,
+    // Gamepad buttons, read once per frame
+    pad: u8,
+    pad_turbo: (bool, bool),
+    pad_frame: Option<u64>,
+}
+
+impl Input {
+    pub fn new() -> Input {
+        let keys = [
+            vec![Key::A], vec![Key::B], vec![Key::Space], vec![Key::Enter],
+            vec![Key::Right], vec![Key::Left], vec![Key::Up], vec![Key::Down], vec![], vec![],
+        ];
+        #[cfg(feature = "gamepad")]
+        let pad_buttons = [
+            vec![Button::East], vec![Button::South], vec![Button::Select], vec![Button::Start],
+            // The D-pad always moves, on top of these
+            vec![], vec![], vec![], vec![],
+            vec![Button::North], vec![Button::West],
+        ];
+        Input {
+            keys: keys.to_vec(),
+            #[cfg(feature = "gamepad")]
+            pad_buttons: pad_buttons.to_vec(),
+            deadzone: 0.5,
+            turbo_rate: 10,
+            #[cfg(feature = "gamepad")]
+            gilrs: None,
+            pad: 0,
+            pad_turbo: (false, false),
+            pad_frame: None,
+        }
+    }
+
+    // Bindings given in the configuration replace the default ones
+    pub fn configure(&mut self, config: &Config) -> Result<(), String> {
+        for (key, value) in config.section("keys") {
+            match BUTTONS.iter().position(|&b| b == key) {
+                Some(i) => self.keys[i] = parse_list(&value, parse_key)?,
+                None if key == "turbo_rate" => {
+                    self.turbo_rate = value.parse().ok().filter(|&r| r > 0 && r <= 30)
+                        .ok_or_else(|| format!("invalid turbo rate '{}', 1 to 30 presses per second", value))?;
+                },
+                None => return Err(format!("unknown button '{}' in [keys]", key)),
+            }
+        }
+        self.configure_gamepad(config)
+    }
+
+    #[cfg(feature = "gamepad")]
+    fn configure_gamepad(&mut self, config: &Config) -> Result<(), String> {
+        for (key, value) in config.section("gamepad") {
+            match BUTTONS.iter().position(|&b| b == key) {
+                Some(i) => self.pad_buttons[i] = parse_list(&value, parse_pad_button)?,
+                None if key == "deadzone" => {
+                    self.deadzone = value.parse().ok().filter(|&d| d > 0.0 && d < 1.0)
+                        .ok_or_else(|| format!("invalid deadzone '{}'", value))?;
+                },
+                None => return Err(format!("unknown button '{}' in [gamepad]", key)),
+            }
+        }
+        Ok(())
+    }
+
+    // Without gamepad support the section is only checked for typos
+    #[cfg(not(feature = "gamepad"))]
+    fn configure_gamepad(&mut self, config: &Config) -> Result<(), String> {
+        for (key, _) in config.section("gamepad") {
+            if !BUTTONS.contains(&key.as_str()) && key != "deadzone" {
+                return Err(format!("unknown button '{}' in [gamepad]", key));
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "gamepad")]
+    pub fn open_gamepads(&mut self) {
+        match Gilrs::new() {
+            Ok(g) => {
+                for (_, pad) in g.gamepads() {
+                    println!("Gamepad: {}", pad.name());
+                }
+                self.gilrs = Some(g);
+            },
+            Err(e) => println!("No gamepad support: {}", e),
+        }
+    }
+
+    #[cfg(not(feature = "gamepad"))]
+    pub fn open_gamepads(&mut self) {
+        println!("No gamepad support: built without the gamepad feature");
+    }
+
+    // Turbo buttons are pressed half of each cycle
+    fn turbo_on(&self, frame: u64) -> bool {
+        let period = (60 / self.turbo_rate as u64).max(2);
+        frame % period < period / 2
+    }
+
+    #[cfg(not(feature = "gamepad"))]
+    fn poll_gamepads(&mut self) {}
+
+    #[cfg(feature = "gamepad")]
+    fn poll_gamepads(&mut self) {
+        let gilrs = match self.gilrs {
+            Some(ref mut g) => g,
+            None => return,
+        };
+        while gilrs.next_event().is_some() {}
+        let (mut pad, mut turbo_a, mut turbo_b) = (0, false, false);
+        for (_, gamepad) in gilrs.gamepads() {
+            for (i, buttons) in self.pad_buttons.iter().enumerate() {
+                if buttons.iter().any(|&b| gamepad.is_pressed(b)) {
+                    match i {
+                        TURBO_A => turbo_a = true,
+                        TURBO_B => turbo_b = true,
+                        _ => pad |= 1 << i,
+                    }
+                }
+            }
+            let dpad = [Button::DPadRight, Button::DPadLeft, Button::DPadUp, Button::DPadDown];
+            for (i, &b) in dpad.iter().enumerate() {
+                if gamepad.is_pressed(b) {
+                    pad |= 1 << (4 + i);
+                }
+            }
+            let (x, y) = (gamepad.value(Axis::LeftStickX), gamepad.value(Axis::LeftStickY));
+            if x > self.deadzone  { pad |= 1 << 4; }
+            if x < -self.deadzone { pad |= 1 << 5; }
+            if y > self.deadzone  { pad |= 1 << 6; }
+            if y < -self.deadzone { pad |= 1 << 7; }
+        }
+        self.pad = pad;
+        self.pad_turbo = (turbo_a, turbo_b);
+    }
+
+    // Pressed buttons, as Joypad::get_buttons
+    pub fn buttons(&mut self, window: Option<&Window>, frame: u64) -> u8 {
+        if self.pad_frame != Some(frame) {
+            self.pad_frame = Some(frame);
+            self.poll_gamepads();
+        }
+        let down = |keys: &Vec<Key>| window.map_or(false, |w| keys.iter().any(|&k| w.is_key_down(k)));
+        let mut buttons = self.pad;
+        for i in 0..8 {
+            if down(&self.keys[i]) {
+                buttons |= 1 << i;
+            }
+        }
+        if self.turbo_on(frame) {
+            if down(&self.keys[TURBO_A]) || self.pad_turbo.0 {
+                buttons |= 1;
+            }
+            if down(&self.keys[TURBO_B]) || self.pad_turbo.1 {
+                buttons |= 2;
+            }
+        }
+        buttons
+    }
+}
@@ -1,5 +1,6 @@
 #![allow(dead_code)]
 use std::marker::PhantomData;
+use savestate;
 
 // Joypad controller
 #[derive(Clone, Debug, Default)]
@@ -16,13 +17,19 @@ pub struct Joypad<'a> {
     btn_up:      bool,
     btn_down:    bool,
     interrupt:   bool,
+    // Low nibble at the last update, to find the falling edges
+    last_lines:  u8,
+    // Buttons held by a script, on top of the live input
+    held:        u8,
+    // Buttons kept for the whole frame by a movie, instead of the live input
+    latched:     Option<u8>,
 }
 
 
 impl<'a> Joypad<'a>{
     pub fn new() -> Joypad<'a> {
         Joypad{
-            input: 0b0011_1111,
+            input: 0b0011_0000,
             phantom: PhantomData,
             debug: false,
             btn_a:      false,
@@ -34,47 +41,101 @@ impl<'a> Joypad<'a>{
             btn_up:     false,
             btn_down:   false,
             interrupt:  false,
+            last_lines: 0x0F,
+            held:       0,
+            latched:    None,
         }
     }
+
+    // Buttons are live input and are not part of the state
+    pub fn save_state(&self, w: &mut savestate::Writer) {
+        w.u8(self.input);
+        w.bool(self.interrupt);
+    }
+    pub fn load_state(&mut self, r: &mut savestate::Reader) -> Result<(), String> {
+        self.input     = r.u8()? & 0b0011_0000;
+        self.interrupt = r.bool()?;
+        self.last_lines = self.lines();
+        Ok(())
+    }
     pub fn write8(&mut self, v: u8)  {
         self.input = v & 0b0011_0000;
+        self.update();
     }
 
-    pub fn read8(&mut self) -> u8 {
-        self.input
+    // Bits 6-7 always read as 1, and the low nibble is computed from the
+    // selected groups: a line is low when one of its buttons is pressed in
+    // any selected group, high when no group is selected
+    pub fn read8(&self) -> u8 {
+        0b1100_0000 | self.input | self.lines()
     }
 
-    pub fn update(&mut self) {
-        if ((self.input&0b0010_0000) >> 5) == 0 { // Action
-            let old_input = self.input;
-            self.input = (self.input&0b1111_0000) |
-                ((!self.btn_start  as u8) << 3) |
-                ((!self.btn_select as u8) << 2) |
-                ((!self.btn_b      as u8) << 1) |
-                !self.btn_a        as u8;
-            if self.input&0b0000_1111 != old_input&0b0000_1111 {
-                self.interrupt = true;
-            } else {
-                self.interrupt = false;
-            }
+    fn lines(&self) -> u8 {
+        let pressed = self.latched.unwrap_or(self.get_buttons()) | self.held;
+        let mut low = 0;
+        if self.input&0b0010_0000 == 0 { // Action
+            low |= pressed & 0x0F;
+        }
+        if self.input&0b0001_0000 == 0 { // Direction
+            low |= pressed >> 4;
+        }
+        !low & 0x0F
+    }
 
-        } else if ((self.input&0b0001_0000) >> 4) == 0 {  // Direction
-            let old_input = self.input;
-            self.input = (self.input&0b1111_0000) |
-                ((!self.btn_down  as u8) << 3) |
-                ((!self.btn_up as u8) << 2) |
-                ((!self.btn_left      as u8) << 1) |
-                !self.btn_right        as u8;
-            if self.input&0b0000_1111 != old_input&0b0000_1111 {
-                self.interrupt = true;
-            } else {
-                self.interrupt = false;
-            }
+    // Raises the interrupt when a line goes from high to low, run after each
+    // instruction and on writes
+    pub fn update(&mut self) {
+        let lines = self.lines();
+        if self.last_lines & !lines != 0 {
+            self.interrupt = true;
         }
+        self.last_lines = lines;
+    }
+
+    // Pressed buttons: A, B, Select, Start, Right, Left, Up, Down from bit 0 to 7
+    pub fn get_buttons(&self) -> u8 {
+        (self.btn_a as u8) |
+            ((self.btn_b      as u8) << 1) |
+            ((self.btn_select as u8) << 2) |
+            ((self.btn_start  as u8) << 3) |
+            ((self.btn_right  as u8) << 4) |
+            ((self.btn_left   as u8) << 5) |
+            ((self.btn_up     as u8) << 6) |
+            ((self.btn_down   as u8) << 7)
+    }
+
+    // Live and script buttons, what a movie records
+    pub fn get_pressed(&self) -> u8 {
+        self.get_buttons() | self.held
+    }
+    // Same bits as get_buttons
+    pub fn set_held(&mut self, buttons: u8) {
+        self.held = buttons;
+    }
+
+    pub fn set_latched(&mut self, buttons: Option<u8>) {
+        self.latched = buttons;
     }
 
     pub fn int_joypad(&mut self) -> bool {
-        self.interrupt
+        if self.interrupt {
+            self.interrupt = false;
+            true
+        } else {
+            false
+        }
+    }
+
+    // All of them, same bits as get_buttons
+    pub fn set_buttons(&mut self, buttons: u8) {
+        self.btn_a      = buttons & 0x01 != 0;
+        self.btn_b      = buttons & 0x02 != 0;
+        self.btn_select = buttons & 0x04 != 0;
+        self.btn_start  = buttons & 0x08 != 0;
+        self.btn_right  = buttons & 0x10 != 0;
+        self.btn_left   = buttons & 0x20 != 0;
+        self.btn_up     = buttons & 0x40 != 0;
+        self.btn_down   = buttons & 0x80 != 0;
     }
 
     pub fn set_a(&mut self, val: bool) {
@@ -1,5 +1,6 @@
 #![allow(dead_code)]
 use std::marker::PhantomData;
+use savestate;
 
 // LCD controller
 #[derive(Clone, Debug, Default)]
@@ -17,6 +18,13 @@ pub struct LCD<'a> {
     mode3_counter: u64,
     need_render: bool,
     need_new_line: bool,
+    first_frame: bool,
+    entered_hblank: bool,
+    // CGB palettes
+    bg_cram: Vec<u8>,
+    obj_cram: Vec<u8>,
+    bcps: u8,
+    ocps: u8,
     t: u32,
 }
 
@@ -37,11 +45,79 @@ impl<'a> LCD<'a>{
             mode3_counter: 0,
             need_render: true,
             need_new_line: true,
+            first_frame: false,
+            entered_hblank: false,
+            bg_cram: vec![0xFF; 64],
+            obj_cram: vec![0xFF; 64],
+            bcps: 0,
+            ocps: 0,
             t: 0
         }
     }
+
+    pub fn save_state(&self, w: &mut savestate::Writer) {
+        w.bytes(&self.regs);
+        w.bool(self.vblank);
+        w.u64(self.counter);
+        w.u64(self.mode as u64);
+        w.u64(self.mode0_counter);
+        w.u64(self.mode1_counter);
+        w.u64(self.mode2_counter);
+        w.u64(self.mode3_counter);
+        w.bool(self.need_render);
+        w.bool(self.need_new_line);
+        w.bool(self.first_frame);
+        w.bool(self.entered_hblank);
+        w.bytes(&self.bg_cram);
+        w.bytes(&self.obj_cram);
+        w.u8(self.bcps);
+        w.u8(self.ocps);
+    }
+    pub fn load_state(&mut self, r: &mut savestate::Reader) -> Result<(), String> {
+        r.bytes_into(&mut self.regs)?;
+        self.vblank         = r.bool()?;
+        self.counter        = r.u64()?;
+        self.mode           = r.u64()? as usize;
+        self.mode0_counter  = r.u64()?;
+        self.mode1_counter  = r.u64()?;
+        self.mode2_counter  = r.u64()?;
+        self.mode3_counter  = r.u64()?;
+        self.need_render    = r.bool()?;
+        self.need_new_line  = r.bool()?;
+        self.first_frame    = r.bool()?;
+        self.entered_hblank = r.bool()?;
+        r.bytes_into(&mut self.bg_cram)?;
+        r.bytes_into(&mut self.obj_cram)?;
+        self.bcps           = r.u8()?;
+        self.ocps           = r.u8()?;
+        Ok(())
+    }
     pub fn write8(&mut self, addr: u16, v: u8)  {
         match addr {
+            0xFF40 => {
+                let was_enabled = self.is_enabled();
+                self.regs[0] = v;
+                if was_enabled && !self.is_enabled() {
+                    self.turn_off();
+                } else if !was_enabled && self.is_enabled() {
+                    self.turn_on();
+                }
+            }
+            // CGB palettes, index with auto increment then data
+            0xFF68 => {self.bcps = v;}
+            0xFF69 => {
+                self.bg_cram[(self.bcps&0x3F) as usize] = v;
+                if self.bcps&0x80 != 0 {
+                    self.bcps = 0x80 | (self.bcps.wrapping_add(1)&0x3F);
+                }
+            }
+            0xFF6A => {self.ocps = v;}
+            0xFF6B => {
+                self.obj_cram[(self.ocps&0x3F) as usize] = v;
+                if self.ocps&0x80 != 0 {
+                    self.ocps = 0x80 | (self.ocps.wrapping_add(1)&0x3F);
+                }
+            }
             // DMA OAM, handled in mem.rs
             0xFF46 => {println!("ERROR OAM DMA {:04X} -> {:02X}", addr, v);}
             0xFF47 => {self.regs[(addr-0xFF40) as usize] = v;}
@@ -52,6 +128,10 @@ impl<'a> LCD<'a>{
     pub fn read8(&self, addr: u16) -> u8 {
         match addr {
             0xFF40..=0xFF4F => {self.regs[(addr-0xFF40) as usize]}
+            0xFF68 => {self.bcps | 0x40}
+            0xFF69 => {self.bg_cram[(self.bcps&0x3F) as usize]}
+            0xFF6A => {self.ocps | 0x40}
+            0xFF6B => {self.obj_cram[(self.ocps&0x3F) as usize]}
             _ => {error!("LCD read8 range error"); 0}
         }
     }
@@ -79,9 +159,48 @@ impl<'a> LCD<'a>{
         }
     }
 
+    // LY and mode go back to 0 and the PPU stays frozen until the LCD is enabled again
+    fn turn_off(&mut self) {
+        self.mode = 0;
+        self.mode0_counter = 0;
+        self.mode1_counter = 0;
+        self.mode2_counter = 0;
+        self.mode3_counter = 0;
+        self.vblank = false;
+        self.regs[4] = 0;
+        self.regs[1] &= 0b1111_1100;
+        // Blank the screen once
+        self.need_new_line = true;
+        self.need_render = true;
+    }
+
+    // Restart at line 0. The first frame after enabling the LCD is not displayed
+    fn turn_on(&mut self) {
+        self.counter = 0;
+        self.mode = 2;
+        let mut stat = self.regs[1] & 0b1111_1100;
+        stat |= 0b0000_0010;
+        if self.regs[5] == 0 {
+            stat |= 1 << 2;
+        } else {
+            stat &= !(1 << 2);
+        }
+        self.regs[1] = stat;
+        self.first_frame = true;
+    }
+
     pub fn update(&mut self, cur_cycles: u64) {
         self.counter += cur_cycles;
 
+        if !self.is_enabled() {
+            // Keep presenting the blank screen at the usual frame rate
+            if self.counter >= self.max_cycles {
+                self.counter = 0;
+                self.need_render = true;
+            }
+            return;
+        }
+
         match self.mode {
             // H-Blank
             0=>{
@@ -113,7 +232,11 @@ impl<'a> LCD<'a>{
                         stat &= 0b1111_1100;
                         stat = stat | 0x01;
                         self.vblank = true;
-                        self.need_render = true;
+                        if self.first_frame {
+                            self.first_frame = false;
+                        } else {
+                            self.need_render = true;
+                        }
                     } else {
                         self.mode = 2;
                         stat &= 0b1111_1100;
@@ -155,6 +278,7 @@ impl<'a> LCD<'a>{
                 self.mode3_counter+=cur_cycles;
                 if self.mode3_counter >= 43*4 {
                     self.need_new_line = true;
+                    self.entered_hblank = true;
                     self.mode3_counter = 0;
                     self.mode = 0;
                     let mut stat = self.read8(0xFF41) as u8;
@@ -179,6 +303,15 @@ impl<'a> LCD<'a>{
             false
         }
     }
+    // Used by the CGB H-Blank DMA
+    pub fn entered_hblank(&mut self) -> bool {
+        if self.entered_hblank {
+            self.entered_hblank = false;
+            true
+        } else {
+            false
+        }
+    }
     pub fn need_render(&mut self) -> bool {
         if self.need_render {
             self.need_render = false;
@@ -205,6 +338,13 @@ impl<'a> LCD<'a>{
     pub fn get_mode(&mut self) -> usize {
         self.mode
     }
+    pub fn is_enabled(&self) -> bool {
+        self.regs[0]&0b1000_0000 != 0
+    }
+    // Row of OAM (8 bytes, two sprites) being scanned during mode 2
+    pub fn get_oam_row(&self) -> usize {
+        (self.mode2_counter / 4) as usize
+    }
 
     pub fn int_stat(&mut self) -> bool {
         let mut s = self.read8(0xFF41) & 0b0000_0100;
@@ -237,4 +377,18 @@ impl<'a> LCD<'a>{
         self.get_palette(0xFF48+id)
     }
 
+    // CGB palettes hold 8 palettes of 4 RGB555 colors, returned as RGB888
+    fn get_cram_color(cram: &Vec<u8>, palette: u8, color: u8) -> (u8, u8, u8) {
+        let offset = (palette as usize)*8 + (color as usize)*2;
+        let v = (cram[offset] as u16) | ((cram[offset+1] as u16) << 8);
+        let convert = |c: u16| -> u8 { (((c&0x1F) << 3) | ((c&0x1F) >> 2)) as u8 };
+        (convert(v), convert(v >> 5), convert(v >> 10))
+    }
+    pub fn get_cgb_bg_color(&self, palette: u8, color: u8) -> (u8, u8, u8) {
+        LCD::get_cram_color(&self.bg_cram, palette, color)
+    }
+    pub fn get_cgb_obj_color(&self, palette: u8, color: u8) -> (u8, u8, u8) {
+        LCD::get_cram_color(&self.obj_cram, palette, color)
+    }
+
 }
@@ -0,0 +1,82 @@
+// Hæstkuk, a DMG-01 GameBoy emulator
+#[macro_use]
+extern crate log;
+extern crate minifb;
+
+pub mod mem;
+pub mod rom;
+pub mod lr35902;
+pub mod lcd;
+pub mod render;
+pub mod joypad;
+pub mod timer;
+pub mod MBC1;
+pub mod palette;
+pub mod config;
+pub mod sgb;
+pub mod headless;
+pub mod savestate;
+pub mod options;
+pub mod font;
+pub mod memview;
+pub mod debugger;
+pub mod gdbstub;
+pub mod symbols;
+pub mod disasm;
+pub mod trace;
+pub mod cdl;
+pub mod profiler;
+pub mod cheats;
+pub mod search;
+pub mod script;
+pub mod movie;
+pub mod input;
+
+use lr35902::Cpu;
+use render::Render;
+
+// 4.194304 MHz
+pub const CPU_MHZ: u64 = 4_194_304;
+
+// Run one instruction and the hardware for the cycles it took, returns the
+// cycles as seen by the PPU (halved in CGB double speed mode)
+pub fn step<'a>(cpu: &mut Cpu<'a>, render: &mut Render<'a>) -> u64 {
+    let mut script = cpu.take_script();
+    if let Some(ref mut s) = script {
+        s.before_step(cpu);
+    }
+    // A general purpose VRAM DMA stalls the CPU while the hardware goes on
+    let cur_cycles = cpu.step() as u64 + cpu.mem.take_stall_cycles();
+
+    // In CGB double speed mode the PPU runs at half the CPU rate
+    let lcd_cycles = if cpu.mem.is_double_speed() { cur_cycles/2 } else { cur_cycles };
+
+    cpu.mem.update(cur_cycles);
+    cpu.mem.timer.update(cur_cycles);
+    cpu.mem.lcd.update(lcd_cycles);
+
+    if cpu.mem.lcd.need_new_line() {
+        render.update_screen(cpu);
+    }
+    if cpu.mem.lcd.need_render() {
+        cpu.mem.apply_cheats();
+        if let Some(ref mut s) = script {
+            s.frame_end(cpu, render);
+        }
+        render.render_screen();
+        if let Some(ref mut s) = script {
+            s.take_screenshots(render);
+        }
+        if let Some(mut m) = cpu.take_movie() {
+            m.frame_end(cpu, render);
+            cpu.set_movie(Some(m));
+        }
+    }
+    if let Some(ref mut s) = script {
+        s.after_step(cpu);
+    }
+    cpu.set_script(script);
+
+    cpu.mem.joypad.update();
+    lcd_cycles
+}
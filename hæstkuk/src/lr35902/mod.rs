@@ -6,6 +6,13 @@ use std::thread::sleep;
 use std::time::Duration;
 use std::process;
 use mem;
+use savestate;
+use symbols::Symbols;
+use disasm;
+use cdl;
+use trace;
+use script;
+use movie;
 
 #[derive(Copy, Clone)]
 struct Opcode {
@@ -118,6 +125,22 @@ impl Registers {
 
 }
 
+// Shadow call stack entry, for backtraces
+#[derive(Clone, Copy, Debug)]
+pub struct CallFrame {
+    // CALL or RST instruction, or the interrupted one
+    pub bank: u16,
+    pub addr: u16,
+    pub target_bank: u16,
+    pub target: u16,
+    // Where the return address is
+    pub sp: u16,
+    pub interrupt: bool,
+}
+
+// Calls that never return would grow the shadow stack forever
+const CALL_STACK_MAX: usize = 256;
+
 pub struct Cpu<'a> {
     pub mem: mem::Mem<'a>,
     regs: Registers,
@@ -126,24 +149,32 @@ pub struct Cpu<'a> {
     alt_opcodes: Vec<Opcode>,
     halted: bool,
     disasm: bool,
+    symbols: Symbols,
+    trace: Option<trace::Trace>,
+    call_stack: Vec<CallFrame>,
+    script: Option<script::Script>,
+    movie: Option<movie::Movie>,
 }
 
 pub fn imm16(cpu: &mut Cpu) -> u16 {
-    cpu.mem.read16(cpu.regs.get_PC()+1)
+    let pc = cpu.regs.get_PC();
+    ((cpu.mem.fetch8(pc+2) as u16)<<8) | (cpu.mem.fetch8(pc+1) as u16)
 }
 pub fn imm8(cpu: &mut Cpu) -> u8 {
-    cpu.mem.read8(cpu.regs.get_PC()+1)
+    cpu.mem.fetch8(cpu.regs.get_PC()+1)
 }
 
 pub fn UNK(cpu: &mut Cpu) {
     println!("*** Unknow instruction at {:04X}", cpu.regs.get_PC());
     cpu.print_status();
+    cpu.print_backtrace();
     sleep(Duration::from_secs(5));
     process::exit(3);
 }
 pub fn ALTUNK(cpu: &mut Cpu) {
     println!("*** Unknow alternative instruction [{:02X}] at {:04X}", cpu.mem.read8(cpu.regs.get_PC()+1), cpu.regs.get_PC());
     cpu.print_status();
+    cpu.print_backtrace();
     process::exit(3);
 }
 pub fn alu_sub(cpu: &mut Cpu, b: u8, carry: bool) {
@@ -531,6 +562,11 @@ impl<'a> Cpu<'a>{
                     }; 256],
                 halted: false,
                 disasm: false,
+                symbols: Symbols::new(),
+                trace: None,
+                script: None,
+                movie: None,
+                call_stack: vec![],
         };
         cpu.opcodes[0] = Opcode {
             name: "NOP",
@@ -564,6 +600,7 @@ impl<'a> Cpu<'a>{
             cycles: 8,
             execute: |cpu|{
                 let bc = cpu.regs.get_BC();
+                cpu.mem.oam_bug_write(bc);
                 cpu.regs.set_BC(bc.wrapping_add(1));
             },
             jump: false,
@@ -638,6 +675,7 @@ impl<'a> Cpu<'a>{
             cycles: 4,
             execute: |cpu|{
                 let bc = cpu.regs.get_BC();
+                cpu.mem.oam_bug_write(bc);
                 cpu.regs.set_BC(bc.wrapping_sub(1));
             },
             jump: false,
@@ -685,7 +723,12 @@ impl<'a> Cpu<'a>{
             name: "STOP",
             len: 2,
             cycles: 12,
-            execute: |cpu|{ cpu.halted = true; },
+            execute: |cpu|{
+                // On CGB, STOP performs an armed speed switch instead
+                if !cpu.mem.switch_speed() {
+                    cpu.halted = true;
+                }
+            },
             jump: false,
         };
         cpu.opcodes[0x11] = Opcode {
@@ -713,6 +756,7 @@ impl<'a> Cpu<'a>{
             cycles: 8,
             execute: |cpu|{
                 let de = cpu.regs.get_DE();
+                cpu.mem.oam_bug_write(de);
                 cpu.regs.set_DE(de.wrapping_add(1));
             },
             jump: false,
@@ -782,6 +826,7 @@ impl<'a> Cpu<'a>{
             cycles: 4,
             execute: |cpu| {
                 let de = cpu.regs.get_DE();
+                cpu.mem.oam_bug_write(de);
                 cpu.regs.set_DE(de.wrapping_sub(1)) },
                 jump: false,
         };
@@ -844,6 +889,7 @@ impl<'a> Cpu<'a>{
             cycles: 8,
             execute: |cpu|{
                 let hl = cpu.regs.get_HL();
+                cpu.mem.oam_bug_write(hl);
                 cpu.regs.set_HL(hl.wrapping_add(1));
             },
             jump: false,
@@ -908,7 +954,7 @@ impl<'a> Cpu<'a>{
             name: "DEC HL",
             len: 1,
             cycles: 8,
-            execute: |cpu|{let v = cpu.regs.get_HL().wrapping_sub(1); cpu.regs.set_HL(v);},
+            execute: |cpu|{let hl = cpu.regs.get_HL(); cpu.mem.oam_bug_write(hl); cpu.regs.set_HL(hl.wrapping_sub(1));},
             jump: false,
         };
         cpu.opcodes[0x2C] = Opcode {
@@ -968,6 +1014,7 @@ impl<'a> Cpu<'a>{
             cycles: 8,
             execute: |cpu|{
                 let sp = cpu.regs.get_SP();
+                cpu.mem.oam_bug_write(sp);
                 cpu.regs.set_SP(sp.wrapping_add(1));
             },
             jump: false,
@@ -1034,6 +1081,7 @@ impl<'a> Cpu<'a>{
             len: 1,
             cycles: 4,
             execute: |cpu| {
+                cpu.mem.oam_bug_write(cpu.regs.SP);
                 cpu.regs.SP = cpu.regs.SP.wrapping_sub(1);
             },
             jump: false,
@@ -4458,39 +4506,6 @@ impl<'a> Cpu<'a>{
         self.mem.write8(addr, v)
     }
 
-    pub fn get_opcode_args(&mut self, codestr: &str) -> String {
-        let mut code_str = String::from(codestr);
-        if code_str.contains("r8") {
-            code_str = code_str.replace("r8", &String::from(format!("0x{:02X}",imm8(self) as i8)));
-        }
-        if code_str.contains("a8") {
-            code_str = code_str.replace("a8", &String::from(format!("0x{:02X}",imm8(self) as i8)));
-        }
-        if code_str.contains("d8") {
-            code_str = code_str.replace("d8", &String::from(format!("0x{:02X}",imm8(self) as i8)));
-        }
-        if code_str.contains("a16") {
-            code_str = code_str.replace("a16", &String::from(format!("0x{:04X}",imm16(self) as i16)));
-        }
-        if code_str.contains("d16") {
-            code_str = code_str.replace("d16", &String::from(format!("0x{:04X}",imm16(self) as i16)));
-        }
-        if code_str.contains("(HL)") {
-            let hl = self.mem.read8(self.regs.get_HL());
-            code_str = code_str.replace("(HL)", &String::from(format!("0x{:02X}", hl as i8)));
-        }
-        if code_str.contains("(HL-)") {
-            let hl = self.regs.get_HL();
-            code_str = code_str.replace("(HL-)", &String::from(format!("(0x{:04X})", hl as u16)));
-        }
-        if code_str.contains("(HL+)") {
-            let hl = self.regs.get_HL();
-            code_str = code_str.replace("(HL+)", &String::from(format!("(0x{:04X})", hl as u16)));
-        }
-
-        code_str
-    }
-
     pub fn print_status(&mut self) {
         let code = self.mem.read8(self.regs.PC) as usize;
         let alt_code = self.mem.read8(self.regs.PC+1) as usize;
@@ -4509,32 +4524,27 @@ impl<'a> Cpu<'a>{
     }
 
     pub fn print_status_small(&mut self) {
-        let code = self.mem.read8(self.regs.PC) as usize;
-        let alt_code = self.mem.read8(self.regs.PC+1) as usize;
-        let opcode;
-        let codestr =
-            if code == 0xCB {
-                opcode = self.alt_opcodes[alt_code];
-                format!("{:02X} {:02X}", code, alt_code)
-            } else {
-                opcode = self.opcodes[code];
-                format!("{:02X}", code)
-            };
+        let pc = self.regs.PC;
+        let bytes = [self.mem.peek8(pc), self.mem.peek8(pc.wrapping_add(1)), self.mem.peek8(pc.wrapping_add(2))];
+        let instr = disasm::decode(&bytes);
+        let codestr: Vec<String> = bytes[..instr.len as usize].iter().map(|b| format!("{:02X}", b)).collect();
         let foo = (self.regs.get_SP(), self.regs.get_FZ(), self.regs.get_FN(),self.regs.get_FH(),self.regs.get_FC());
-        let disas = self.get_opcode_args(opcode.name);
-        println!("{:04X}: {: <16}\t{}\tA {:02X} B {:02X} C {:02X} D {:02X} E {:02X} F {:02X} H {:02X} L {:02X}\tSP: {:04X} Z:{: <5} N:{: <5} H:{: <5} C:{: <5}", self.regs.PC, disas, codestr,
+        let mut disas = instr.format(pc);
+        if let Some(label) = self.symbols.label(self.symbol_bank(pc), pc) {
+            println!("{}:", label);
+        }
+        // Jump and call targets
+        if let Some(label) = instr.target(pc).and_then(|t| self.describe_address(t)) {
+            disas = format!("{} <{}>", disas, label);
+        }
+        println!("{:04X}: {: <16}\t{}\tA {:02X} B {:02X} C {:02X} D {:02X} E {:02X} F {:02X} H {:02X} L {:02X}\tSP: {:04X} Z:{: <5} N:{: <5} H:{: <5} C:{: <5}", pc, disas, codestr.join(" "),
                  self.regs.A,self.regs.B,self.regs.C,self.regs.D,
                  self.regs.E,self.regs.F,self.regs.H,self.regs.L,
                  foo.0, foo.1, foo.2, foo.3, foo.4
                 );
     }
     pub fn print_dump(&mut self) {
-        let pc = self.regs.get_PC();
-        println!("A: {:02X} F: {:02X} B: {:02X} C: {:02X} D: {:02X} E: {:02X} H: {:02X} L: {:02X} SP: {:04X} PC: 00:{:04X} ({:02X} {:02X} {:02X} {:02X})",
-        self.regs.A, self.regs.F, self.regs.B,self.regs.C,self.regs.D,
-        self.regs.E,self.regs.H,self.regs.L, self.regs.get_SP(), self.regs.get_PC(),
-        self.mem.read8(pc), self.mem.read8(pc+1),self.mem.read8(pc+2),self.mem.read8(pc+3));
-
+        println!("{}", trace::format_line(self));
     }
     pub fn interrupts_enabled(&mut self) -> bool {
         self.regs.I
@@ -4542,7 +4552,210 @@ impl<'a> Cpu<'a>{
 
     pub fn reset(&mut self) {
         println!("RESET");
+        self.call_stack.clear();
         self.regs.PC = 0x0000;
+        // There is no CGB boot ROM, start the cartridge directly
+        if self.mem.is_cgb() || !self.mem.has_bootrom() {
+            self.post_boot();
+        }
+    }
+
+    // State left by the boot ROM when it jumps to the cartridge, also used to skip it
+    pub fn post_boot(&mut self) {
+        self.mem.disable_bootrom();
+        if self.mem.is_cgb() {
+            self.regs.A = 0x11; self.regs.F = 0x80;
+            self.regs.B = 0x00; self.regs.C = 0x00;
+            self.regs.D = 0xFF; self.regs.E = 0x56;
+            self.regs.H = 0x00; self.regs.L = 0x0D;
+        } else {
+            self.regs.A = 0x01; self.regs.F = 0xB0;
+            self.regs.B = 0x00; self.regs.C = 0x13;
+            self.regs.D = 0x00; self.regs.E = 0xD8;
+            self.regs.H = 0x01; self.regs.L = 0x4D;
+        }
+        self.regs.SP = 0xFFFE;
+        self.regs.PC = 0x0100;
+        self.mem.write8(0xFF47, 0xFC);
+        self.mem.write8(0xFF40, 0x91);
+    }
+
+    pub fn save_state(&self, w: &mut savestate::Writer) {
+        let r = &self.regs;
+        for &v in [r.A, r.B, r.C, r.D, r.E, r.F, r.H, r.L].iter() {
+            w.u8(v);
+        }
+        w.u16(r.PC);
+        w.u16(r.SP);
+        w.bool(r.I);
+        w.u64(self.total_cyles);
+        w.bool(self.halted);
+        self.mem.save_state(w);
+    }
+    pub fn load_state(&mut self, r: &mut savestate::Reader) -> Result<(), String> {
+        self.regs.A = r.u8()?; self.regs.B = r.u8()?;
+        self.regs.C = r.u8()?; self.regs.D = r.u8()?;
+        self.regs.E = r.u8()?; self.regs.F = r.u8()?;
+        self.regs.H = r.u8()?; self.regs.L = r.u8()?;
+        self.regs.PC = r.u16()?;
+        self.regs.SP = r.u16()?;
+        self.regs.I  = r.bool()?;
+        self.total_cyles = r.u64()?;
+        self.halted      = r.bool()?;
+        self.call_stack.clear();
+        self.mem.load_state(r)
+    }
+
+    pub fn get_pc(&self) -> u16 {
+        self.regs.PC
+    }
+    pub fn get_sp(&self) -> u16 {
+        self.regs.SP
+    }
+
+    // Register by name, as used by the debugger
+    pub fn get_reg(&self, name: &str) -> Option<u16> {
+        let r = self.regs;
+        match name.to_uppercase().as_str() {
+            "A"  => Some(r.A as u16),
+            "F"  => Some(r.F as u16),
+            "B"  => Some(r.B as u16),
+            "C"  => Some(r.C as u16),
+            "D"  => Some(r.D as u16),
+            "E"  => Some(r.E as u16),
+            "H"  => Some(r.H as u16),
+            "L"  => Some(r.L as u16),
+            "AF" => Some(r.get_AF()),
+            "BC" => Some(r.get_BC()),
+            "DE" => Some(r.get_DE()),
+            "HL" => Some(r.get_HL()),
+            "SP" => Some(r.get_SP()),
+            "PC" => Some(r.get_PC()),
+            _    => None,
+        }
+    }
+    // Returns false for an unknown register
+    pub fn set_reg(&mut self, name: &str, v: u16) -> bool {
+        match name.to_uppercase().as_str() {
+            "A"  => self.regs.A = v as u8,
+            "F"  => self.regs.F = (v as u8) & 0xF0,
+            "B"  => self.regs.B = v as u8,
+            "C"  => self.regs.C = v as u8,
+            "D"  => self.regs.D = v as u8,
+            "E"  => self.regs.E = v as u8,
+            "H"  => self.regs.H = v as u8,
+            "L"  => self.regs.L = v as u8,
+            "AF" => self.regs.set_AF(v),
+            "BC" => self.regs.set_BC(v),
+            "DE" => self.regs.set_DE(v),
+            "HL" => self.regs.set_HL(v),
+            "SP" => self.regs.set_SP(v),
+            "PC" => { self.regs.set_PC(v); self.halted = false },
+            _    => return false,
+        }
+        true
+    }
+    // Waiting for an interrupt after HALT
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+    pub fn get_symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+    // Bank of addr as written in symbol files, None outside of the banked areas
+    pub fn symbol_bank(&self, addr: u16) -> Option<u16> {
+        if self.mem.get_bank_count(addr) > 1 { Some(self.mem.get_bank(addr)) } else { None }
+    }
+    // "label+offset" for addr as currently mapped
+    pub fn describe_address(&self, addr: u16) -> Option<String> {
+        self.symbols.describe(self.symbol_bank(addr), addr)
+    }
+
+    pub fn get_call_stack(&self) -> &[CallFrame] {
+        &self.call_stack
+    }
+
+    fn push_call(&mut self, addr: u16, interrupt: bool) {
+        if self.call_stack.len() == CALL_STACK_MAX {
+            self.call_stack.remove(0);
+        }
+        let frame = CallFrame {
+            bank: self.mem.get_bank(addr),
+            addr: addr,
+            target_bank: self.mem.get_bank(self.regs.PC),
+            target: self.regs.PC,
+            sp: self.regs.SP,
+            interrupt: interrupt,
+        };
+        self.call_stack.push(frame);
+    }
+
+    // Frames whose return address was popped are gone, by RET or by hand
+    fn prune_calls(&mut self) {
+        while self.call_stack.last().map_or(false, |f| f.sp < self.regs.SP) {
+            self.call_stack.pop();
+        }
+    }
+
+    // Current PC then the call sites, innermost first
+    pub fn backtrace(&self) -> Vec<String> {
+        let describe = |bank: u16, addr: u16| {
+            let label = self.symbols.describe(self.symbol_bank(addr).map(|_| bank), addr)
+                .map_or(String::new(), |l| format!(" <{}>", l));
+            format!("{:02X}:{:04X}{}", bank, addr, label)
+        };
+        let mut lines = vec![format!("#0  {}", describe(self.mem.get_bank(self.regs.PC), self.regs.PC))];
+        for (i, f) in self.call_stack.iter().rev().enumerate() {
+            lines.push(format!("#{:<2} {}{}", i+1, describe(f.bank, f.addr),
+                               if f.interrupt { format!(", interrupted by {:04X}", f.target) } else { String::new() }));
+        }
+        lines
+    }
+    pub fn print_backtrace(&self) {
+        println!("Backtrace:");
+        for l in self.backtrace() {
+            println!("  {}", l);
+        }
+    }
+
+    // Log each instruction to the trace
+    pub fn set_trace(&mut self, trace: trace::Trace) {
+        self.trace = Some(trace);
+    }
+    pub fn flush_trace(&mut self) {
+        if let Some(ref mut t) = self.trace {
+            t.flush();
+        }
+    }
+
+    // Taken out by each step to run its hooks
+    pub fn set_script(&mut self, script: Option<script::Script>) {
+        self.script = script;
+    }
+    pub fn take_script(&mut self) -> Option<script::Script> {
+        self.script.take()
+    }
+    // Some(passed) once the script asked to quit
+    pub fn get_script_quit(&self) -> Option<bool> {
+        self.script.as_ref().and_then(|s| s.get_quit())
+    }
+
+    pub fn set_movie(&mut self, movie: Option<movie::Movie>) {
+        self.movie = movie;
+    }
+    pub fn take_movie(&mut self) -> Option<movie::Movie> {
+        self.movie.take()
+    }
+    pub fn get_movie(&self) -> Option<&movie::Movie> {
+        self.movie.as_ref()
+    }
+    // Recording or playing, a finished playback doesn't count
+    pub fn has_movie(&self) -> bool {
+        self.movie.as_ref().map_or(false, |m| m.get_mode() != movie::Mode::Finished)
     }
 
     pub fn toggle_disasm(&mut self) {
@@ -4553,11 +4766,11 @@ impl<'a> Cpu<'a>{
         let mut cycles = 1;
 
         if self.halted == false {
-            let code = self.mem.read8(self.regs.PC) as usize;
+            let code = self.mem.fetch8(self.regs.PC) as usize;
 
             let opcode;
             if code == 0xCB {
-                let code = self.mem.read8(self.regs.PC+1) as usize;
+                let code = self.mem.fetch8(self.regs.PC+1) as usize;
                 opcode = self.alt_opcodes[code];
             } else {
                 opcode = self.opcodes[code];
@@ -4566,11 +4779,12 @@ impl<'a> Cpu<'a>{
             if self.disasm {
                 self.print_status_small();
             }
-            if self.regs.PC > 0x00FF || (self.mem.is_bootrom_enabled() == false) {
-      //          self.print_dump();
-            //        self.print_status_small();
-                //            println!("I: {}  IFLAG {:08b} ", self.regs.I, self.mem.read8(0xFF0F));
+            if let Some(mut t) = self.trace.take() {
+                t.record(self);
+                self.trace = Some(t);
             }
+            let pc = self.regs.PC;
+            let sp = self.regs.SP;
             (opcode.execute)(self);
 
             self.total_cyles = self.total_cyles + opcode.cycles as u64;
@@ -4584,11 +4798,41 @@ impl<'a> Cpu<'a>{
          //       self.mem.write8(0xff02, 0x0);
          //   }
             cycles = opcode.cycles;
+
+            // Taken CALL or RST: a jump that pushed the return address
+            let taken = opcode.jump && self.regs.PC != pc.wrapping_add(opcode.len);
+            let called = taken && self.regs.SP == sp.wrapping_sub(2);
+            if called {
+                self.push_call(pc, false);
+            } else if !self.call_stack.is_empty() {
+                self.prune_calls();
+            }
+            if taken && self.mem.get_cdl().is_some() {
+                let target = self.regs.PC;
+                if called {
+                    self.mem.cdl_mark(target, cdl::SUB_ENTRY_POINT);
+                } else if self.regs.SP == sp {
+                    // Not a return
+                    self.mem.cdl_mark(target, cdl::JUMP_TARGET);
+                }
+            }
+
+            if self.mem.get_profiler().is_some() {
+                let bank = self.mem.get_bank(pc);
+                let target = self.regs.PC;
+                let target_bank = self.mem.get_bank(target);
+                let p = self.mem.get_profiler_mut().unwrap();
+                p.instruction(bank, pc, opcode.cycles);
+                if called {
+                    p.call(target_bank, target);
+                }
+            }
         }
         /* Interrupts */
 
-        let ie = self.mem.read8(0xFFFF);
-        let mut iflag = self.mem.read8(0xFF0F);
+        // Not bus accesses, peek them
+        let ie = self.mem.peek8(0xFFFF);
+        let mut iflag = self.mem.peek8(0xFF0F);
 
         if self.mem.lcd.int_vblank() {
             iflag = iflag | (1 << 0);
@@ -4600,7 +4844,7 @@ impl<'a> Cpu<'a>{
             iflag = iflag | (1 << 2);
         }
         // Serial
-        if self.mem.read8(0xFF02) == 0x81 {
+        if self.mem.peek8(0xFF02) == 0x81 {
             iflag = iflag | (1<<3);
             //let _c = self.mem.read8(0xFF01);
             //println!("SERIAL got {:02X} ({})", _c, _c as char);
@@ -4613,6 +4857,7 @@ impl<'a> Cpu<'a>{
             self.halted = false;
         }
 
+        let interrupted = self.regs.PC;
         if self.regs.I {
             if (ie&0b0000_0001)!=0 && (iflag&0b0000_0001)!=0 { // VBLANK
                 //println!("INT VBLANK");
@@ -4638,14 +4883,17 @@ impl<'a> Cpu<'a>{
                 self.regs.PC = 0x0058;
                 DI(self);
             } else if ((ie&0b0001_0000)!=0) && (iflag&0b0001_0000)!=0 { // Joypad
-                println!("INT Joypad");
+                debug!("INT Joypad");
                 iflag = iflag & !(1 << 4);
                 PushStack(self, self.regs.PC);
                 self.regs.PC = 0x0060;
                 DI(self);
             }
         }
-        self.mem.write8(0xFF0F, iflag);
+        self.mem.set_interrupt_flags(iflag);
+        if self.regs.PC != interrupted {
+            self.push_call(interrupted, true);
+        }
 
         cycles as u8
     }
@@ -1,27 +1,113 @@
-#[macro_use]
-extern crate log;
 extern crate env_logger;
+extern crate haestkuk;
 use std::io;
+use std::io::{BufRead, Write};
 use std::env;
+use std::fs;
 use std::process;
-mod mem;
-mod rom;
-mod lr35902;
-mod lcd;
-mod render;
-mod joypad;
-mod timer;
-mod MBC1;
-
-extern crate minifb;
-
-const CPU_MHZ: u64 = 4_194_304;
+use std::path::Path;
+use haestkuk::{mem, rom, lr35902, lcd, render, joypad, timer, palette, config, headless, options, savestate, debugger, gdbstub, symbols, disasm, trace, cdl, profiler, cheats, script, movie, input};
+use haestkuk::CPU_MHZ;
+
 const REFRESH_CYCLES : u64 = (CPU_MHZ  as f64 / 59.727500569606) as u64;
 
-// 4.194304 MHz
-fn main() {
-    env_logger::init();
+// Debugger console on stdin, returns false to quit
+fn debug_console<'a>(debugger: &mut debugger::Debugger<'a>, cpu: &mut lr35902::Cpu<'a>) -> bool {
+    let stdin = io::stdin();
+    loop {
+        print!("(hkdb) ");
+        io::stdout().flush().unwrap();
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => return false,
+            Ok(_) => {},
+        }
+        match debugger.command(cpu, &line) {
+            Ok(debugger::Action::Prompt) => {},
+            Ok(debugger::Action::Resume) => return true,
+            Ok(debugger::Action::Quit)   => return false,
+            Err(e) => println!("Error: {}", e),
+        }
+    }
+}
+
+// "disasm <rom> [output]": disassemble the whole ROM, with its symbols
+fn disasm_command(args: &[String]) -> i32 {
+    if args.is_empty() || args.len() > 2 {
+        println!("Usage: disasm <rom.gb> [output.asm]");
+        return 2;
+    }
+    let rom = match rom::ROM::new(args[0].clone()) {
+        Ok(r) => r,
+        Err(e) => {
+            println!("Error: {}", e);
+            return 1;
+        },
+    };
+    let sym_path = Path::new(&args[0]).with_extension("sym");
+    let symbols = symbols::Symbols::load(&sym_path.to_string_lossy()).unwrap_or(symbols::Symbols::new());
+    let text = disasm::disassemble_rom(&rom.buffer, &symbols);
+    match args.get(1) {
+        Some(path) => {
+            if let Err(e) = fs::write(path, text) {
+                println!("Error: {}: {}", path, e);
+                return 1;
+            }
+        },
+        None => print!("{}", text),
+    }
+    0
+}
+
+// Save the Code/Data Logger file and show its coverage
+fn save_cdl(cpu: &lr35902::Cpu, path: &Option<String>) {
+    if let (Some(path), Some(cdl)) = (path.as_ref(), cpu.mem.get_cdl()) {
+        match cdl.save(path) {
+            Ok(_)  => print!("CDL saved to {}\n{}", path, cdl.format_stats()),
+            Err(e) => println!("Can't save CDL {}: {}", path, e),
+        }
+    }
+}
+
+// Save the profiler report, and its heatmap next to it
+fn save_profile(cpu: &lr35902::Cpu, frames: u64, path: &Option<String>) {
+    if let (Some(path), Some(profiler)) = (path.as_ref(), cpu.mem.get_profiler()) {
+        let heatmap = Path::new(path).with_extension("png").to_string_lossy().into_owned();
+        if let Err(e) = fs::write(path, profiler.report(cpu.get_symbols(), frames)) {
+            println!("Can't save profile {}: {}", path, e);
+        } else if let Err(e) = profiler.save_heatmap(&heatmap) {
+            println!("Can't save heatmap {}: {}", heatmap, e);
+        } else {
+            println!("Profile saved to {}, heatmap to {}", path, heatmap);
+        }
+    }
+}
 
+// Save a recorded movie, or tell how the playback went
+fn end_movie(cpu: &mut lr35902::Cpu) {
+    if let Some(m) = cpu.get_movie() {
+        if m.get_mode() == movie::Mode::Recording {
+            if let Err(e) = m.save() {
+                println!("Error: {}", e);
+                return;
+            }
+        }
+        if m.get_mode() != movie::Mode::Finished {
+            println!("{}", m.summary());
+        }
+    }
+}
+
+// Flush and save everything that is written at exit
+fn shutdown(cpu: &mut lr35902::Cpu, render: &mut render::Render, options: &options::Options) {
+    render.stop_recording();
+    cpu.flush_trace();
+    end_movie(cpu);
+    save_cdl(cpu, &options.cdl);
+    save_profile(cpu, render.get_frame_count(), &options.profile);
+}
+
+fn main() {
     let lcd: lcd::LCD;
     let timer: timer::Timer;
     let joypad: joypad::Joypad;
@@ -30,17 +116,33 @@ fn main() {
     let mem: mem::Mem;
     let mut render: render::Render;
 
-    println!("Hæstkuk.");
-
     /* Parse arguments */
     let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        println!("Usage:\n\t{} <rom.gb>", args[0]);
-        process::exit(2);
+    if args.get(1).map_or(false, |a| a == "disasm") {
+        process::exit(disasm_command(&args[2..]));
+    }
+    let options = match options::parse(&args[1..]) {
+        Ok(o) => o,
+        Err(e) => {
+            println!("Error: {}\n\n{}", e, options::usage(&args[0]));
+            process::exit(2);
+        },
+    };
+    if options.help {
+        print!("{}", options::usage(&args[0]));
+        process::exit(0);
+    }
+
+    let mut logger = env_logger::Builder::from_default_env();
+    if let Some(level) = options.log_level {
+        logger.filter_level(level);
     }
+    logger.init();
 
-    let v: io::Result<rom::ROM> = rom::ROM::new(String::from(args[1].clone()));
-    /* Read first argument and create a ROM from that */
+    println!("Hæstkuk.");
+
+    let v: io::Result<rom::ROM> = rom::ROM::new(options.rom.clone());
+    /* Create a ROM from the file */
     match v {
         Ok(_v) => rom = _v.clone(),
         Err(_e) => {
@@ -49,44 +151,240 @@ fn main() {
         },
     }
     rom.print_infos();
+    let compat_palette = palette::cgb_compat(&rom);
+    let mut sgb = options.sgb;
+    if sgb && (!rom.is_sgb() || rom.is_cgb()) {
+        println!("The cartridge has no SGB support, running as DMG");
+        sgb = false;
+    }
 
     timer  = timer::Timer::new(CPU_MHZ);
     lcd    = lcd::LCD::new();
     joypad = joypad::Joypad::new();
     mem    = mem::Mem::new(rom, lcd, joypad, timer);
     cpu    = lr35902::Cpu::new(mem);
-    render = render::Render::new();
+    if sgb {
+        cpu.mem.sgb.enable();
+    }
+    if let Some(ref path) = options.trace {
+        match trace::Trace::create(path, options.trace_start, options.trace_stop) {
+            Ok(t) => cpu.set_trace(t),
+            Err(e) => {
+                println!("Can't create trace {}: {}", path, e);
+                process::exit(1);
+            },
+        }
+    }
+    if let Some(ref path) = options.cdl {
+        let log = {
+            let rom = &cpu.mem.get_rom().buffer;
+            if Path::new(path).exists() { cdl::Cdl::load(path, rom) } else { Ok(cdl::Cdl::new(rom)) }
+        };
+        match log {
+            Ok(c) => cpu.mem.set_cdl(c),
+            Err(e) => {
+                println!("Can't read CDL {}: {}", path, e);
+                process::exit(1);
+            },
+        }
+    }
+    if options.profile.is_some() {
+        cpu.mem.set_profiler(profiler::Profiler::new());
+    }
+    // RGBDS symbols next to the ROM
+    let sym_path = Path::new(&options.rom).with_extension("sym").to_string_lossy().into_owned();
+    if Path::new(&sym_path).exists() {
+        match symbols::Symbols::load(&sym_path) {
+            Ok(s) => {
+                println!("{} symbols from {}", s.len(), sym_path);
+                cpu.set_symbols(s);
+            },
+            Err(e) => println!("Can't read symbols {}: {}", sym_path, e),
+        }
+    }
+    // Cheat codes next to the ROM
+    let cheats_path = Path::new(&options.rom).with_extension("cht").to_string_lossy().into_owned();
+    if Path::new(&cheats_path).exists() {
+        match cheats::Cheats::load(&cheats_path) {
+            Ok(c) => {
+                println!("{} cheats from {}", c.len(), cheats_path);
+                cpu.mem.set_cheats(c);
+            },
+            Err(e) => println!("Can't read cheats {}: {}", cheats_path, e),
+        }
+    }
+    // Without a boot ROM, reset starts the cartridge directly
+    if !options.skip_boot {
+        if let Err(e) = cpu.mem.load_bootrom(&options.bootrom) {
+            println!("Can't read boot ROM {}: {}, skipping it", options.bootrom, e);
+        }
+    }
+
+    render = if options.headless {
+        render::Render::new_headless(sgb)
+    } else {
+        render::Render::new(sgb, options.scale, options.debug_windows)
+    };
+    render.set_speed(options.speed);
+    render.set_compat_palette(compat_palette);
+    if let Some(ref path) = options.record {
+        render.set_record_path(path);
+        render.start_recording();
+    }
+
+    /* Palettes from the configuration file, then the one selected by the config or --palette */
+    let config = config::Config::load(config::DEFAULT_PATH).unwrap_or_default();
+    for (name, spec) in config.section("palettes") {
+        match palette::parse(&spec) {
+            Ok(p)  => render.add_palette(&name, p),
+            Err(e) => println!("Palette '{}': {}", name, e),
+        }
+    }
+    let mut bindings = input::Input::new();
+    if let Err(e) = bindings.configure(&config) {
+        println!("Error: {}: {}", config::DEFAULT_PATH, e);
+        process::exit(2);
+    }
+    if !options.headless {
+        bindings.open_gamepads();
+    }
+    render.set_input(bindings);
+    let palette_spec = options.palette.clone().or(config.get("options", "palette").map(|p| p.to_string()));
+    // The boot logo button combo only applies without an explicit palette
+    let mut boot_combo = palette_spec.is_none() && cpu.mem.is_bootrom_enabled();
+    if let Some(spec) = palette_spec {
+        if let Err(e) = render.select_palette(&spec) {
+            println!("Error: {}", e);
+            process::exit(2);
+        }
+    }
 
     let mut refresh_counter: i64 = REFRESH_CYCLES as i64;
+    let mut debugger = debugger::Debugger::new();
+    if options.debug {
+        debugger.request_break();
+    }
+    let mut gdb = match options.gdb {
+        Some(port) => match gdbstub::GdbStub::listen(port) {
+            Ok(g) => Some(g),
+            Err(e) => {
+                println!("Error: {}", e);
+                process::exit(1);
+            },
+        },
+        None => None,
+    };
 
     cpu.reset();
+    if let Some(ref path) = options.state {
+        if let Err(e) = savestate::load(&mut cpu, path) {
+            println!("Error: {}", e);
+            process::exit(1);
+        }
+    }
+    // Movies start from here, before the script can change anything
+    if let Some(ref path) = options.movie_record {
+        let m = movie::Movie::record(path, &mut cpu, options.state.is_some());
+        cpu.set_movie(Some(m));
+    }
+    if let Some(ref path) = options.movie_play {
+        match movie::Movie::play(path, &mut cpu) {
+            Ok(m) => cpu.set_movie(Some(m)),
+            Err(e) => {
+                println!("Error: {}", e);
+                process::exit(1);
+            },
+        }
+    }
+    if let Some(ref path) = options.script {
+        match script::Script::load(path, &mut cpu) {
+            Ok(s) => cpu.set_script(Some(s)),
+            Err(e) => {
+                println!("Error: {}", e);
+                process::exit(1);
+            },
+        }
+    }
 
+    if options.headless {
+        let mut run = headless::Options::new();
+        if let Some(n) = options.frames {
+            run.frames = n;
+        }
+        run.pass_serial = options.until_serial.clone();
+        run.fail_serial = options.fail_serial.clone();
+        run.pass_pc     = options.until_pc;
+        run.pass_loop   = options.until_loop;
+        run.png         = options.png.clone();
+        run.print_serial = !options.no_serial;
 
-    loop {
-        let cur_cycles = cpu.step() as u64;
-
-        cpu.mem.timer.update(cur_cycles);
-        cpu.mem.lcd.update(cur_cycles);
+        let outcome = headless::run(&mut cpu, &mut render, &run);
+        shutdown(&mut cpu, &mut render, &options);
+        println!("\n{:?} after {} frames", outcome, render.get_frame_count());
+        process::exit(outcome.exit_code());
+    }
 
-        if cpu.mem.lcd.need_new_line() {
-            render.update_screen(&mut cpu);
+    loop {
+        if render.is_paused() {
+            render.refresh_paused(&mut cpu);
+            if render.get_events(&mut cpu) {
+                println!("EXIT");
+                break;
+            }
+            continue;
         }
-        if cpu.mem.lcd.need_render() {
-            render.render_screen();
+        if render.take_break_request() {
+            debugger.request_break();
         }
+        if let Some(stop) = debugger.check(&mut cpu) {
+            let reason = stop.describe();
+            if !reason.is_empty() {
+                println!("{}", reason);
+            }
+            let resume = match gdb {
+                Some(ref mut g) if g.is_connected() => match g.stopped(&mut debugger, &mut cpu, &stop) {
+                    debugger::Action::Quit => false,
+                    _ => true,
+                },
+                _ => {
+                    debugger::Debugger::print_location(&mut cpu);
+                    debug_console(&mut debugger, &mut cpu)
+                },
+            };
+            if !resume {
+                println!("EXIT");
+                break;
+            }
+        }
+        let lcd_cycles = haestkuk::step(&mut cpu, &mut render);
 
-        cpu.mem.joypad.update();
+        // Like the CGB boot ROM, a button combo held at the end of the boot logo picks a palette
+        if boot_combo && !cpu.mem.is_bootrom_enabled() {
+            boot_combo = false;
+            if let Some(p) = palette::cgb_combo(cpu.mem.joypad.get_buttons()) {
+                render.set_compat_palette(p);
+                render.select_palette("cgb").unwrap();
+            }
+        }
 
-        refresh_counter-=cur_cycles as i64;
+        refresh_counter-=lcd_cycles as i64;
         if refresh_counter <= 0 {
             refresh_counter = REFRESH_CYCLES as i64;
-            render.display_BG_map(&mut cpu);
-            render.display_tile_pattern_tables (&mut cpu);
+            render.update_debug_windows(&mut cpu);
+            if let Some(ref mut g) = gdb {
+                g.poll(&mut debugger, &mut cpu);
+            }
         }
         if render.get_events(&mut cpu) {
             println!("EXIT");
             break;
         }
-
+        if options.frames.map_or(false, |n| render.get_frame_count() >= n) {
+            break;
+        }
+        if cpu.get_script_quit().is_some() {
+            break;
+        }
     }
+    shutdown(&mut cpu, &mut render, &options);
 }
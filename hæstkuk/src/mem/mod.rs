@@ -1,5 +1,6 @@
 #![allow(unused_variables)]
 #![allow(dead_code)]
+use std::io;
 use std::fs::File;
 use std::io::Read;
 use rom;
@@ -7,6 +8,11 @@ use lcd;
 use joypad;
 use timer;
 use MBC1;
+use sgb;
+use savestate;
+use cdl;
+use profiler;
+use cheats;
 // Memory controller
 #[derive(Clone, Debug, Default)]
 pub struct Mem<'a> {
@@ -18,16 +24,73 @@ pub struct Mem<'a> {
     pub lcd:  lcd::LCD<'a>,
     pub joypad: joypad::Joypad<'a>,
     pub timer: timer::Timer<'a>,
+    pub sgb: sgb::Sgb<'a>,
     mbc1_bank: u8,
     ram_bank: u8,
     ram_mode: bool,
     ram_enabled: bool,
     mbc1: MBC1::MBC1<'a>,
+    dma_reg: u8,
+    dma_active: bool,
+    dma_source: u16,
+    dma_offset: u16,
+    dma_cycles: u64,
+    // CGB
+    cgb: bool,
+    double_speed: bool,
+    speed_switch: bool,
+    vram_bank: u8,
+    vram1: Vec<u8>,
+    wram_bank: u8,
+    wram: Vec<u8>,
+    hdma_source: u16,
+    hdma_dest: u16,
+    hdma_blocks: u8,
+    hdma_active: bool,
+    // CPU cycles the last instruction was stalled by a general purpose DMA
+    stall_cycles: u64,
+    // Bytes sent over the link cable, with the internal clock
+    serial: Vec<u8>,
+    // Debugger watchpoints, and the first one hit by the CPU
+    watches: Vec<Watch>,
+    watch_hit: Option<WatchHit>,
+    // Code/Data Logger, and the last ROM byte read as data, to catch its copy to the tiles
+    cdl: Option<cdl::Cdl>,
+    cdl_last_read: Option<(usize, u8)>,
+    profiler: Option<profiler::Profiler>,
+    // Addresses watched by script hooks, and the writes to them since the last step
+    write_hooks: Vec<u16>,
+    write_hits: Vec<(u16, u8)>,
+    // Game Genie patches on ROM reads, GameShark writes each frame
+    cheats: cheats::Cheats,
 }
 
+// Read or write watchpoint on an address range, in a given bank or any of them
+#[derive(Clone, Copy, Debug)]
+pub struct Watch {
+    pub bank: Option<u16>,
+    pub start: u16,
+    pub end: u16,
+    pub read: bool,
+    pub write: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct WatchHit {
+    pub addr: u16,
+    pub bank: u16,
+    pub value: u8,
+    pub write: bool,
+}
+
+// OAM DMA copies 160 bytes, one per M-cycle
+const DMA_LENGTH: u16 = 0xA0;
+const DMA_CYCLES_PER_BYTE: u64 = 4;
+
 impl<'a> Mem<'a>{
     pub fn new(arom: rom::ROM<'a>, alcd: lcd::LCD<'a>, ajoypad: joypad::Joypad<'a>, atimer: timer::Timer<'a>) -> Mem<'a> {
-        let mut mem = Mem{
+        let cgb = arom.is_cgb();
+        let mem = Mem{
             _size: 0xFFFF,
             rom: arom,
             ram: vec![0x00; 16384*200],
@@ -40,18 +103,404 @@ impl<'a> Mem<'a>{
             ram_mode: false,
             ram_enabled: false,
             mbc1: MBC1::MBC1::new(),
+            sgb: sgb::Sgb::new(),
+            cgb: cgb,
+            vram1: vec![0x00; 0x2000],
+            wram_bank: 1,
+            wram: vec![0x00; 0x1000*8],
             ..Default::default()
         };
+        mem
+    }
 
-        let mut f = File::open("./DMG_ROM.bin".to_string()).expect("File not found");
-        let read_size = f.read_to_end(&mut mem.bootrom).expect("Can't read bootrom");
+    // Without a boot ROM, the CPU starts directly at 0x0100
+    pub fn load_bootrom(&mut self, path: &str) -> io::Result<()> {
+        let mut f = File::open(path)?;
+        self.bootrom.clear();
+        let read_size = f.read_to_end(&mut self.bootrom)?;
         println!("Boot ROM: {} bytes", read_size);
-        mem
+        Ok(())
+    }
+    pub fn has_bootrom(&self) -> bool {
+        !self.bootrom.is_empty()
+    }
+    pub fn get_rom(&self) -> &rom::ROM<'a> {
+        &self.rom
+    }
+
+    pub fn save_state(&self, w: &mut savestate::Writer) {
+        w.bool(self.bootrom_enable);
+        w.bytes(&self.ram[..0x10000]);
+        w.u8(self.mbc1_bank);
+        w.u8(self.ram_bank);
+        w.bool(self.ram_mode);
+        w.bool(self.ram_enabled);
+        w.u8(self.dma_reg);
+        w.bool(self.dma_active);
+        w.u16(self.dma_source);
+        w.u16(self.dma_offset);
+        w.u64(self.dma_cycles);
+        w.bool(self.double_speed);
+        w.bool(self.speed_switch);
+        w.u8(self.vram_bank);
+        w.bytes(&self.vram1);
+        w.u8(self.wram_bank);
+        w.bytes(&self.wram);
+        w.u16(self.hdma_source);
+        w.u16(self.hdma_dest);
+        w.u8(self.hdma_blocks);
+        w.bool(self.hdma_active);
+        self.lcd.save_state(w);
+        self.joypad.save_state(w);
+        self.timer.save_state(w);
+        self.sgb.save_state(w);
+    }
+    pub fn load_state(&mut self, r: &mut savestate::Reader) -> Result<(), String> {
+        self.bootrom_enable = r.bool()?;
+        r.bytes_into(&mut self.ram[..0x10000])?;
+        self.mbc1_bank      = r.u8()?;
+        self.ram_bank       = r.u8()?;
+        self.ram_mode       = r.bool()?;
+        self.ram_enabled    = r.bool()?;
+        self.dma_reg        = r.u8()?;
+        self.dma_active     = r.bool()?;
+        self.dma_source     = r.u16()?;
+        self.dma_offset     = r.u16()?;
+        self.dma_cycles     = r.u64()?;
+        self.double_speed   = r.bool()?;
+        self.speed_switch   = r.bool()?;
+        self.vram_bank      = r.u8()?;
+        r.bytes_into(&mut self.vram1)?;
+        self.wram_bank      = r.u8()?;
+        r.bytes_into(&mut self.wram)?;
+        self.hdma_source    = r.u16()?;
+        self.hdma_dest      = r.u16()?;
+        self.hdma_blocks    = r.u8()?;
+        self.hdma_active    = r.bool()?;
+        self.lcd.load_state(r)?;
+        self.joypad.load_state(r)?;
+        self.timer.load_state(r)?;
+        self.sgb.load_state(r)
     }
     pub fn is_bootrom_enabled(&mut self) -> bool {
         self.bootrom_enable
     }
+    pub fn disable_bootrom(&mut self) {
+        self.bootrom_enable = false;
+    }
+    // Serial output since the last call
+    pub fn take_serial(&mut self) -> Vec<u8> {
+        std::mem::replace(&mut self.serial, vec![])
+    }
+    pub fn is_cgb(&self) -> bool {
+        self.cgb
+    }
+    pub fn is_double_speed(&self) -> bool {
+        self.double_speed
+    }
+
+    // Called by STOP, returns true if a CGB speed switch was armed through KEY1
+    pub fn switch_speed(&mut self) -> bool {
+        if !self.cgb || !self.speed_switch {
+            return false;
+        }
+        self.double_speed = !self.double_speed;
+        self.speed_switch = false;
+        println!("Switching to {} speed", if self.double_speed {"double"} else {"normal"});
+        true
+    }
+
+    // VRAM access for the PPU, bank 1 only exists in CGB mode
+    pub fn peek_vram(&self, bank: u8, addr: u16) -> u8 {
+        if self.cgb && bank == 1 {
+            self.vram1[(addr - 0x8000) as usize]
+        } else {
+            self.ram[addr as usize]
+        }
+    }
+    fn write_vram(&mut self, addr: u16, v: u8) {
+        if self.cgb && self.vram_bank == 1 {
+            self.vram1[(addr - 0x8000) as usize] = v;
+        } else {
+            self.ram[addr as usize] = v;
+        }
+    }
+    fn wram_offset(&self, addr: u16) -> usize {
+        let bank = if self.wram_bank == 0 { 1 } else { self.wram_bank as usize };
+        bank*0x1000 + (addr - 0xD000) as usize
+    }
+
+    // Copy one 16 bytes block of a CGB VRAM DMA
+    fn hdma_copy_block(&mut self) {
+        for _ in 0..16 {
+            let v = self.peek8(self.hdma_source);
+            if self.cdl.is_some() {
+                let source = self.hdma_source;
+                self.cdl_mark(source, cdl::GRAPHICS);
+            }
+            self.write_vram(0x8000 | (self.hdma_dest & 0x1FFF), v);
+            self.hdma_source = self.hdma_source.wrapping_add(1);
+            self.hdma_dest = self.hdma_dest.wrapping_add(1);
+        }
+        self.hdma_blocks = self.hdma_blocks.wrapping_sub(1);
+    }
+
+    fn start_hdma(&mut self, v: u8) {
+        if v & 0x80 == 0 {
+            if self.hdma_active {
+                // Writing bit 7 cleared stops a running H-Blank DMA, the blocks
+                // left can still be read to resume it
+                self.hdma_active = false;
+                return;
+            }
+            // General purpose DMA, copied at once while the CPU waits,
+            // 8us per block at either speed
+            self.hdma_blocks = (v & 0x7F) + 1;
+            let per_block = if self.double_speed { 64 } else { 32 };
+            self.stall_cycles += self.hdma_blocks as u64 * per_block;
+            while self.hdma_blocks != 0 {
+                self.hdma_copy_block();
+            }
+        } else {
+            // H-Blank DMA, 16 bytes per H-Blank
+            self.hdma_blocks = (v & 0x7F) + 1;
+            self.hdma_active = true;
+        }
+    }
+    pub fn take_stall_cycles(&mut self) -> u64 {
+        let cycles = self.stall_cycles;
+        self.stall_cycles = 0;
+        cycles
+    }
+    // Advance the memory side hardware (OAM DMA, CGB H-Blank DMA) by the given cycles
+    pub fn update(&mut self, cycles: u64) {
+        if self.lcd.entered_hblank() && self.hdma_active {
+            self.hdma_copy_block();
+            if self.hdma_blocks == 0 {
+                self.hdma_active = false;
+            }
+        }
+        // SGB VRAM transfers happen once the data is on screen
+        if self.sgb.get_pending_transfer().is_some() && self.lcd.get_mode() == 1 {
+            let data = self.sgb_vram_data();
+            self.sgb.vram_transfer(&data);
+        }
+        if !self.dma_active {
+            return;
+        }
+        self.dma_cycles += cycles;
+        while self.dma_active && self.dma_cycles >= DMA_CYCLES_PER_BYTE {
+            self.dma_cycles -= DMA_CYCLES_PER_BYTE;
+            let mut src = self.dma_source + self.dma_offset;
+            // Sources above 0xDFFF read from the echo of the work RAM
+            if src >= 0xE000 {
+                src -= 0x2000;
+            }
+            let value = self.peek8(src);
+            if self.cdl.is_some() {
+                self.cdl_mark(src, cdl::DATA);
+            }
+            self.ram[(0xFE00 + self.dma_offset) as usize] = value;
+            self.dma_offset += 1;
+            if self.dma_offset == DMA_LENGTH {
+                self.dma_active = false;
+            }
+        }
+    }
+
+    // The 4KB of an SGB VRAM transfer: the first 256 tiles of the BG map, as displayed
+    fn sgb_vram_data(&mut self) -> Vec<u8> {
+        let lcdc = self.lcd.read8(0xFF40);
+        let map: u16 = if lcdc&0b0000_1000 != 0 { 0x9C00 } else { 0x9800 };
+        let mut data = Vec::with_capacity(0x1000);
+        for i in 0..256 {
+            let id = self.ram[(map + (i/20)*32 + i%20) as usize];
+            let addr = self.lcd.get_tile_addr(id, false);
+            for j in 0..16 {
+                data.push(self.ram[(addr+j) as usize]);
+            }
+        }
+        data
+    }
+
+    pub fn is_dma_active(&self) -> bool {
+        self.dma_active
+    }
+
+    fn start_dma(&mut self, v: u8) {
+        self.dma_reg = v;
+        self.dma_source = (v as u16) << 8;
+        self.dma_offset = 0;
+        self.dma_cycles = 0;
+        self.dma_active = true;
+    }
+
+    // CPU read. While an OAM DMA runs, the CPU only sees HRAM and I/O registers
     pub fn read8(&mut self, addr: u16) -> u8 {
+        self.read8_logged(addr, cdl::DATA)
+    }
+    // Instruction fetch, logged as code
+    pub fn fetch8(&mut self, addr: u16) -> u8 {
+        self.read8_logged(addr, cdl::CODE)
+    }
+    fn read8_logged(&mut self, addr: u16, flag: u8) -> u8 {
+        if self.dma_active && addr < 0xFF00 {
+            return 0xFF;
+        }
+        if self.is_locked_by_ppu(addr) {
+            return 0xFF;
+        }
+        let mut v = self.peek8(addr);
+        if addr < 0x8000 && !self.cheats.is_empty() {
+            v = self.cheats.patch(addr, v);
+        }
+        // Fetches are left to the execute watchpoints, checked on PC
+        if flag == cdl::DATA && !self.watches.is_empty() {
+            self.check_watches(addr, v, false);
+        }
+        if self.cdl.is_some() {
+            self.cdl_log(addr, v, flag);
+        }
+        if flag == cdl::DATA {
+            if let Some(ref mut p) = self.profiler {
+                p.read(addr);
+            }
+        }
+        v
+    }
+
+    fn cdl_log(&mut self, addr: u16, v: u8, flag: u8) {
+        let offset = self.rom_offset(addr);
+        if let (Some(offset), Some(ref mut cdl)) = (offset, self.cdl.as_mut()) {
+            cdl.mark(offset, flag);
+        }
+        if flag == cdl::DATA {
+            self.cdl_last_read = offset.map(|o| (o, v));
+        }
+    }
+
+    // Offset in the cartridge ROM of a mapped address
+    pub fn rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x0000..=0x00FF if self.bootrom_enable => None,
+            0x0000..=0x3FFF => Some(addr as usize),
+            0x4000..=0x7FFF => Some(self.get_bank(addr) as usize * 0x4000 + (addr - 0x4000) as usize),
+            _ => None,
+        }
+    }
+
+    pub fn set_cdl(&mut self, cdl: cdl::Cdl) {
+        self.cdl = Some(cdl);
+    }
+    pub fn get_cdl(&self) -> Option<&cdl::Cdl> {
+        self.cdl.as_ref()
+    }
+    pub fn set_profiler(&mut self, profiler: profiler::Profiler) {
+        self.profiler = Some(profiler);
+    }
+    pub fn get_profiler(&self) -> Option<&profiler::Profiler> {
+        self.profiler.as_ref()
+    }
+    pub fn get_profiler_mut(&mut self) -> Option<&mut profiler::Profiler> {
+        self.profiler.as_mut()
+    }
+
+    pub fn set_write_hooks(&mut self, addrs: Vec<u16>) {
+        self.write_hooks = addrs;
+    }
+    pub fn take_write_hits(&mut self) -> Vec<(u16, u8)> {
+        ::std::mem::replace(&mut self.write_hits, vec![])
+    }
+
+    pub fn set_cheats(&mut self, cheats: cheats::Cheats) {
+        self.cheats = cheats;
+    }
+    pub fn get_cheats(&self) -> &cheats::Cheats {
+        &self.cheats
+    }
+    pub fn get_cheats_mut(&mut self) -> &mut cheats::Cheats {
+        &mut self.cheats
+    }
+    // Once per frame, GameShark codes write their values again
+    pub fn apply_cheats(&mut self) {
+        for (bank, addr, v) in self.cheats.writes() {
+            self.poke_bank8(bank, addr, v);
+        }
+    }
+
+    // Interrupt flags updated by the CPU itself, not a bus access
+    pub fn set_interrupt_flags(&mut self, v: u8) {
+        self.ram[0xFF0F] = v;
+    }
+
+    pub fn cdl_mark(&mut self, addr: u16, flag: u8) {
+        if let (Some(offset), Some(ref mut cdl)) = (self.rom_offset(addr), self.cdl.as_mut()) {
+            cdl.mark(offset, flag);
+        }
+    }
+
+    pub fn set_watches(&mut self, watches: Vec<Watch>) {
+        self.watches = watches;
+        self.watch_hit = None;
+    }
+    pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+    fn check_watches(&mut self, addr: u16, value: u8, write: bool) {
+        if self.watch_hit.is_some() {
+            return;
+        }
+        let bank = self.get_bank(addr);
+        let hit = self.watches.iter().any(|w| {
+            addr >= w.start && addr <= w.end && (if write { w.write } else { w.read }) &&
+                w.bank.map_or(true, |b| b == bank)
+        });
+        if hit {
+            self.watch_hit = Some(WatchHit { addr: addr, bank: bank, value: value, write: write });
+        }
+    }
+
+    // VRAM is unreachable during pixel transfer, OAM during OAM search and pixel transfer
+    fn is_locked_by_ppu(&mut self, addr: u16) -> bool {
+        if !self.lcd.is_enabled() {
+            return false;
+        }
+        match addr {
+            0x8000..=0x9FFF => self.lcd.get_mode() == 3,
+            0xFE00..=0xFE9F => self.lcd.get_mode() == 2 || self.lcd.get_mode() == 3,
+            _ => false,
+        }
+    }
+
+    // OAM corruption bug: a 16-bit INC/DEC with a value in 0xFE00-0xFEFF during
+    // mode 2 puts that value on the OAM bus and corrupts the row being scanned
+    pub fn oam_bug_write(&mut self, v: u16) {
+        // The CGB doesn't have the bug
+        if self.cgb || v < 0xFE00 || v > 0xFEFF || !self.lcd.is_enabled() || self.lcd.get_mode() != 2 {
+            return;
+        }
+        let row = self.lcd.get_oam_row();
+        // The first row is never corrupted
+        if row == 0 || row >= 20 {
+            return;
+        }
+        let cur  = 0xFE00 + row*8;
+        let prev = cur - 8;
+        let word = |ram: &Vec<u8>, at: usize| -> u16 { (ram[at] as u16) | ((ram[at+1] as u16) << 8) };
+        let a = word(&self.ram, cur);
+        let b = word(&self.ram, prev);
+        let c = word(&self.ram, prev+4);
+        let first = ((a ^ c) & (b ^ c)) ^ c;
+        self.ram[cur]   = (first & 0xFF) as u8;
+        self.ram[cur+1] = (first >> 8) as u8;
+        // The last three words are copied from the preceding row
+        for i in 2..8 {
+            self.ram[cur+i] = self.ram[prev+i];
+        }
+    }
+
+    // Read without any CPU bus restriction, used by the PPU and debugging views
+    pub fn peek8(&mut self, addr: u16) -> u8 {
         match addr {
             0x0000..=0x7FFF => {
                 if self.rom.get_mbc() == 0x00 {
@@ -71,10 +520,30 @@ impl<'a> Mem<'a>{
                     self.read8_mbc1(addr) // Use MBC1 FIXME
                 }
             }
+            // OAM DMA
+            0xFF46          => { self.dma_reg },
+            // CGB registers
+            0x8000..=0x9FFF if self.cgb => { self.peek_vram(self.vram_bank, addr) },
+            0xD000..=0xDFFF if self.cgb => { self.wram[self.wram_offset(addr)] },
+            // Echo of 0xC000-0xDDFF
+            0xE000..=0xFDFF => { self.peek8(addr - 0x2000) },
+            0xFF4D if self.cgb => { 0x7E | ((self.double_speed as u8) << 7) | (self.speed_switch as u8) },
+            0xFF4F if self.cgb => { 0xFE | self.vram_bank },
+            0xFF51..=0xFF54 if self.cgb => { 0xFF },
+            0xFF55 if self.cgb => {
+                // Blocks left minus one, with bit 7 set once stopped: 0xFF when done
+                let left = self.hdma_blocks.wrapping_sub(1) & 0x7F;
+                if self.hdma_active { left } else { 0x80 | left }
+            },
+            0xFF68..=0xFF6B if self.cgb => { self.lcd.read8(addr) },
+            0xFF70 if self.cgb => { 0xF8 | self.wram_bank },
             // LCD
             0xFF40..=0xFF4F => { self.lcd.read8(addr) },
             // Joypad
-            0xFF00          => { self.joypad.read8() },
+            0xFF00          => {
+                let v = self.joypad.read8();
+                if self.sgb.is_enabled() { self.sgb.read_joypad(v) } else { v }
+            },
             // Timer
             0xFF04..=0xFF07 => { self.timer.read8(addr) },
             // IF
@@ -86,6 +555,76 @@ impl<'a> Mem<'a>{
         }
     }
 
+    // Bank currently mapped at addr, for the banked areas
+    pub fn get_bank(&self, addr: u16) -> u16 {
+        match addr {
+            0x4000..=0x7FFF if self.rom.mbc != 0x00 => self.mbc1_bank as u16,
+            0x4000..=0x7FFF => 1,
+            0xA000..=0xBFFF if self.ram_mode => self.ram_bank as u16,
+            0x8000..=0x9FFF if self.cgb => self.vram_bank as u16,
+            0xD000..=0xDFFF if self.cgb => if self.wram_bank == 0 { 1 } else { self.wram_bank as u16 },
+            _ => 0,
+        }
+    }
+    pub fn get_bank_count(&self, addr: u16) -> u16 {
+        match addr {
+            0x4000..=0x7FFF => ((self.rom.buffer.len()/0x4000) as u16).max(2),
+            // Only 4 banks can be selected with MBC1
+            0xA000..=0xBFFF if self.rom.mbc != 0x00 => ((self.rom.get_ram_size_kb()/8) as u16).max(1).min(4),
+            0x8000..=0x9FFF if self.cgb => 2,
+            0xD000..=0xDFFF if self.cgb => 8,
+            _ => 1,
+        }
+    }
+
+    // Cartridge RAM is stored by bank with an MBC, at its own address without
+    fn cart_ram_offset(&self, bank: usize, addr: u16) -> usize {
+        if self.rom.mbc == 0x00 { addr as usize } else { bank*0x2000 | (addr & 0x1FFF) as usize }
+    }
+
+    // Read at addr in the given bank, or in the mapped one with None.
+    // The bank is ignored outside of the banked areas.
+    pub fn peek_bank8(&mut self, bank: Option<u16>, addr: u16) -> u8 {
+        let bank = match bank {
+            Some(b) if b < self.get_bank_count(addr) => b as usize,
+            _ => return self.peek8(addr),
+        };
+        match addr {
+            0x4000..=0x7FFF => {
+                let offset = bank*0x4000 + (addr - 0x4000) as usize;
+                self.rom.buffer.get(offset).cloned().unwrap_or(0xFF)
+            },
+            0xA000..=0xBFFF => self.ram[self.cart_ram_offset(bank, addr)],
+            0x8000..=0x9FFF => self.peek_vram(bank as u8, addr),
+            0xD000..=0xDFFF if self.cgb => self.wram[bank*0x1000 + (addr - 0xD000) as usize],
+            _ => self.peek8(addr),
+        }
+    }
+
+    // Write anywhere, ROM included, without the MBC and PPU side effects.
+    // I/O registers still go through write8.
+    pub fn poke_bank8(&mut self, bank: Option<u16>, addr: u16, v: u8) {
+        let bank = match bank {
+            Some(b) if b < self.get_bank_count(addr) => b as usize,
+            _ => self.get_bank(addr) as usize,
+        };
+        match addr {
+            0x0000..=0x3FFF => self.rom.buffer[addr as usize] = v,
+            0x4000..=0x7FFF => {
+                let offset = bank*0x4000 + (addr - 0x4000) as usize;
+                if offset < self.rom.buffer.len() {
+                    self.rom.buffer[offset] = v;
+                }
+            },
+            0xA000..=0xBFFF => { let offset = self.cart_ram_offset(bank, addr); self.ram[offset] = v; },
+            0x8000..=0x9FFF if self.cgb && bank == 1 => self.vram1[(addr - 0x8000) as usize] = v,
+            0xD000..=0xDFFF if self.cgb => self.wram[bank*0x1000 + (addr - 0xD000) as usize] = v,
+            0xE000..=0xFDFF => self.poke_bank8(None, addr - 0x2000, v),
+            0xFF00..=0xFFFF => self.write8(addr, v),
+            _ => self.ram[addr as usize] = v,
+        }
+    }
+
     pub fn dump_mem(&mut self, addr: u16, len: u16) {
         print!("{:04X}: ", addr);
         for i in addr..addr+len {
@@ -142,6 +681,29 @@ impl<'a> Mem<'a>{
     }
 
     pub fn write8(&mut self, addr: u16, v: u8)  {
+        if self.dma_active && addr < 0xFF00 {
+            return;
+        }
+        if self.is_locked_by_ppu(addr) {
+            return;
+        }
+        if !self.watches.is_empty() {
+            self.check_watches(addr, v, true);
+        }
+        if let Some(ref mut p) = self.profiler {
+            p.write(addr);
+        }
+        if !self.write_hooks.is_empty() && self.write_hooks.contains(&addr) {
+            self.write_hits.push((addr, v));
+        }
+        // ROM bytes copied to the tiles are graphics
+        if let (0x8000..=0x97FF, Some((offset, value))) = (addr, self.cdl_last_read) {
+            if value == v {
+                if let Some(ref mut cdl) = self.cdl {
+                    cdl.mark(offset, cdl::GRAPHICS);
+                }
+            }
+        }
         match addr {
             0x0000..=0x00FF => {
                 if addr<=0x00FF {
@@ -196,20 +758,37 @@ impl<'a> Mem<'a>{
                 }
                 self.ram[(bank as usize * 0x2000) | ((addr & 0x1FFF) as usize)] = v
             },
-            0xFF40..=0xFF4F => {
-                // OAM DMA
-                if addr == 0xFF46 {
-                    let start = (v as u16)<<8;
-                    for i in 0x00..0x9F {
-                        let value = self.read8(start+i);
-                        self.write8(0xFE00+i, value);
-                    }
-                } else {
-                    self.lcd.write8(addr, v)
+            // OAM DMA
+            0xFF46 =>          { self.start_dma(v) },
+            // CGB registers
+            0x8000..=0x9FFF if self.cgb => { self.write_vram(addr, v) },
+            0xD000..=0xDFFF if self.cgb => { let offset = self.wram_offset(addr); self.wram[offset] = v; },
+            // Echo of 0xC000-0xDDFF
+            0xF000..=0xFDFF if self.cgb => { let offset = self.wram_offset(addr - 0x2000); self.wram[offset] = v; },
+            0xE000..=0xFDFF => { self.ram[(addr - 0x2000) as usize] = v; },
+            0xFF4D if self.cgb => { self.speed_switch = v & 0x01 != 0 },
+            0xFF4F if self.cgb => { self.vram_bank = v & 0x01 },
+            0xFF51 if self.cgb => { self.hdma_source = (self.hdma_source & 0x00FF) | ((v as u16) << 8) },
+            0xFF52 if self.cgb => { self.hdma_source = (self.hdma_source & 0xFF00) | ((v & 0xF0) as u16) },
+            0xFF53 if self.cgb => { self.hdma_dest = (self.hdma_dest & 0x00FF) | (((v & 0x1F) as u16) << 8) },
+            0xFF54 if self.cgb => { self.hdma_dest = (self.hdma_dest & 0xFF00) | ((v & 0xF0) as u16) },
+            0xFF55 if self.cgb => { self.start_hdma(v) },
+            0xFF68..=0xFF6B if self.cgb => { self.lcd.write8(addr, v) },
+            0xFF70 if self.cgb => { self.wram_bank = v & 0x07 },
+            0xFF40..=0xFF4F => { self.lcd.write8(addr, v) },
+            0xFF50 =>          { self.bootrom_enable = false; println!("Disabling BOOTROM");}
+            0xFF00 =>          {
+                self.joypad.write8(v);
+                if self.sgb.is_enabled() {
+                    self.sgb.write_joypad(v);
                 }
             },
-            0xFF50 =>          { self.bootrom_enable = false; println!("Disabling BOOTROM");}
-            0xFF00 =>          { self.joypad.write8(v);},
+            0xFF02 =>          {
+                if v&0x81 == 0x81 {
+                    self.serial.push(self.ram[0xFF01]);
+                }
+                self.ram[addr as usize] = v;
+            },
             0xFF04..=0xFF07 => { self.timer.write8(addr, v) },
             // IE
             0xFFFF => {self.ram[addr as usize] = v;}
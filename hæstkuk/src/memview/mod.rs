@@ -0,0 +1,263 @@
+// Memory viewer and hex editor window
+#![allow(dead_code)]
+use std::marker::PhantomData;
+use minifb::{Key, KeyRepeat, Window, WindowOptions, Scale};
+
+use lr35902::Cpu;
+use font;
+
+const COLUMNS: usize = 16;
+const ROWS: usize    = 32;
+const PAGE: u16      = (COLUMNS*ROWS) as u16;
+
+// 74 characters by 2 header lines and the rows
+const WIDTH: usize  = 74*font::WIDTH;
+const HEIGHT: usize = (ROWS+2)*font::HEIGHT + 2;
+
+const TEXT_COLOR: u32    = 0xC0C0C0;
+const HEADER_COLOR: u32  = 0xFFFF80;
+const CHANGED_COLOR: u32 = 0xFF4040;
+const CURSOR_COLOR: u32  = 0x2040A0;
+const EDIT_COLOR: u32    = 0xA08020;
+
+pub struct MemView<'a> {
+    window: Option<Window>,
+    buffer: Vec<u32>,
+    // First displayed address and edited one
+    addr: u16,
+    cursor: u16,
+    // Displayed bank, None for the mapped one
+    bank: Option<u16>,
+    // Bytes displayed last time, to highlight the changes
+    previous: Vec<u8>,
+    previous_addr: u16,
+    previous_bank: Option<u16>,
+    // Hex digits typed after G
+    goto: Option<String>,
+    // High nibble typed while editing
+    nibble: Option<u8>,
+    phantom: PhantomData<&'a u8>,
+}
+
+fn hex_digit(key: Key) -> Option<u8> {
+    match key {
+        Key::Key0 | Key::NumPad0 => Some(0x0),
+        Key::Key1 | Key::NumPad1 => Some(0x1),
+        Key::Key2 | Key::NumPad2 => Some(0x2),
+        Key::Key3 | Key::NumPad3 => Some(0x3),
+        Key::Key4 | Key::NumPad4 => Some(0x4),
+        Key::Key5 | Key::NumPad5 => Some(0x5),
+        Key::Key6 | Key::NumPad6 => Some(0x6),
+        Key::Key7 | Key::NumPad7 => Some(0x7),
+        Key::Key8 | Key::NumPad8 => Some(0x8),
+        Key::Key9 | Key::NumPad9 => Some(0x9),
+        Key::A => Some(0xA),
+        Key::B => Some(0xB),
+        Key::C => Some(0xC),
+        Key::D => Some(0xD),
+        Key::E => Some(0xE),
+        Key::F => Some(0xF),
+        _ => None,
+    }
+}
+
+impl<'a> MemView<'a> {
+    pub fn new() -> MemView<'a> {
+        MemView {
+            window: None,
+            buffer: vec![0x00; WIDTH*HEIGHT],
+            addr: 0xC000,
+            cursor: 0xC000,
+            bank: None,
+            previous: vec![],
+            previous_addr: 0,
+            previous_bank: None,
+            goto: None,
+            nibble: None,
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn toggle(&mut self) {
+        if self.window.is_some() {
+            self.window = None;
+            return;
+        }
+        self.window = Some(Window::new(
+            "Memory - ESC to exit",
+            WIDTH,
+            HEIGHT,
+            WindowOptions {
+                scale: Scale::X2,
+                ..WindowOptions::default()
+            },
+            )
+            .unwrap_or_else(|e| {
+                panic!("{}", e);
+            }));
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.window.as_ref().map_or(false, |w| w.is_open())
+    }
+
+    pub fn escape_pressed(&self) -> bool {
+        self.window.as_ref().map_or(false, |w| w.is_key_down(Key::Escape))
+    }
+
+    fn move_cursor(&mut self, delta: i32) {
+        self.cursor = (self.cursor as i32 + delta) as u16;
+        self.nibble = None;
+        // Scroll one line when leaving the screen by an edge, else jump to the cursor
+        let row = self.cursor & !(COLUMNS as u16 - 1);
+        if row.wrapping_sub(self.addr) >= PAGE {
+            self.addr = if row == self.addr.wrapping_add(PAGE) {
+                self.addr.wrapping_add(COLUMNS as u16)
+            } else {
+                row
+            };
+        }
+    }
+
+    // Keyboard input, P pausing the emulation in which case the bytes can be edited
+    pub fn handle_keys(&mut self, cpu: &mut Cpu<'a>, paused: &mut bool) {
+        let keys = match self.window {
+            Some(ref w) if w.is_open() => w.get_keys_pressed(KeyRepeat::Yes),
+            // Closed with its close button
+            Some(_) => { self.window = None; return },
+            None => return,
+        };
+        for key in keys {
+            if let Some(mut goto) = self.goto.take() {
+                match (key, hex_digit(key)) {
+                    (_, Some(d)) => goto.push_str(&format!("{:X}", d)),
+                    (Key::Backspace, _) => { goto.pop(); },
+                    (Key::Enter, _) => goto.push_str(&"0".repeat(4 - goto.len().min(4))),
+                    _ => continue,
+                }
+                if goto.len() == 4 {
+                    let addr = u16::from_str_radix(&goto, 16).unwrap();
+                    self.cursor = addr;
+                    self.addr = addr & !(COLUMNS as u16 - 1);
+                } else {
+                    self.goto = Some(goto);
+                }
+                continue;
+            }
+            if let Some(d) = hex_digit(key) {
+                if !*paused {
+                    continue;
+                }
+                match self.nibble.take() {
+                    None     => self.nibble = Some(d),
+                    Some(hi) => {
+                        cpu.mem.poke_bank8(self.bank, self.cursor, (hi<<4) | d);
+                        self.move_cursor(1);
+                    },
+                }
+                continue;
+            }
+            match key {
+                Key::Left     => self.move_cursor(-1),
+                Key::Right    => self.move_cursor(1),
+                Key::Up       => self.move_cursor(-(COLUMNS as i32)),
+                Key::Down     => self.move_cursor(COLUMNS as i32),
+                Key::PageUp   => { self.addr = self.addr.wrapping_sub(PAGE); self.move_cursor(-(PAGE as i32)) },
+                Key::PageDown => { self.addr = self.addr.wrapping_add(PAGE); self.move_cursor(PAGE as i32) },
+                Key::G        => { self.goto = Some(String::new()); self.nibble = None },
+                Key::P        => { *paused = !*paused; self.nibble = None },
+                Key::LeftBracket | Key::RightBracket => {
+                    let count = cpu.mem.get_bank_count(self.cursor);
+                    let bank = self.bank.unwrap_or(cpu.mem.get_bank(self.cursor)) % count;
+                    self.bank = Some(if key == Key::RightBracket { (bank+1) % count } else { (bank+count-1) % count });
+                },
+                Key::Backspace => self.bank = None,
+                _ => {},
+            }
+        }
+    }
+
+    pub fn update(&mut self, cpu: &mut Cpu<'a>, paused: bool) {
+        if self.window.is_none() {
+            return;
+        }
+        for p in self.buffer.iter_mut() {
+            *p = 0x000000;
+        }
+
+        // Header: area, bank and mode
+        let count = cpu.mem.get_bank_count(self.cursor);
+        let bank_str = match self.bank {
+            _ if count == 1 => String::new(),
+            Some(b) if b < count => format!("BANK {:02X}/{:02X}", b, count-1),
+            _ => format!("BANK {:02X}/{:02X} MAPPED", cpu.mem.get_bank(self.cursor), count-1),
+        };
+        let mode = match self.goto {
+            Some(ref g) => format!("GOTO {}_", g),
+            None if paused => String::from("PAUSED, 0-F TO EDIT"),
+            None => String::from("RUNNING"),
+        };
+        let header = format!("{:04X}  {:<22} {}", self.cursor, bank_str, mode);
+        font::draw_text(&mut self.buffer, WIDTH, 1, 1, &header, HEADER_COLOR);
+        font::draw_text(&mut self.buffer, WIDTH, 1, 1+font::HEIGHT,
+                        "ARROWS PGUP PGDN MOVE  G GOTO  [ ] BACKSPACE BANK  P PAUSE", HEADER_COLOR);
+
+        let same_page = self.previous_addr == self.addr && self.previous_bank == self.bank;
+        let mut bytes = Vec::with_capacity(PAGE as usize);
+        for row in 0..ROWS {
+            let y = 1 + (row+2)*font::HEIGHT;
+            let row_addr = self.addr.wrapping_add((row*COLUMNS) as u16);
+            let row_count = cpu.mem.get_bank_count(row_addr);
+            let label = if row_count > 1 {
+                let bank = match self.bank {
+                    Some(b) if b < row_count => b,
+                    _ => cpu.mem.get_bank(row_addr),
+                };
+                format!("{:02X}:{:04X}", bank, row_addr)
+            } else {
+                format!("   {:04X}", row_addr)
+            };
+            font::draw_text(&mut self.buffer, WIDTH, 1, y, &label, TEXT_COLOR);
+
+            for col in 0..COLUMNS {
+                let addr = row_addr.wrapping_add(col as u16);
+                let v = cpu.mem.peek_bank8(self.bank, addr);
+                let i = bytes.len();
+                bytes.push(v);
+
+                let x = 1 + (9 + col*3)*font::WIDTH;
+                let ascii_x = 1 + (58 + col)*font::WIDTH;
+                if addr == self.cursor {
+                    let color = if self.nibble.is_some() { EDIT_COLOR } else { CURSOR_COLOR };
+                    self.fill(x-1, y-1, 2*font::WIDTH+1, font::HEIGHT, color);
+                    self.fill(ascii_x-1, y-1, font::WIDTH, font::HEIGHT, color);
+                }
+                let changed = same_page && self.previous.get(i).map_or(false, |&p| p != v);
+                let color = if changed { CHANGED_COLOR } else { TEXT_COLOR };
+                let text = match self.nibble {
+                    Some(hi) if addr == self.cursor => format!("{:X}_", hi),
+                    _ => format!("{:02X}", v),
+                };
+                font::draw_text(&mut self.buffer, WIDTH, x, y, &text, color);
+                let c = v as char;
+                let c = if v >= 0x20 && v < 0x7F && font::has_glyph(c) { c } else { '.' };
+                font::draw_text(&mut self.buffer, WIDTH, ascii_x, y, &c.to_string(), color);
+            }
+        }
+        self.previous = bytes;
+        self.previous_addr = self.addr;
+        self.previous_bank = self.bank;
+
+        if let Some(ref mut w) = self.window {
+            w.update_with_buffer(&self.buffer, WIDTH, HEIGHT).unwrap();
+        }
+    }
+
+    fn fill(&mut self, x: usize, y: usize, w: usize, h: usize, color: u32) {
+        for py in y..(y+h).min(HEIGHT) {
+            for px in x..(x+w).min(WIDTH) {
+                self.buffer[px + py*WIDTH] = color;
+            }
+        }
+    }
+}
@@ -0,0 +1,180 @@
+// Input movies: the joypad state of each frame, replayed to run the exact
+// same session again.
+//
+// While a movie records or plays, the buttons are only read at the end of
+// each frame and kept for the whole next one, instead of following the
+// keyboard at any time. The emulation doesn't depend on anything else from
+// the host, so the same inputs from the same start always give the same
+// frames: each one is recorded with a hash of the screen, checked on playback.
+// The hash uses the DMG shades, so the palette can differ from the recording.
+//
+// File, in the save state encoding:
+// "HKMV", version, ROM global checksum, boot ROM used, start state (empty
+// from power-on), frame count, then the buttons and screen hash of each frame
+#![allow(dead_code)]
+use std::fs;
+use lr35902::Cpu;
+use render::Render;
+use savestate;
+
+const MAGIC: &[u8; 4] = b"HKMV";
+const VERSION: u8 = 2;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Frame {
+    // Pressed buttons, as Joypad::get_pressed
+    pub buttons: u8,
+    pub hash: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Recording,
+    Playing,
+    // Played to the end, the keyboard is back
+    Finished,
+}
+
+pub struct Movie {
+    path: String,
+    mode: Mode,
+    checksum: u16,
+    boot: bool,
+    // Save state to start from, empty to start from power-on
+    state: Vec<u8>,
+    frames: Vec<Frame>,
+    // Frames run since the start
+    current: usize,
+    // Frames whose hash differs from the recorded one
+    desyncs: Vec<usize>,
+}
+
+impl Movie {
+    // Record from the current state, which is the power-on one unless from_state
+    pub fn record(path: &str, cpu: &mut Cpu, from_state: bool) -> Movie {
+        let buttons = cpu.mem.joypad.get_pressed();
+        cpu.mem.joypad.set_latched(Some(buttons));
+        Movie {
+            path: path.to_string(),
+            mode: Mode::Recording,
+            checksum: cpu.mem.get_rom().get_global_checksum(),
+            boot: cpu.mem.is_bootrom_enabled(),
+            state: if from_state { savestate::save_to_vec(cpu) } else { vec![] },
+            frames: vec![Frame { buttons: buttons, hash: 0 }],
+            current: 0,
+            desyncs: vec![],
+        }
+    }
+
+    // Load a movie, from a machine just powered on, and put it in the movie start state
+    pub fn play(path: &str, cpu: &mut Cpu) -> Result<Movie, String> {
+        let data = fs::read(path).map_err(|e| format!("can't read {}: {}", path, e))?;
+        let mut r = savestate::Reader::new(&data);
+        if r.take(4)? != &MAGIC[..] {
+            return Err(format!("{} is not a movie", path));
+        }
+        let version = r.u8()?;
+        if version != VERSION {
+            return Err(format!("unsupported movie version {}", version));
+        }
+        let checksum = r.u16()?;
+        if checksum != cpu.mem.get_rom().get_global_checksum() {
+            return Err(String::from("movie is for another cartridge"));
+        }
+        let boot = r.bool()?;
+        let state = r.bytes()?;
+        let count = r.u32()? as usize;
+        let mut frames = Vec::with_capacity(count);
+        for _ in 0..count {
+            frames.push(Frame { buttons: r.u8()?, hash: r.u32()? });
+        }
+
+        if state.is_empty() {
+            if boot != cpu.mem.is_bootrom_enabled() {
+                return Err(format!("movie recorded {} the boot ROM", if boot { "with" } else { "without" }));
+            }
+        } else {
+            savestate::load_from_slice(cpu, &state)?;
+        }
+        if frames.is_empty() {
+            return Err(format!("{} has no frames", path));
+        }
+        cpu.mem.joypad.set_latched(Some(frames[0].buttons));
+        Ok(Movie {
+            path: path.to_string(),
+            mode: Mode::Playing,
+            checksum: checksum,
+            boot: boot,
+            state: state,
+            frames: frames,
+            current: 0,
+            desyncs: vec![],
+        })
+    }
+
+    pub fn get_mode(&self) -> Mode {
+        self.mode
+    }
+    pub fn get_desyncs(&self) -> &[usize] {
+        &self.desyncs
+    }
+
+    // Once the frame is displayed: its hash, then the buttons of the next one
+    pub fn frame_end(&mut self, cpu: &mut Cpu, render: &Render) {
+        let hash = render.frame_hash();
+        let frame = self.current;
+        self.current += 1;
+        match self.mode {
+            Mode::Recording => {
+                self.frames[frame].hash = hash;
+                let buttons = cpu.mem.joypad.get_pressed();
+                self.frames.push(Frame { buttons: buttons, hash: 0 });
+                cpu.mem.joypad.set_latched(Some(buttons));
+            },
+            Mode::Playing => {
+                if self.frames[frame].hash != hash {
+                    if self.desyncs.is_empty() {
+                        println!("Movie desync at frame {}", frame);
+                    }
+                    self.desyncs.push(frame);
+                }
+                match self.frames.get(frame + 1) {
+                    Some(f) => cpu.mem.joypad.set_latched(Some(f.buttons)),
+                    None => {
+                        self.mode = Mode::Finished;
+                        cpu.mem.joypad.set_latched(None);
+                        println!("{}", self.summary());
+                    },
+                }
+            },
+            Mode::Finished => {},
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        match self.mode {
+            Mode::Recording => format!("Movie {}: {} frames recorded", self.path, self.current),
+            _ if self.desyncs.is_empty() => format!("Movie {}: {} frames played, all in sync", self.path, self.current),
+            _ => format!("Movie {}: {} frames played, {} out of sync from frame {}",
+                         self.path, self.current, self.desyncs.len(), self.desyncs[0]),
+        }
+    }
+
+    // Write a recording, without the frame still running
+    pub fn save(&self) -> Result<(), String> {
+        let mut w = savestate::Writer::new();
+        for &b in MAGIC.iter() {
+            w.u8(b);
+        }
+        w.u8(VERSION);
+        w.u16(self.checksum);
+        w.bool(self.boot);
+        w.bytes(&self.state);
+        w.u32(self.current as u32);
+        for f in self.frames.iter().take(self.current) {
+            w.u8(f.buttons);
+            w.u32(f.hash);
+        }
+        fs::write(&self.path, w.into_vec()).map_err(|e| format!("can't write {}: {}", self.path, e))
+    }
+}
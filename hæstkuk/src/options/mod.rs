@@ -0,0 +1,227 @@
+// Command line options
+#![allow(dead_code)]
+use std::str::FromStr;
+use log::LevelFilter;
+use headless;
+use trace;
+
+pub const DEFAULT_BOOTROM: &str = "./DMG_ROM.bin";
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub rom: String,
+    pub bootrom: String,
+    pub skip_boot: bool,
+    // Window scale, a power of two from 1 to 32
+    pub scale: usize,
+    pub palette: Option<String>,
+    pub sgb: bool,
+    pub headless: bool,
+    // Quit after this many frames
+    pub frames: Option<u64>,
+    // Emulation speed, 1.0 being real time and 0 unlimited
+    pub speed: f64,
+    pub debug_windows: bool,
+    pub debug: bool,
+    pub gdb: Option<u16>,
+    pub trace: Option<String>,
+    pub trace_start: trace::Condition,
+    pub trace_stop: Option<trace::Condition>,
+    pub cdl: Option<String>,
+    pub profile: Option<String>,
+    pub script: Option<String>,
+    pub movie_record: Option<String>,
+    pub movie_play: Option<String>,
+    pub log_level: Option<LevelFilter>,
+    // WebP recording, from the start
+    pub record: Option<String>,
+    // Save state loaded after reset
+    pub state: Option<String>,
+    // Headless run
+    pub png: Option<String>,
+    pub until_serial: Option<String>,
+    pub fail_serial: Option<String>,
+    pub until_pc: Option<u16>,
+    pub until_loop: bool,
+    pub no_serial: bool,
+    pub help: bool,
+}
+
+// Option, argument name and description, as shown by --help
+const HELP: [(&str, &str, &str); 29] = [
+    ("--bootrom",         "<file>",  "boot ROM (default ./DMG_ROM.bin)"),
+    ("--skip-boot",       "",        "start the cartridge directly at 0x0100"),
+    ("--scale",           "<n>",     "window scale: 1, 2, 4, 8, 16 or 32 (default 4)"),
+    ("--palette",         "<name>",  "DMG palette name, or 4 hex colors from the lightest"),
+    ("--sgb",             "",        "run SGB enhanced cartridges as on a Super Game Boy"),
+    ("--speed",           "<x>",     "speed multiplier, 0 for unlimited (default 1)"),
+    ("--frames",          "<n>",     "quit after n frames"),
+    ("--debug-windows",   "",        "open the BG map, tiles and OAM windows at start"),
+    ("--debug",           "",        "break into the debugger console at start, F9 breaks later"),
+    ("--gdb",             "<port>",  "GDB remote protocol server on a localhost port"),
+    ("--trace",           "<file>",  "log each instruction to the file"),
+    ("--trace-start",     "<cond>",  "start the trace at: boot, pc:<addr> or an instruction count"),
+    ("--trace-stop",      "<cond>",  "stop the trace at: pc:<addr> or an instruction count"),
+    ("--cdl",             "<file>",  "log ROM code, data and graphics bytes to the file, kept across runs"),
+    ("--profile",         "<file>",  "profile the run, report in the file and heatmap next to it as PNG"),
+    ("--movie-record",    "<file>",  "record the joypad of each frame to a movie, from power-on or --state"),
+    ("--movie-play",      "<file>",  "replay a movie, checking each frame against the recorded one"),
+    ("--script",          "<file>",  "run a Lua script, see the README for its functions"),
+    ("--log",             "<level>", "log level: off, error, warn, info, debug or trace"),
+    ("--record",          "<file>",  "record a WebP animation from the start"),
+    ("--state",           "<file>",  "load this save state at start"),
+    ("--headless",        "",        "run without any window"),
+    ("--png",             "<file>",  "headless: save the last frame"),
+    ("--until-serial",    "<text>",  "headless: pass when the serial output contains text"),
+    ("--fail-serial",     "<text>",  "headless: fail when the serial output contains text"),
+    ("--until-pc",        "<hex>",   "headless: pass when PC reaches the address"),
+    ("--until-loop",      "",        "headless: pass when the CPU jumps to itself"),
+    ("--no-serial",       "",        "headless: don't copy the serial output to stdout"),
+    ("--help",            "",        "show this help"),
+];
+
+impl Options {
+    pub fn new() -> Options {
+        Options {
+            rom: String::new(),
+            bootrom: String::from(DEFAULT_BOOTROM),
+            skip_boot: false,
+            scale: 4,
+            palette: None,
+            sgb: false,
+            headless: false,
+            frames: None,
+            speed: 1.0,
+            debug_windows: false,
+            debug: false,
+            gdb: None,
+            trace: None,
+            trace_start: trace::Condition::Always,
+            trace_stop: None,
+            cdl: None,
+            profile: None,
+            script: None,
+            movie_record: None,
+            movie_play: None,
+            log_level: None,
+            record: None,
+            state: None,
+            png: None,
+            until_serial: None,
+            fail_serial: None,
+            until_pc: None,
+            until_loop: false,
+            no_serial: false,
+            help: false,
+        }
+    }
+}
+
+fn parse_number<T: FromStr>(option: &str, v: &str) -> Result<T, String> {
+    v.parse().map_err(|_| format!("invalid value '{}' for {}", v, option))
+}
+
+// Parse the arguments, without the program name.
+// Options taking a value accept both "--option value" and "--option=value".
+pub fn parse(args: &[String]) -> Result<Options, String> {
+    let mut o = Options::new();
+    let mut i = 0;
+    while i < args.len() {
+        let arg = &args[i];
+        i += 1;
+        if !arg.starts_with("--") {
+            if !o.rom.is_empty() {
+                return Err(format!("unexpected argument '{}'", arg));
+            }
+            o.rom = arg.clone();
+            continue;
+        }
+        let (name, inline) = match arg.find('=') {
+            Some(p) => (&arg[..p], Some(arg[p+1..].to_string())),
+            None    => (&arg[..], None),
+        };
+        let takes_value = match HELP.iter().find(|h| h.0 == name) {
+            Some(h) => !h.1.is_empty(),
+            None    => return Err(format!("unknown option '{}'", name)),
+        };
+        let value = if takes_value {
+            match inline {
+                Some(v) => v,
+                None if i < args.len() => { i += 1; args[i-1].clone() },
+                None => return Err(format!("missing value for {}", name)),
+            }
+        } else {
+            if inline.is_some() {
+                return Err(format!("{} takes no value", name));
+            }
+            String::new()
+        };
+
+        match name {
+            "--bootrom"          => o.bootrom = value,
+            "--skip-boot"        => o.skip_boot = true,
+            "--scale"            => {
+                o.scale = parse_number(name, &value)?;
+                if !o.scale.is_power_of_two() || o.scale > 32 {
+                    return Err(format!("invalid scale {}", o.scale));
+                }
+            },
+            "--palette"          => o.palette = Some(value),
+            "--sgb"              => o.sgb = true,
+            "--speed"            => {
+                o.speed = parse_number(name, &value)?;
+                if !(o.speed >= 0.0) {
+                    return Err(format!("invalid speed {}", value));
+                }
+            },
+            "--frames"           => o.frames = Some(parse_number(name, &value)?),
+            "--debug-windows"    => o.debug_windows = true,
+            "--debug"            => o.debug = true,
+            "--gdb"              => o.gdb = Some(parse_number(name, &value)?),
+            "--trace"            => o.trace = Some(value),
+            "--trace-start"      => o.trace_start = trace::Condition::parse(&value)?,
+            "--trace-stop"       => o.trace_stop = Some(trace::Condition::parse(&value)?),
+            "--cdl"              => o.cdl = Some(value),
+            "--profile"          => o.profile = Some(value),
+            "--script"           => o.script = Some(value),
+            "--movie-record"     => o.movie_record = Some(value),
+            "--movie-play"       => o.movie_play = Some(value),
+            "--log"              => {
+                o.log_level = Some(LevelFilter::from_str(&value)
+                                   .map_err(|_| format!("invalid log level '{}'", value))?);
+            },
+            "--record"           => o.record = Some(value),
+            "--state"            => o.state = Some(value),
+            "--headless"         => o.headless = true,
+            "--png"              => o.png = Some(value),
+            "--until-serial"     => o.until_serial = Some(value),
+            "--fail-serial"      => o.fail_serial = Some(value),
+            "--until-pc"         => {
+                let hex = value.trim_start_matches("0x").trim_start_matches('$');
+                o.until_pc = Some(u16::from_str_radix(hex, 16)
+                                  .map_err(|_| format!("invalid address '{}'", value))?);
+            },
+            "--until-loop"       => o.until_loop = true,
+            "--no-serial"        => o.no_serial = true,
+            "--help"             => o.help = true,
+            _ => unreachable!(),
+        }
+    }
+    if o.rom.is_empty() && !o.help {
+        return Err(String::from("no ROM given"));
+    }
+    if o.movie_play.is_some() && (o.movie_record.is_some() || o.state.is_some()) {
+        return Err(String::from("--movie-play starts from the movie, without --movie-record or --state"));
+    }
+    Ok(o)
+}
+
+pub fn usage(program: &str) -> String {
+    let mut s = format!("Usage:\n\t{0} <rom.gb> [options]\n\t{0} disasm <rom.gb> [output.asm]\n\nOptions:\n", program);
+    for &(name, arg, help) in HELP.iter() {
+        s += &format!("\t{:<18} {:<8} {}\n", name, arg, help);
+    }
+    s += &format!("\nHeadless exit codes: {} passed, {} failed, {} timeout\n",
+                  headless::EXIT_PASSED, headless::EXIT_FAILED, headless::EXIT_TIMEOUT);
+    s
+}
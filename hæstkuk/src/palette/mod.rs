@@ -0,0 +1,260 @@
+// DMG palettes
+#![allow(dead_code)]
+use rom;
+
+// Colors are 0xRRGGBB, from the lightest (color 0) to the darkest (color 3)
+#[derive(Clone, Copy, Debug)]
+pub struct Palette {
+    pub bg:   [u32; 4],
+    pub obj0: [u32; 4],
+    pub obj1: [u32; 4],
+}
+
+impl Palette {
+    pub const fn uniform(colors: [u32; 4]) -> Palette {
+        Palette {
+            bg:   colors,
+            obj0: colors,
+            obj1: colors,
+        }
+    }
+}
+
+// Built-in palettes, GREEN being the original greenish screen
+pub const GREEN: Palette     = Palette::uniform([0xD0D058, 0xA0A840, 0x708028, 0x405010]);
+pub const GRAYSCALE: Palette = Palette::uniform([0xFFFFFF, 0xAAAAAA, 0x555555, 0x000000]);
+pub const POCKET: Palette    = Palette::uniform([0xC4CFA1, 0x8B956D, 0x4D533C, 0x1F1F1F]);
+
+// CGB boot ROM compatibility palettes, selectable with a button combo during the boot logo
+pub const CGB_BROWN: Palette      = Palette::uniform([0xFFFFFF, 0xFFAD63, 0x843100, 0x000000]);
+pub const CGB_RED: Palette        = Palette {
+    bg:   [0xFFFFFF, 0xFF8484, 0x943A3A, 0x000000],
+    obj0: [0xFFFFFF, 0x7BFF31, 0x008400, 0x000000],
+    obj1: [0xFFFFFF, 0x63A5FF, 0x0000FF, 0x000000],
+};
+pub const CGB_DARK_BROWN: Palette = Palette::uniform([0xFFE6C5, 0xCE9C84, 0x846B29, 0x5A3108]);
+pub const CGB_BLUE: Palette       = Palette {
+    bg:   [0xFFFFFF, 0x63A5FF, 0x0000FF, 0x000000],
+    obj0: [0xFFFFFF, 0xFF8484, 0x943A3A, 0x000000],
+    obj1: [0xFFFFFF, 0x7BFF31, 0x008400, 0x000000],
+};
+pub const CGB_DARK_BLUE: Palette  = Palette {
+    bg:   [0xFFFFFF, 0x8C8CDE, 0x52528C, 0x000000],
+    obj0: [0xFFFFFF, 0xFF8484, 0x943A3A, 0x000000],
+    obj1: [0xFFFFFF, 0xFFAD63, 0x843100, 0x000000],
+};
+pub const CGB_GRAY: Palette       = Palette::uniform([0xFFFFFF, 0xA5A5A5, 0x525252, 0x000000]);
+pub const CGB_PASTEL: Palette     = Palette::uniform([0xFFFFA5, 0xFF9494, 0x9494FF, 0x000000]);
+pub const CGB_ORANGE: Palette     = Palette::uniform([0xFFFFFF, 0xFFFF00, 0xFF0000, 0x000000]);
+pub const CGB_YELLOW: Palette     = Palette {
+    bg:   [0xFFFFFF, 0xFFFF00, 0x7B4A00, 0x000000],
+    obj0: [0xFFFFFF, 0x63A5FF, 0x0000FF, 0x000000],
+    obj1: [0xFFFFFF, 0x7BFF31, 0x008400, 0x000000],
+};
+pub const CGB_GREEN: Palette      = Palette::uniform([0xFFFFFF, 0x52FF00, 0xFF4200, 0x000000]);
+pub const CGB_DARK_GREEN: Palette = Palette {
+    bg:   [0xFFFFFF, 0x7BFF31, 0x0063C5, 0x000000],
+    obj0: [0xFFFFFF, 0xFF8484, 0x943A3A, 0x000000],
+    obj1: [0xFFFFFF, 0xFF8484, 0x943A3A, 0x000000],
+};
+pub const CGB_INVERTED: Palette   = Palette::uniform([0x000000, 0x008484, 0xFFDE00, 0xFFFFFF]);
+
+// Used by the boot ROM for titles it doesn't know
+pub const CGB_DEFAULT: Palette = CGB_DARK_GREEN;
+
+// Boot ROM tables for the Nintendo titles it knows.
+// Colors, 4 per palette, in the CGB RGB555 format.
+const CGB_COLORS: [u16; 120] = [
+    0x7FFF, 0x32BF, 0x00D0, 0x0000,  0x639F, 0x4279, 0x15B0, 0x04CB,
+    0x7FFF, 0x6E31, 0x454A, 0x0000,  0x7FFF, 0x1BEF, 0x0200, 0x0000,
+    0x7FFF, 0x421F, 0x1CF2, 0x0000,  0x7FFF, 0x5294, 0x294A, 0x0000,
+    0x7FFF, 0x03FF, 0x012F, 0x0000,  0x7FFF, 0x03EF, 0x01D6, 0x0000,
+    0x7FFF, 0x42B5, 0x3DC8, 0x0000,  0x7E74, 0x03FF, 0x0180, 0x0000,
+    0x67FF, 0x77AC, 0x1A13, 0x2D6B,  0x7ED6, 0x4BFF, 0x2175, 0x0000,
+    0x53FF, 0x4A5F, 0x7E52, 0x0000,  0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0,
+    0x03ED, 0x7FFF, 0x255F, 0x0000,  0x036A, 0x021F, 0x03FF, 0x7FFF,
+    0x7FFF, 0x01DF, 0x0112, 0x0000,  0x231F, 0x035F, 0x00F2, 0x0009,
+    0x7FFF, 0x03EA, 0x011F, 0x0000,  0x299F, 0x001A, 0x000C, 0x0000,
+    0x7FFF, 0x027F, 0x001F, 0x0000,  0x7FFF, 0x03E0, 0x0206, 0x0120,
+    0x7FFF, 0x7EEB, 0x001F, 0x7C00,  0x7FFF, 0x3FFF, 0x7E00, 0x001F,
+    0x7FFF, 0x03FF, 0x001F, 0x0000,  0x03FF, 0x001F, 0x000C, 0x0000,
+    0x7FFF, 0x033F, 0x0193, 0x0000,  0x0000, 0x4200, 0x037F, 0x7FFF,
+    0x7FFF, 0x7E8C, 0x7C00, 0x0000,  0x7FFF, 0x1BEF, 0x6180, 0x0000,
+];
+
+// OBJ0, OBJ1 and BG palettes, as offsets in CGB_COLORS. A few of them
+// start in the middle of a palette.
+const CGB_COMBINATIONS: [(u8, u8, u8); 51] = [
+    ( 16,  16, 116), ( 72,  72,  72), ( 80,  80,  80), ( 96,  96,  96), ( 36,  36,  36), (  0,   0,   0),
+    (108, 108, 108), ( 20,  20,  20), ( 48,  48,  48), (104, 104, 104), ( 64,  32,  32), ( 16, 112, 112),
+    ( 16,   8,   8), ( 12,  16,  16), ( 16, 116, 116), (112,  16, 112), (  8,  68,   8), ( 64,  64,  32),
+    ( 16,  16,  28), ( 16,  16,  72), ( 16,  16,  80), ( 76,  76,  36), ( 15,  15,  44), ( 68,  68,   8),
+    ( 16,  16,   8), ( 16,  16,  12), (112, 112,   0), ( 12,  12,   0), (  0,   0,   4), ( 72,  88,  72),
+    ( 80,  88,  80), ( 96,  88,  96), ( 64,  88,  32), ( 68,  16,  52), (111,   0,  56), (111,  16,  60),
+    ( 76,  91,  36), ( 64, 112,  40), ( 16,  92, 112), ( 68,  88,   8), ( 16,   0,   8), ( 16, 112,  12),
+    (112,  12,   0), ( 12, 112,  16), ( 84, 112,  16), ( 12, 112,   0), (100,  12, 112), (  0, 112,  32),
+    ( 16,  12, 112), (112,  12,  24), ( 16, 112, 116),
+];
+
+// Title checksums, the last ones being shared by several titles
+const TITLE_CHECKSUMS: [u8; 94] = [
+    0x00, 0x88, 0x16, 0x36, 0xD1, 0xDB, 0xF2, 0x3C, 0x8C, 0x92, 0x3D, 0x5C, 0x58, 0xC9, 0x3E, 0x70,
+    0x1D, 0x59, 0x69, 0x19, 0x35, 0xA8, 0x14, 0xAA, 0x75, 0x95, 0x99, 0x34, 0x6F, 0x15, 0xFF, 0x97,
+    0x4B, 0x90, 0x17, 0x10, 0x39, 0xF7, 0xF6, 0xA2, 0x49, 0x4E, 0x43, 0x68, 0xE0, 0x8B, 0xF0, 0xCE,
+    0x0C, 0x29, 0xE8, 0xB7, 0x86, 0x9A, 0x52, 0x01, 0x9D, 0x71, 0x9C, 0xBD, 0x5D, 0x6D, 0x67, 0x3F,
+    0x6B,
+    0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4, 0xB3, 0x46,
+    0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4, 0xB3,
+];
+// From this entry, the 4th title letter must match too
+const FIRST_AMBIGUOUS: usize = 65;
+const FOURTH_LETTERS: &[u8; 29] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+// Combination of each title
+const TITLE_COMBINATIONS: [u8; 94] = [
+    0, 4, 5, 35, 34, 3, 31, 15, 10, 5, 19, 36, 7, 37, 30, 44,
+    21, 32, 31, 20, 5, 33, 13, 14, 5, 29, 5, 18, 9, 3, 2, 26,
+    25, 25, 41, 42, 26, 45, 42, 45, 36, 38, 26, 42, 30, 41, 34, 34,
+    5, 42, 6, 5, 33, 25, 42, 42, 40, 2, 16, 25, 42, 42, 5, 0,
+    39,
+    36, 22, 25, 6, 32, 12, 36, 11, 39, 18, 39, 24, 31, 50, 17, 46,
+    6, 27, 0, 47, 41, 41, 0, 0, 19, 34, 23, 18, 29,
+];
+
+// RGB555 to 0xRRGGBB
+fn rgb555(c: u16) -> u32 {
+    let channel = |v: u16| ((v as u32 & 0x1F) * 255 + 15) / 31;
+    channel(c) << 16 | channel(c >> 5) << 8 | channel(c >> 10)
+}
+
+fn cgb_combination(i: usize) -> Palette {
+    let (obj0, obj1, bg) = CGB_COMBINATIONS[i];
+    let colors = |offset: u8| {
+        let o = offset as usize;
+        [rgb555(CGB_COLORS[o]), rgb555(CGB_COLORS[o+1]), rgb555(CGB_COLORS[o+2]), rgb555(CGB_COLORS[o+3])]
+    };
+    Palette { bg: colors(bg), obj0: colors(obj0), obj1: colors(obj1) }
+}
+
+// Joypad buttons as returned by Joypad::get_buttons
+pub const BTN_A:     u8 = 0b0000_0001;
+pub const BTN_B:     u8 = 0b0000_0010;
+pub const BTN_RIGHT: u8 = 0b0001_0000;
+pub const BTN_LEFT:  u8 = 0b0010_0000;
+pub const BTN_UP:    u8 = 0b0100_0000;
+pub const BTN_DOWN:  u8 = 0b1000_0000;
+
+// Palette picked by the boot ROM from the cartridge header
+pub fn cgb_compat(rom: &rom::ROM) -> Palette {
+    if !rom.is_nintendo_licensee() {
+        return CGB_DEFAULT;
+    }
+    let checksum = rom.get_title_checksum();
+    let fourth = rom.buffer[0x134+3];
+    for (i, &sum) in TITLE_CHECKSUMS.iter().enumerate() {
+        if sum == checksum && (i < FIRST_AMBIGUOUS || FOURTH_LETTERS[i - FIRST_AMBIGUOUS] == fourth) {
+            return cgb_combination(TITLE_COMBINATIONS[i] as usize);
+        }
+    }
+    CGB_DEFAULT
+}
+
+// Palette selected by holding a direction and optionally A or B during the boot logo
+pub fn cgb_combo(buttons: u8) -> Option<Palette> {
+    let a = buttons&BTN_A != 0;
+    let b = buttons&BTN_B != 0;
+    let pick = |plain: Palette, with_a: Palette, with_b: Palette| -> Palette {
+        if a { with_a } else if b { with_b } else { plain }
+    };
+    if buttons&BTN_UP != 0 {
+        Some(pick(CGB_BROWN, CGB_RED, CGB_DARK_BROWN))
+    } else if buttons&BTN_LEFT != 0 {
+        Some(pick(CGB_BLUE, CGB_DARK_BLUE, CGB_GRAY))
+    } else if buttons&BTN_DOWN != 0 {
+        Some(pick(CGB_PASTEL, CGB_ORANGE, CGB_YELLOW))
+    } else if buttons&BTN_RIGHT != 0 {
+        Some(pick(CGB_GREEN, CGB_DARK_GREEN, CGB_INVERTED))
+    } else {
+        None
+    }
+}
+
+// Parse a palette from hex colors, lightest first, e.g. "e0f8d0,88c070,346856,081820".
+// Three lists separated by ';' give the BG, OBJ0 and OBJ1 colors.
+pub fn parse(spec: &str) -> Result<Palette, String> {
+    let mut layers = vec![];
+    for list in spec.split(';') {
+        let mut colors = [0_u32; 4];
+        let mut count = 0;
+        for c in list.split(|c: char| c == ',' || c.is_whitespace()).filter(|c| !c.is_empty()) {
+            if count == 4 {
+                return Err(format!("too many colors in '{}'", list.trim()));
+            }
+            let hex = c.trim_start_matches('#').trim_start_matches("0x");
+            if hex.len() != 6 {
+                return Err(format!("'{}' is not a RRGGBB color", c));
+            }
+            colors[count] = u32::from_str_radix(hex, 16).map_err(|_| format!("'{}' is not a RRGGBB color", c))?;
+            count += 1;
+        }
+        if count != 4 {
+            return Err(format!("expected 4 colors in '{}'", list.trim()));
+        }
+        layers.push(colors);
+    }
+    match layers.len() {
+        1 => Ok(Palette::uniform(layers[0])),
+        3 => Ok(Palette { bg: layers[0], obj0: layers[1], obj1: layers[2] }),
+        _ => Err(format!("expected 1 or 3 color lists, got {}", layers.len())),
+    }
+}
+
+// Named palettes available at runtime, the current one being cycled with a key
+#[derive(Clone, Debug)]
+pub struct Palettes {
+    list: Vec<(String, Palette)>,
+    current: usize,
+}
+
+impl Palettes {
+    pub fn new() -> Palettes {
+        Palettes {
+            list: vec![
+                (String::from("green"),     GREEN),
+                (String::from("grayscale"), GRAYSCALE),
+                (String::from("pocket"),    POCKET),
+                (String::from("cgb"),       CGB_DEFAULT),
+            ],
+            current: 0,
+        }
+    }
+
+    // Add or replace a named palette
+    pub fn set(&mut self, name: &str, p: Palette) {
+        match self.list.iter().position(|e| e.0 == name) {
+            Some(i) => self.list[i].1 = p,
+            None    => self.list.push((String::from(name), p)),
+        }
+    }
+
+    // Select a palette by name, or from a list of hex colors
+    pub fn select(&mut self, spec: &str) -> Result<(), String> {
+        if let Some(i) = self.list.iter().position(|e| e.0 == spec) {
+            self.current = i;
+            return Ok(());
+        }
+        let p = parse(spec).map_err(|e| format!("unknown palette '{}' ({})", spec, e))?;
+        self.set("custom", p);
+        self.select("custom")
+    }
+
+    pub fn next(&mut self) {
+        self.current = (self.current+1) % self.list.len();
+    }
+    pub fn get(&self) -> Palette {
+        self.list[self.current].1
+    }
+    pub fn get_name(&self) -> &str {
+        &self.list[self.current].0
+    }
+}
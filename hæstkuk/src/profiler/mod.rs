@@ -0,0 +1,127 @@
+// Profiler: instructions and cycles per PC, reads and writes per address
+#![allow(dead_code)]
+extern crate image;
+use std::collections::{BTreeSet, HashMap};
+use symbols::Symbols;
+
+// CPU cycles in a frame
+pub const FRAME_CYCLES: u64 = 70224;
+
+// Interrupt vectors, always function starts
+const VECTORS: [u16; 5] = [0x40, 0x48, 0x50, 0x58, 0x60];
+
+#[derive(Clone, Debug, Default)]
+pub struct Profiler {
+    // Instructions run and their cycles per (bank, PC)
+    exec: HashMap<(u16, u16), (u64, u64)>,
+    reads: Vec<u64>,
+    writes: Vec<u64>,
+    // Targets of the calls taken, the function starts without symbols
+    calls: BTreeSet<(u16, u16)>,
+    instructions: u64,
+    cycles: u64,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            reads: vec![0; 0x10000],
+            writes: vec![0; 0x10000],
+            ..Default::default()
+        }
+    }
+
+    pub fn instruction(&mut self, bank: u16, pc: u16, cycles: u32) {
+        let e = self.exec.entry((bank, pc)).or_insert((0, 0));
+        e.0 += 1;
+        e.1 += cycles as u64;
+        self.instructions += 1;
+        self.cycles += cycles as u64;
+    }
+    pub fn call(&mut self, bank: u16, target: u16) {
+        self.calls.insert((bank, target));
+    }
+    pub fn read(&mut self, addr: u16) {
+        self.reads[addr as usize] += 1;
+    }
+    pub fn write(&mut self, addr: u16) {
+        self.writes[addr as usize] += 1;
+    }
+
+    // Function containing pc: the closest label, or call target, before it
+    fn function(&self, symbols: &Symbols, bank: u16, pc: u16) -> String {
+        if let Some((name, _)) = symbols.nearest(Some(bank), pc).or(symbols.nearest(None, pc)) {
+            return name.to_string();
+        }
+        let area = if pc < 0x4000 { 0 } else if pc < 0x8000 { 0x4000 } else { pc & 0xE000 };
+        let vectors = VECTORS.iter().filter(|&&v| v <= pc && v >= area).map(|&v| (0, v)).last();
+        let call = self.calls.range((bank, area)..=(bank, pc)).next_back().cloned();
+        match call.into_iter().chain(vectors).max_by_key(|c| c.1) {
+            Some((b, a)) => format!("Call_{:03X}_{:04X}", b, a),
+            None => String::from("?"),
+        }
+    }
+
+    // Text report, frames being the frames run while profiling
+    pub fn report(&self, symbols: &Symbols, frames: u64) -> String {
+        let frames = frames.max(1);
+        let percent = |c: u64| c as f64 * 100.0 / self.cycles.max(1) as f64;
+        let mut out = format!("{} instructions, {} cycles in {} frames, {} cycles per frame ({:.1}% of {})\n",
+                              self.instructions, self.cycles, frames, self.cycles / frames,
+                              (self.cycles / frames) as f64 * 100.0 / FRAME_CYCLES as f64, FRAME_CYCLES);
+
+        let mut functions: HashMap<String, u64> = HashMap::new();
+        for (&(bank, pc), &(_, cycles)) in self.exec.iter() {
+            *functions.entry(self.function(symbols, bank, pc)).or_insert(0) += cycles;
+        }
+        let mut functions: Vec<(String, u64)> = functions.into_iter().collect();
+        functions.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        out += "\nFunctions\n    cycles      %  per frame  name\n";
+        for &(ref name, cycles) in functions.iter().take(30) {
+            out += &format!("{:10} {:5.1}% {:10}  {}\n", cycles, percent(cycles), cycles / frames, name);
+        }
+
+        let mut exec: Vec<(&(u16, u16), &(u64, u64))> = self.exec.iter().collect();
+        exec.sort_by(|a, b| (b.1).1.cmp(&(a.1).1).then(a.0.cmp(b.0)));
+        out += "\nHottest instructions\n     count     cycles      %  address\n";
+        for &(&(bank, pc), &(count, cycles)) in exec.iter().take(30) {
+            let label = symbols.describe(Some(bank), pc).or(symbols.describe(None, pc))
+                .map_or(String::new(), |l| format!(" <{}>", l));
+            out += &format!("{:10} {:10} {:5.1}%  {:02X}:{:04X}{}\n", count, cycles, percent(cycles), bank, pc, label);
+        }
+
+        for &(title, counts) in [("reads", &self.reads), ("writes", &self.writes)].iter() {
+            let mut top: Vec<(usize, u64)> = counts.iter().cloned().enumerate().filter(|&(_, n)| n > 0).collect();
+            top.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+            out += &format!("\nMost {}\n     count  address\n", title);
+            for &(addr, n) in top.iter().take(16) {
+                let label = symbols.describe(None, addr as u16).map_or(String::new(), |l| format!(" <{}>", l));
+                out += &format!("{:10}  {:04X}{}\n", n, addr, label);
+            }
+        }
+        out
+    }
+
+    // 256x256 image of the address space, one pixel per address from 0x0000
+    // at the top left: executions in blue, reads in green and writes in red,
+    // on a log scale
+    pub fn save_heatmap(&self, path: &str) -> image::ImageResult<()> {
+        let mut exec = vec![0u64; 0x10000];
+        for (&(_, pc), &(count, _)) in self.exec.iter() {
+            exec[pc as usize] += count;
+        }
+        let scale = |counts: &[u64]| {
+            let max = (*counts.iter().max().unwrap_or(&0) as f64 + 1.0).ln();
+            counts.iter().map(|&n| if n == 0 { 0 } else { (64.0 + 191.0 * (n as f64 + 1.0).ln() / max) as u8 })
+                .collect::<Vec<u8>>()
+        };
+        let (r, g, b) = (scale(&self.writes), scale(&self.reads), scale(&exec));
+        let mut buffer = vec![0u8; 0x10000*3];
+        for i in 0..0x10000 {
+            buffer[i*3]   = r[i];
+            buffer[i*3+1] = g[i];
+            buffer[i*3+2] = b[i];
+        }
+        image::save_buffer(path, &buffer, 256, 256, image::ColorType::Rgb8)
+    }
+}
@@ -21,64 +21,107 @@ use std::process;
 use std::time::Duration;
 
 use lr35902::Cpu;
+use palette;
+use palette::Palette;
+use sgb;
+use font;
+use memview;
+use input;
 
 #[derive(Clone, Debug, Copy)]
 pub enum PixelBuffer {
     Render,
     BG,
     Tiles,
+    OAM,
+}
+
+// DMG layers, each one having its own colors in a palette
+#[derive(Clone, Debug, Copy)]
+pub enum Layer {
+    BG,
+    OBJ0,
+    OBJ1,
 }
 
 #[allow(dead_code)]
 pub struct Render<'a> {
-    render_window: Window,
-    bg_window: Window,
-    tiles_window: Window,
+    // None when running headless
+    render_window: Option<Window>,
+    bg_window: Option<Window>,
+    tiles_window: Option<Window>,
+    oam_window: Option<Window>,
+    memview: memview::MemView<'a>,
+    input: input::Input,
+    // Emulation paused from a debug window
+    paused: bool,
+    // F9 pressed, for the debugger console
+    break_request: bool,
+    // BG map shown in the BG map window, None for the one used by LCDC
+    bg_map: Option<u16>,
     width: usize,
     height: usize,
     buffer_render: Vec<u32>,
+    // Screen as hashed for the movies, DMG shades instead of the user palette colors
+    buffer_shades: Vec<u32>,
     buffer_bg: Vec<u32>,
     buffer_tiles: Vec<u32>,
+    buffer_oam: Vec<u32>,
     f1_pressed: bool,
+    f2_pressed: bool,
     f11_pressed: bool,
     f12_pressed: bool,
     recording: bool,
+    record_path: String,
     webp_encoder: webp_animation::Encoder,
     webp_timestamp: i32,
+    // Frame size the encoder was created for
+    record_size: (usize, usize),
+    palette: Palette,
+    palettes: palette::Palettes,
+    // Super Game Boy output
+    sgb: bool,
+    sgb_line: Option<Vec<[u32; 4]>>,
+    sgb_border: Vec<Option<u32>>,
+    sgb_mask: u8,
+    sgb_backdrop: u32,
+    last_frame: Vec<u32>,
+    // Frames displayed since start
+    frames: u64,
+    // Drawn by scripts over the screen, 0xFFRRGGBB pixels or 0 for none
+    overlay: Vec<u32>,
 
     phantom: PhantomData<&'a u8>,
 }
 
 
 impl<'a> Render<'a> {
-    pub fn new() -> Render<'a> {
-        let tiles_window = Window::new(
-            "Tiles - ESC to exit",
-            256,
-            256,
-            WindowOptions::default(),
-            )
-            .unwrap_or_else(|e| {
-                panic!("{}", e);
-            });
-        let bg_window = Window::new(
-            "BGMap - ESC to exit",
-            256,
-            256,
-            WindowOptions::default(),
-            )
-            .unwrap_or_else(|e| {
-                panic!("{}", e);
-            });
-        let mut render_window = Window::new(
+    // The debug windows are opened at start with debug_windows, or later with their keys
+    pub fn new(sgb: bool, scale: usize, debug_windows: bool) -> Render<'a> {
+        let (frame_width, frame_height) = if sgb { (sgb::WIDTH, sgb::HEIGHT) } else { (160, 144) };
+        let mut render = Render::new_headless(sgb);
+        let scale = match scale {
+            1  => Scale::X1,
+            2  => Scale::X2,
+            8  => Scale::X8,
+            16 => Scale::X16,
+            32 => Scale::X32,
+            _  => Scale::X4,
+        };
+        if debug_windows {
+            render.toggle_debug_window(PixelBuffer::BG);
+            render.toggle_debug_window(PixelBuffer::Tiles);
+            render.toggle_debug_window(PixelBuffer::OAM);
+        }
+        let render_window = Window::new(
             "Render - ESC to exit",
-            160,
-            144,
+            frame_width,
+            frame_height,
             WindowOptions {
                 borderless: false,
                 title: true,
                 resize: false,
-                scale: Scale::X4,
+                scale: scale,
                 scale_mode: ScaleMode::Stretch,
                 topmost: false,
                 transparency: false,
@@ -88,104 +131,261 @@ impl<'a> Render<'a> {
             .unwrap_or_else(|e| {
                 panic!("{}", e);
             });
-        render_window.limit_update_rate(Some(std::time::Duration::from_micros(16600)));
-        let render = Render {
-            render_window: render_window,
-            bg_window: bg_window,
-            tiles_window: tiles_window,
+        render.render_window = Some(render_window);
+        render.set_speed(1.0);
+        render
+    }
+
+    // Speed multiplier of the display rate, 0 for unlimited
+    pub fn set_speed(&mut self, speed: f64) {
+        if let Some(ref mut w) = self.render_window {
+            let rate = if speed > 0.0 { Some(Duration::from_micros((16600.0/speed) as u64)) } else { None };
+            w.limit_update_rate(rate);
+        }
+    }
+
+    pub fn set_record_path(&mut self, path: &str) {
+        self.record_path = String::from(path);
+    }
+
+    pub fn start_recording(&mut self) {
+        let (w, h) = self.get_frame_size();
+        self.webp_encoder = Encoder::new((w as u32, h as u32)).unwrap();
+        self.webp_timestamp = 0;
+        self.record_size = (w, h);
+        self.recording = true;
+    }
+
+    // Save the recording, if any
+    pub fn stop_recording(&mut self) {
+        if !self.recording {
+            return;
+        }
+        println!("Saving {}", self.record_path);
+        let (w, h) = self.record_size;
+        let mut webpe = Encoder::new((w as u32, h as u32)).unwrap();
+        swap(&mut webpe, &mut self.webp_encoder);
+
+        let contents = &webpe.finalize(self.webp_timestamp).unwrap();
+
+        if let Err(e) = fs::write(&self.record_path, contents) {
+            println!("Can't write {}: {}", self.record_path, e);
+        }
+
+        self.webp_timestamp = 0;
+        self.recording = false;
+    }
+
+    // Renderer without any window, frames are only kept in memory
+    pub fn new_headless(sgb: bool) -> Render<'a> {
+        let (frame_width, frame_height) = if sgb { (sgb::WIDTH, sgb::HEIGHT) } else { (160, 144) };
+        Render {
+            render_window: None,
+            bg_window: None,
+            tiles_window: None,
+            oam_window: None,
+            memview: memview::MemView::new(),
+            input: input::Input::new(),
+            paused: false,
+            break_request: false,
+            bg_map: None,
             width: 256,
             height: 256,
             buffer_render: vec![0x00; 256*256],
+            buffer_shades: vec![0x00; 256*256],
             buffer_bg:     vec![0x00; 256*256],
             buffer_tiles:  vec![0x00; 256*256],
+            buffer_oam:    vec![0x00; 256*256],
             f1_pressed: false,
+            f2_pressed: false,
             f11_pressed: false,
             f12_pressed: false,
             recording: false,
-            webp_encoder: Encoder::new((160, 144)).unwrap(),
+            record_path: String::from("test.webp"),
+            webp_encoder: Encoder::new((frame_width as u32, frame_height as u32)).unwrap(),
             webp_timestamp: 0,
+            record_size: (0, 0),
+            palette: palette::GREEN,
+            palettes: palette::Palettes::new(),
+            sgb: sgb,
+            sgb_line: None,
+            sgb_border: vec![None; sgb::WIDTH*sgb::HEIGHT],
+            sgb_mask: 0,
+            sgb_backdrop: 0xFFFFFF,
+            last_frame: vec![],
+            frames: 0,
+            overlay: vec![],
             phantom: PhantomData,
+        }
+    }
+
+    // Open or close one of the debug windows
+    pub fn toggle_debug_window(&mut self, view: PixelBuffer) {
+        let (window, title) = match view {
+            PixelBuffer::BG    => (&mut self.bg_window,    "BGMap - M to switch map, ESC to exit"),
+            PixelBuffer::Tiles => (&mut self.tiles_window, "Tiles - ESC to exit"),
+            PixelBuffer::OAM   => (&mut self.oam_window,   "OAM - ESC to exit"),
+            PixelBuffer::Render => return,
         };
-        render
+        if window.is_some() {
+            *window = None;
+            return;
+        }
+        *window = Some(Window::new(
+            title,
+            256,
+            256,
+            WindowOptions {
+                scale: Scale::X2,
+                ..WindowOptions::default()
+            },
+            )
+            .unwrap_or_else(|e| {
+                panic!("{}", e);
+            }));
+    }
+
+    // Refresh the opened debug windows
+    pub fn update_debug_windows(&mut self, cpu: &mut Cpu<'a>) {
+        self.display_BG_map(cpu);
+        self.display_tile_pattern_tables(cpu);
+        self.display_OAM(cpu);
+        self.memview.update(cpu, self.paused);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    // Keep the windows alive while the emulation is paused
+    pub fn refresh_paused(&mut self, cpu: &mut Cpu<'a>) {
+        self.update_debug_windows(cpu);
+        let (w, h) = self.get_frame_size();
+        let buf = self.get_frame();
+        if let Some(ref mut window) = self.render_window {
+            window.update_with_buffer(&buf, w, h).unwrap();
+        }
+    }
+
+    // Key bindings and gamepads
+    pub fn set_input(&mut self, input: input::Input) {
+        self.input = input;
+    }
+
+    pub fn take_break_request(&mut self) -> bool {
+        let r = self.break_request;
+        self.break_request = false;
+        r
+    }
+
+    pub fn is_headless(&self) -> bool {
+        self.render_window.is_none()
+    }
 
+    fn key_pressed(&self, key: Key) -> bool {
+        self.render_window.as_ref().map_or(false, |w| w.is_key_pressed(key, KeyRepeat::No))
+    }
+    fn key_released(&self, key: Key) -> bool {
+        self.render_window.as_ref().map_or(false, |w| w.is_key_released(key))
     }
 
     // Handle key pressed, returns true on quit
     pub fn get_events(&mut self, cpu: &mut Cpu<'a>) -> bool {
-        cpu.mem.joypad.set_a(self.render_window.is_key_down(Key::A));
-        cpu.mem.joypad.set_b(self.render_window.is_key_down(Key::B));
-        cpu.mem.joypad.set_select(self.render_window.is_key_down(Key::Space));
-        cpu.mem.joypad.set_start(self.render_window.is_key_down(Key::Enter));
-
-        cpu.mem.joypad.set_up(self.render_window.is_key_down(Key::Up));
-        cpu.mem.joypad.set_down(self.render_window.is_key_down(Key::Down));
-        cpu.mem.joypad.set_left(self.render_window.is_key_down(Key::Left));
-        cpu.mem.joypad.set_right(self.render_window.is_key_down(Key::Right));
+        let buttons = self.input.buttons(self.render_window.as_ref(), self.frames);
+        cpu.mem.joypad.set_buttons(buttons);
 
         // Disasm
-        if self.render_window.is_key_pressed(Key::F1, KeyRepeat::No) {
+        if self.key_pressed(Key::F1) {
             if self.f1_pressed == false {
                 cpu.toggle_disasm();
             }
             self.f1_pressed = true;
         }
-        if self.render_window.is_key_released(Key::F1) {
+        if self.key_released(Key::F1) {
             self.f1_pressed = false;
         }
 
+        // Cycle through the palettes
+        if self.key_pressed(Key::F2) {
+            if self.f2_pressed == false {
+                self.next_palette();
+            }
+            self.f2_pressed = true;
+        }
+        if self.key_released(Key::F2) {
+            self.f2_pressed = false;
+        }
+
+        // Debug windows
+        if self.key_pressed(Key::F3) {
+            self.toggle_debug_window(PixelBuffer::BG);
+        }
+        if self.key_pressed(Key::F4) {
+            self.toggle_debug_window(PixelBuffer::Tiles);
+        }
+        if self.key_pressed(Key::F6) {
+            self.toggle_debug_window(PixelBuffer::OAM);
+        }
+        if self.key_pressed(Key::F7) {
+            self.memview.toggle();
+        }
+        if self.key_pressed(Key::P) {
+            self.paused = !self.paused;
+            println!("{}", if self.paused { "Paused" } else { "Running" });
+        }
+        if self.key_pressed(Key::F9) {
+            self.break_request = true;
+        }
+        if self.key_pressed(Key::F10) && !cpu.mem.get_cheats().is_empty() {
+            let active = cpu.mem.get_cheats_mut().toggle_active();
+            println!("Cheats {}", if active { "on" } else { "off" });
+        }
+        self.memview.handle_keys(cpu, &mut self.paused);
+        if self.bg_window.as_ref().map_or(false, |w| w.is_key_pressed(Key::M, KeyRepeat::No)) {
+            self.bg_map = match self.bg_map {
+                None         => Some(0x9800),
+                Some(0x9800) => Some(0x9C00),
+                _            => None,
+            };
+        }
+        // Closed with their close button
+        for w in [&mut self.bg_window, &mut self.tiles_window, &mut self.oam_window].iter_mut() {
+            if w.as_ref().map_or(false, |w| !w.is_open()) {
+                **w = None;
+            }
+        }
+
         // Screenshot
-        if self.render_window.is_key_pressed(Key::F11, KeyRepeat::No) {
+        if self.key_pressed(Key::F11) {
             if self.f11_pressed == false {
                 println!("Saving image");
-                let mut buffer = vec![0x00_u8; 160*144*3];
-                let mut offset = 0;
-                for y in 0..144 {
-                    for x in 0..160 {
-                        let b = self.buffer_render[x+y*256];
-                        buffer[offset]   = ((b&0x00FF0000)>>16) as u8;
-                        buffer[offset+1] = ((b&0x0000FF00)>>8)  as u8;
-                        buffer[offset+2] = (b&0x000000FF)       as u8;
-                        offset+=3;
-                    }
-                }
-                image::save_buffer("kuk.png", buffer.as_slice(), 160, 144, image::ColorType::Rgb8).unwrap();
+                self.save_png("kuk.png").unwrap();
                 self.f11_pressed = true;
             }
         }
-        if self.render_window.is_key_released(Key::F11) {
+        if self.key_released(Key::F11) {
             self.f11_pressed = false;
         }
 
         // Animation
-        if self.render_window.is_key_pressed(Key::F12, KeyRepeat::No) {
+        if self.key_pressed(Key::F12) {
             if self.f12_pressed == false {
-                if self.webp_timestamp == 0 {
-                    self.webp_encoder = Encoder::new((160, 144)).unwrap();
-                    self.recording = true;
+                if self.recording {
+                    self.stop_recording();
                 } else {
-                    println!("SAVING");
-                    let w = self.webp_timestamp;
-                    let mut webpe = Encoder::new((160, 144)).unwrap();
-                    swap(&mut webpe, &mut self.webp_encoder);
-
-                    let contents = &webpe.finalize(w).unwrap();
-
-                    fs::write("test.webp", contents).unwrap();
-
-                    self.webp_timestamp = 0;
-                    self.recording = false;
+                    self.start_recording();
                 }
             }
             self.f12_pressed = true;
         }
-        if self.render_window.is_key_released(Key::F12) {
+        if self.key_released(Key::F12) {
             self.f12_pressed = false;
         }
 
 
-        self.bg_window.is_key_down(Key::Escape) ||
-            self.tiles_window.is_key_down(Key::Escape) ||
-            self.render_window.is_key_down(Key::Escape)
+        let escape = |w: &Option<Window>| w.as_ref().map_or(false, |w| w.is_key_down(Key::Escape));
+        escape(&self.bg_window) || escape(&self.tiles_window) || escape(&self.oam_window) ||
+            escape(&self.render_window) || self.memview.escape_pressed()
     }
 
 
@@ -200,25 +400,59 @@ impl<'a> Render<'a> {
 
         match buf {
             PixelBuffer::BG => { self.buffer_bg[x+y*self.width] = c },
-            PixelBuffer::Render => { self.buffer_render[x+y*self.width] = c },
+            PixelBuffer::Render => {
+                self.buffer_render[x+y*self.width] = c;
+                self.buffer_shades[x+y*self.width] = c;
+            },
             PixelBuffer::Tiles => { self.buffer_tiles[x+y*self.width] = c },
+            PixelBuffer::OAM => { self.buffer_oam[x+y*self.width] = c },
         }
     }
+    // The "cgb" palette, picked from the cartridge like the CGB boot ROM does
+    pub fn set_compat_palette(&mut self, p: Palette) {
+        self.palettes.set("cgb", p);
+        self.palette = self.palettes.get();
+    }
+    pub fn add_palette(&mut self, name: &str, p: Palette) {
+        self.palettes.set(name, p);
+    }
+    // Select a palette by name or from hex colors
+    pub fn select_palette(&mut self, spec: &str) -> Result<(), String> {
+        self.palettes.select(spec)?;
+        self.palette = self.palettes.get();
+        Ok(())
+    }
+    pub fn next_palette(&mut self) {
+        self.palettes.next();
+        self.palette = self.palettes.get();
+        println!("Palette: {}", self.palettes.get_name());
+    }
+
     pub fn put_pixel8(&mut self, buf: PixelBuffer, x: usize, y: usize, c: u8) {
+        self.put_pixel_layer(buf, x, y, c, Layer::BG);
+    }
+    pub fn put_pixel_layer(&mut self, buf: PixelBuffer, x: usize, y: usize, c: u8, layer: Layer) {
         let r;
         let g;
         let b;
 
         match c {
-            /*            0x00 => {r=0x00; g=0x00; b=0x00;}, // Black
-                          0x01 => {r=0x55; g=0x55; b=0x55;}, // Dark gray
-                          0x02 => {r=0xAA; g=0xAA; b=0xAA;}, // Light gray
-                          0x03 => {r=0xFF; g=0xFF; b=0xFF;}, // White
-                          */
-            0x00 => {r=0x40; g=0x50; b=0x10;}, // Black
-            0x01 => {r=0x70; g=0x80; b=0x28;}, // Dark gray
-            0x02 => {r=0xA0; g=0xA8; b=0x40;}, // Light gray
-            0x03 => {r=0xD0; g=0xD0; b=0x58;}, // White
+            // 0 Black, 1 Dark gray, 2 Light gray, 3 White
+            0x00..=0x03 => {
+                let colors = match (buf, &self.sgb_line) {
+                    // SGB colors depend on the screen cell, not on the layer
+                    (PixelBuffer::Render, &Some(ref cells)) if x < 160 => cells[x/8],
+                    _ => match layer {
+                        Layer::BG   => self.palette.bg,
+                        Layer::OBJ0 => self.palette.obj0,
+                        Layer::OBJ1 => self.palette.obj1,
+                    },
+                };
+                let rgb = colors[(3-c) as usize];
+                r = ((rgb&0xFF0000)>>16) as u8;
+                g = ((rgb&0x00FF00)>>8)  as u8;
+                b = (rgb&0x0000FF)       as u8;
+            },
 
             // Special colors
             0x55 => {r=0xFF; g=0x00; b=0x00;}, // Red
@@ -228,6 +462,12 @@ impl<'a> Render<'a> {
         }
 
         self.put_pixel24(buf, x, y, r, g, b);
+        // Shade and layer rather than the palette color, above any RGB value
+        if let PixelBuffer::Render = buf {
+            if c <= 0x03 && self.sgb_line.is_none() && x+y*self.width <= 65535 {
+                self.buffer_shades[x+y*self.width] = 0x0100_0000 | (layer as u32) << 2 | c as u32;
+            }
+        }
     }
 
     pub fn get_tile_by_id(&mut self, cpu: &mut Cpu<'a>, id: u8, is_sprite: bool) -> Vec<u8> {
@@ -240,8 +480,8 @@ impl<'a> Render<'a> {
         let mut ret = vec![0; 8*8];
         let mut offset = addr;
         for i in 0..8 {
-            let a = cpu.readMem8(offset);
-            let b = cpu.readMem8(offset+1);
+            let a = cpu.mem.peek8(offset);
+            let b = cpu.mem.peek8(offset+1);
 
             let p1 = ((a&0b10000000)>>7) | (b&0b10000000)>>6;
             let p2 = ((a&0b01000000)>>6) | (b&0b01000000)>>5;
@@ -278,28 +518,158 @@ impl<'a> Render<'a> {
         }
     }
 
-    pub fn display_tile_pattern_tables(&mut self, cpu: &mut Cpu<'a> ) {
-        let mut x = 0;
-        let mut y = 0;
+    // Tile from a given VRAM bank, as 64 color indexes
+    pub fn get_vram_tile(&mut self, cpu: &mut Cpu<'a>, bank: u8, addr: u16) -> Vec<u8> {
+        let mut ret = vec![0; 8*8];
+        for y in 0..8 {
+            for x in 0..8 {
+                ret[x+y*8] = self.get_cgb_tile_pixel(cpu, addr, bank, x, y);
+            }
+        }
+        ret
+    }
 
-        for j in (0x8000..0x97FF).step_by(16) {
-            let tile = self.get_tile_at_addr(cpu, j);
-            self.display_tile(cpu, PixelBuffer::Tiles, x, y, tile);
-            x = x+8;
-            if x > 200 {
-                x = 0;
-                y = y+8;
+    // The 384 tiles of each VRAM bank, 16 per line, then the palettes
+    pub fn display_tile_pattern_tables(&mut self, cpu: &mut Cpu<'a> ) {
+        if self.tiles_window.is_none() {
+            return;
+        }
+        for p in self.buffer_tiles.iter_mut() {
+            *p = 0x000000;
+        }
+        let banks = if cpu.mem.is_cgb() { 2 } else { 1 };
+        for bank in 0..banks {
+            for i in 0..384 {
+                let tile = self.get_vram_tile(cpu, bank, 0x8000 + (i as u16)*16);
+                let x = (bank as usize)*128 + (i%16)*8;
+                let y = (i/16)*8;
+                self.display_tile(cpu, PixelBuffer::Tiles, x, y, tile);
             }
         }
+        self.display_palettes(cpu);
 
-        self.tiles_window.update_with_buffer(&mut self.buffer_tiles, self.width, self.height)
-            .unwrap();
+        if let Some(ref mut w) = self.tiles_window {
+            w.update_with_buffer(&mut self.buffer_tiles, self.width, self.height).unwrap();
+        }
     }
 
+    // Palette swatches below the tiles: BGP, OBP0 and OBP1, or the 8 BG and 8 OBJ CGB palettes
+    pub fn display_palettes(&mut self, cpu: &mut Cpu<'a>) {
+        let top = 200;
+        let swatch = |render: &mut Render<'a>, x: usize, y: usize, w: usize, rgb: (u8, u8, u8)| {
+            for sy in 0..8 {
+                for sx in 0..w {
+                    render.put_pixel24(PixelBuffer::Tiles, x+sx, y+sy, rgb.0, rgb.1, rgb.2);
+                }
+            }
+        };
+        if cpu.mem.is_cgb() {
+            for palette in 0..8 {
+                for color in 0..4 {
+                    let x = palette*32 + color*7;
+                    let bg  = cpu.mem.lcd.get_cgb_bg_color(palette as u8, color as u8);
+                    let obj = cpu.mem.lcd.get_cgb_obj_color(palette as u8, color as u8);
+                    swatch(self, x, top+font::HEIGHT, 7, bg);
+                    swatch(self, x, top+font::HEIGHT*2+10, 7, obj);
+                }
+            }
+            font::draw_text(&mut self.buffer_tiles, self.width, 0, top, "BG", 0xFFFFFF);
+            font::draw_text(&mut self.buffer_tiles, self.width, 0, top+font::HEIGHT+10, "OBJ", 0xFFFFFF);
+            return;
+        }
+        let names = ["BGP", "OBP0", "OBP1"];
+        for (i, &(addr, layer)) in [(0xFF47, Layer::BG), (0xFF48, Layer::OBJ0), (0xFF49, Layer::OBJ1)].iter().enumerate() {
+            let x = i*80;
+            font::draw_text(&mut self.buffer_tiles, self.width, x, top, names[i], 0xFFFFFF);
+            let palette = cpu.mem.lcd.get_palette(addr);
+            for color in 0..4 {
+                for sy in 0..8 {
+                    for sx in 0..16 {
+                        self.put_pixel_layer(PixelBuffer::Tiles, x+color*16+sx, top+font::HEIGHT+sy, palette[color], layer);
+                    }
+                }
+            }
+        }
+    }
+
+    // The 40 sprites with their attributes, 4 per line
+    pub fn display_OAM(&mut self, cpu: &mut Cpu<'a>) {
+        if self.oam_window.is_none() {
+            return;
+        }
+        for p in self.buffer_oam.iter_mut() {
+            *p = 0x000000;
+        }
+        let lcdc = cpu.mem.peek8(0xFF40);
+        let h = if (lcdc&0b0000_0100)!=0 { 16 } else { 8 };
+        let width = self.width;
+        font::draw_text(&mut self.buffer_oam, width, 0, 0, if h == 16 { "OBJ 8X16" } else { "OBJ 8X8" }, 0xFFFFFF);
+
+        for i in 0..40 {
+            let offset = 0xFE00 + (i as u16)*4;
+            let y     = cpu.mem.peek8(offset);
+            let x     = cpu.mem.peek8(offset+1);
+            let index = cpu.mem.peek8(offset+2);
+            let flags = cpu.mem.peek8(offset+3);
+            let cx = (i%4)*64;
+            let cy = font::HEIGHT + (i/4)*25;
+
+            // Sprite, as displayed, over a gray background showing its size
+            let bank = if cpu.mem.is_cgb() { (flags>>3)&0x01 } else { 0 };
+            let first = if h == 16 { index&0xFE } else { index };
+            for t in 0..h/8 {
+                let tile = self.get_vram_tile(cpu, bank, 0x8000 + (first.wrapping_add(t as u8) as u16)*16);
+                for ty in 0..8 {
+                    for tx in 0..8 {
+                        let ox = if flags&0b0010_0000 != 0 { 7-tx } else { tx };
+                        let oy = if flags&0b0100_0000 != 0 { h-1-(t*8+ty) } else { t*8+ty };
+                        let c = tile[tx+ty*8];
+                        let (px, py) = (cx+1+ox, cy+1+oy);
+                        if c == 0 {
+                            self.put_pixel24(PixelBuffer::OAM, px, py, 0x40, 0x40, 0x40);
+                        } else if cpu.mem.is_cgb() {
+                            let (r, g, b) = cpu.mem.lcd.get_cgb_obj_color(flags&0b0000_0111, c);
+                            self.put_pixel24(PixelBuffer::OAM, px, py, r, g, b);
+                        } else {
+                            let palette = cpu.mem.lcd.get_sprite_palette(((flags&0b0001_0000)>>4) as u16);
+                            let layer = if flags&0b0001_0000 != 0 { Layer::OBJ1 } else { Layer::OBJ0 };
+                            self.put_pixel_layer(PixelBuffer::OAM, px, py, palette[c as usize], layer);
+                        }
+                    }
+                }
+            }
+
+            // Hidden sprites are grayed out
+            let visible = y > 0 && y < 160 && x > 0 && x < 168;
+            let color = if visible { 0xFFFFFF } else { 0x808080 };
+            let flags_str = format!("{}{}{}{}",
+                                    if flags&0b1000_0000 != 0 { "P" } else { "-" },
+                                    if flags&0b0100_0000 != 0 { "Y" } else { "-" },
+                                    if flags&0b0010_0000 != 0 { "X" } else { "-" },
+                                    if cpu.mem.is_cgb() {
+                                        format!(" B{} C{}", bank, flags&0b0000_0111)
+                                    } else {
+                                        format!(" O{}", (flags>>4)&0x01)
+                                    });
+            let lines = [
+                format!("{:02} X:{:02X}", i, x),
+                format!("Y:{:02X} T:{:02X}", y, index),
+                format!("A:{:02X}", flags),
+                flags_str,
+            ];
+            for (l, text) in lines.iter().enumerate() {
+                font::draw_text(&mut self.buffer_oam, width, cx+11, cy+l*font::HEIGHT, text, color);
+            }
+        }
+
+        if let Some(ref mut w) = self.oam_window {
+            w.update_with_buffer(&mut self.buffer_oam, self.width, self.height).unwrap();
+        }
+    }
 
     pub fn get_bg_pixel_at(&mut self, cpu: &mut Cpu<'a>, x: usize, y: usize) -> u8 {
 
-        let lcdc = cpu.mem.read8(0xFF40);
+        let lcdc = cpu.mem.peek8(0xFF40);
         let bgmap = if lcdc&0b0000_1000!=0 { 0x9C00 } else {0x9800};
         // X and Y offset in the 32x32 BGMAP
         let xoff = (x / 8)%32;
@@ -310,7 +680,7 @@ impl<'a> Render<'a> {
         // Offset in the BGMAP
         let bgoff = xoff+yoff*32;
         // Tile ID
-        let id = cpu.readMem8(bgmap+bgoff as u16);
+        let id = cpu.mem.peek8(bgmap+bgoff as u16);
         // Tile Pixels
         let tile = self.get_tile_by_id(cpu, id, false);
         // Get Pixel value
@@ -318,7 +688,7 @@ impl<'a> Render<'a> {
     }
     pub fn get_win_pixel_at(&mut self, cpu: &mut Cpu<'a>, x: usize, y: usize) -> u8 {
 
-        let lcdc = cpu.mem.read8(0xFF40);
+        let lcdc = cpu.mem.peek8(0xFF40);
         let winmap = if lcdc&0b0100_0000!=0 { 0x9C00 } else {0x9800};
         // X and Y offset in the 32x32 WIN
         let xoff = (x / 8)%32;
@@ -329,7 +699,7 @@ impl<'a> Render<'a> {
         // Offset in the WINMAP
         let winoff = xoff+yoff*32;
         // Tile ID
-        let id = cpu.readMem8(winmap+winoff as u16);
+        let id = cpu.mem.peek8(winmap+winoff as u16);
         // Tile Pixels
         let tile = self.get_tile_by_id(cpu, id, false);
         // Get Pixel value
@@ -340,7 +710,7 @@ impl<'a> Render<'a> {
         if line>144 {
             return;
         }
-        let lcdc = cpu.mem.read8(0xFF40);
+        let lcdc = cpu.mem.peek8(0xFF40);
         if lcdc&0b0010_0000 == 0 {
             return;
         }
@@ -368,7 +738,7 @@ impl<'a> Render<'a> {
         }
         let SCY  = cpu.mem.lcd.get_scy() as usize;
         let SCX  = cpu.mem.lcd.get_scx() as usize;
-        let lcdc = cpu.mem.read8(0xFF40);
+        let lcdc = cpu.mem.peek8(0xFF40);
 
         if lcdc & 1 == 0 {
             return;
@@ -392,7 +762,7 @@ impl<'a> Render<'a> {
             return;
         }
         let mut offset: u16;
-        let lcdc = cpu.mem.read8(0xFF40);
+        let lcdc = cpu.mem.peek8(0xFF40);
 
         // OBJ Disabled
         if (lcdc&0b0000_0010) == 0 {
@@ -408,19 +778,20 @@ impl<'a> Render<'a> {
             }
             // Sprite position
             offset = 0xFE00 + (i*4);
-            let py = (cpu.readMem8(offset) as isize)-16;
+            let py = (cpu.mem.peek8(offset) as isize)-16;
 
             // Sprite doesn't intersect the line
             if (py>line as isize) || ((py + (h-1)) < line as isize) {
                 continue 'oamloop;
             }
 
-            let flags = cpu.readMem8(offset+3);
+            let flags = cpu.mem.peek8(offset+3);
             let _xflip = flags&0b0010_0000 != 0;
             let _yflip = flags&0b0100_0000 != 0;
-            let mut tile_index = cpu.readMem8(offset+2);
+            let mut tile_index = cpu.mem.peek8(offset+2);
             let palette = cpu.mem.lcd.get_sprite_palette(((flags&0b0001_0000)>>4) as u16);
-            let px = (cpu.readMem8(offset+1) as isize)-8;
+            let layer = if flags&0b0001_0000 != 0 { Layer::OBJ1 } else { Layer::OBJ0 };
+            let px = (cpu.mem.peek8(offset+1) as isize)-8;
 
             // Flip Y
             let mut y = if _yflip {(h-1) as usize -(line-py as usize)} else {line-py as usize};
@@ -446,7 +817,7 @@ impl<'a> Render<'a> {
                     if (flags&0b1000_0000)==0
                         || ((flags&0b1000_0000)!=0 && self.get_bg_pixel_at(cpu, x+px as usize, line)!=0x00)
                             || ((flags&0b1000_0000)!=0 && self.get_win_pixel_at(cpu, x+px as usize, line)!=0x00) {
-                                self.put_pixel8(buffer, x+px as usize, line, palette[c as usize]);
+                                self.put_pixel_layer(buffer, x+px as usize, line, palette[c as usize], layer);
                             }
                 }
             }
@@ -454,12 +825,125 @@ impl<'a> Render<'a> {
         }
     }
 
-    pub fn gen_BG_map(&mut self, cpu: &mut Cpu<'a>, buffer: PixelBuffer) {
+    // CGB tile pixel, the tile data being read from the given VRAM bank
+    pub fn get_cgb_tile_pixel(&mut self, cpu: &mut Cpu<'a>, addr: u16, bank: u8, x: usize, y: usize) -> u8 {
+        let a = cpu.mem.peek_vram(bank, addr+(y as u16)*2);
+        let b = cpu.mem.peek_vram(bank, addr+(y as u16)*2+1);
+        let bit = 7-x;
+        ((a>>bit)&0x01) | (((b>>bit)&0x01)<<1)
+    }
+
+    // CGB color index and attributes of the pixel at x,y of a BG map
+    pub fn get_cgb_map_pixel(&mut self, cpu: &mut Cpu<'a>, map: u16, x: usize, y: usize) -> (u8, u8) {
+        let offset = map + (((y/8)%32)*32 + (x/8)%32) as u16;
+        // Tile ID in bank 0, attributes in bank 1
+        let id   = cpu.mem.peek_vram(0, offset);
+        let attr = cpu.mem.peek_vram(1, offset);
+        let addr = cpu.mem.lcd.get_tile_addr(id, false);
+        let tx = if attr&0b0010_0000 != 0 { 7-x%8 } else { x%8 };
+        let ty = if attr&0b0100_0000 != 0 { 7-y%8 } else { y%8 };
+        let c = self.get_cgb_tile_pixel(cpu, addr, (attr>>3)&0x01, tx, ty);
+        (c, attr)
+    }
+
+    // Whole CGB line: BG, window and sprites with the CGB priority rules
+    pub fn gen_CGB_line(&mut self, cpu: &mut Cpu<'a>, buffer: PixelBuffer, line: usize) {
+        if line>=144 {
+            return;
+        }
+        let lcdc = cpu.mem.peek8(0xFF40);
+        let SCY  = cpu.mem.lcd.get_scy() as usize;
+        let SCX  = cpu.mem.lcd.get_scx() as usize;
+        let WY   = cpu.mem.lcd.get_wy() as usize;
+        let WX   = cpu.mem.lcd.get_wx() as usize;
+        let bgmap  = if lcdc&0b0000_1000!=0 { 0x9C00 } else { 0x9800 };
+        let winmap = if lcdc&0b0100_0000!=0 { 0x9C00 } else { 0x9800 };
+
+        let mut bg_color = vec![0x00_u8; 160];
+        let mut bg_attr  = vec![0x00_u8; 160];
+        for x in 0..160 {
+            let (c, attr) = if lcdc&0b0010_0000 != 0 && line >= WY && x+7 >= WX {
+                self.get_cgb_map_pixel(cpu, winmap, x+7-WX, line-WY)
+            } else {
+                self.get_cgb_map_pixel(cpu, bgmap, (x+SCX)%256, (line+SCY)%256)
+            };
+            bg_color[x] = c;
+            bg_attr[x]  = attr;
+        }
+
+        let mut obj_color = vec![0x00_u8; 160];
+        let mut obj_attr  = vec![0x00_u8; 160];
+        if lcdc&0b0000_0010 != 0 {
+            let h = if (lcdc&0b0000_0100)!=0 { 16 } else { 8 };
+            let mut count = 0;
+            for i in 0..40 {
+                if count==10 {
+                    break;
+                }
+                let offset = 0xFE00 + (i*4);
+                let py = (cpu.mem.peek8(offset) as isize)-16;
+                if (line as isize) < py || (line as isize) >= py+h {
+                    continue;
+                }
+                count+=1;
+                let px = (cpu.mem.peek8(offset+1) as isize)-8;
+                let mut tile_index = cpu.mem.peek8(offset+2);
+                let flags = cpu.mem.peek8(offset+3);
+                if h==16 {
+                    tile_index = tile_index&0b1111_1110;
+                }
+                let mut y = (line as isize-py) as usize;
+                if flags&0b0100_0000 != 0 {
+                    y = (h-1) as usize-y;
+                }
+                let addr = cpu.mem.lcd.get_tile_addr(tile_index, true);
+                for x in 0..8 {
+                    let sx = px+x as isize;
+                    // Lower OAM entries have priority over the following ones
+                    if sx < 0 || sx >= 160 || obj_color[sx as usize] != 0 {
+                        continue;
+                    }
+                    let tx = if flags&0b0010_0000 != 0 { 7-x } else { x };
+                    let c = self.get_cgb_tile_pixel(cpu, addr, (flags>>3)&0x01, tx, y);
+                    if c != 0 {
+                        obj_color[sx as usize] = c;
+                        obj_attr[sx as usize]  = flags;
+                    }
+                }
+            }
+        }
+
+        for x in 0..160 {
+            // LCDC bit 0 is the BG master priority in CGB mode
+            let bg_over_obj = lcdc&0b0000_0001 != 0 && bg_color[x] != 0 &&
+                (bg_attr[x]&0b1000_0000 != 0 || obj_attr[x]&0b1000_0000 != 0);
+            let (r, g, b) = if obj_color[x] != 0 && !bg_over_obj {
+                cpu.mem.lcd.get_cgb_obj_color(obj_attr[x]&0b0000_0111, obj_color[x])
+            } else {
+                cpu.mem.lcd.get_cgb_bg_color(bg_attr[x]&0b0000_0111, bg_color[x])
+            };
+            self.put_pixel24(buffer, x, line, r, g, b);
+        }
+    }
+
+    // Whole 256x256 BG map at 0x9800 or 0x9C00
+    pub fn gen_BG_map(&mut self, cpu: &mut Cpu<'a>, buffer: PixelBuffer, map: u16) {
+        if cpu.mem.is_cgb() {
+            for y in 0..256 {
+                for x in 0..256 {
+                    let (c, attr) = self.get_cgb_map_pixel(cpu, map, x, y);
+                    let (r, g, b) = cpu.mem.lcd.get_cgb_bg_color(attr&0b0000_0111, c);
+                    self.put_pixel24(buffer, x, y, r, g, b);
+                }
+            }
+            return;
+        }
+
         let mut x = 0;
         let mut y = 0;
 
-        for offset in 0x9800..=0x9BFF {
-            let id = cpu.readMem8(offset);
+        for offset in map..map+0x400 {
+            let id = cpu.mem.peek8(offset);
             let tile = self.get_tile_by_id(cpu, id, false);
             self.display_tile(cpu, buffer, x, y, tile);
             x+=8;
@@ -471,19 +955,41 @@ impl<'a> Render<'a> {
     }
 
     pub fn display_BG_map(&mut self, cpu: &mut Cpu<'a> ) {
-        self.gen_BG_map(cpu, PixelBuffer::BG);
+        if self.bg_window.is_none() {
+            return;
+        }
+        let lcdc = cpu.mem.peek8(0xFF40);
+        let map = self.bg_map.unwrap_or(if lcdc&0b0000_1000 != 0 { 0x9C00 } else { 0x9800 });
+        self.gen_BG_map(cpu, PixelBuffer::BG, map);
         self.display_scroll_window(cpu, PixelBuffer::BG);
-        self.bg_window.update_with_buffer(&mut self.buffer_bg, self.width, self.height)
-            .unwrap();
+        self.display_window_overlay(cpu, PixelBuffer::BG);
+        let width = self.width;
+        let label = format!("{:04X}{}", map, if self.bg_map.is_none() { " LCDC" } else { "" });
+        font::draw_text(&mut self.buffer_bg, width, 1, 1, &label, 0xFF00FF);
+        if let Some(ref mut w) = self.bg_window {
+            w.update_with_buffer(&mut self.buffer_bg, self.width, self.height).unwrap();
+        }
     }
 
     pub fn update_screen(&mut self, cpu: &mut Cpu<'a> ) {
         let y = cpu.mem.lcd.get_cur_y() as usize;
-        let lcdc = cpu.mem.read8(0xFF40);
+        if self.sgb {
+            self.sgb_line     = Some(cpu.mem.sgb.get_line_colors(y));
+            self.sgb_mask     = cpu.mem.sgb.get_mask();
+            self.sgb_backdrop = cpu.mem.sgb.get_backdrop();
+            if cpu.mem.sgb.border_changed() {
+                self.sgb_border = cpu.mem.sgb.render_border();
+            }
+        }
+        let lcdc = cpu.mem.peek8(0xFF40);
         if lcdc&0b1000_0000 != 0 {
-            self.gen_BG_map_line(  cpu, PixelBuffer::Render, y);
-            self.gen_WIN_map_line( cpu, PixelBuffer::Render, y);
-            self.gen_OBJ_map_line( cpu, PixelBuffer::Render, y);
+            if cpu.mem.is_cgb() {
+                self.gen_CGB_line(cpu, PixelBuffer::Render, y);
+            } else {
+                self.gen_BG_map_line(  cpu, PixelBuffer::Render, y);
+                self.gen_WIN_map_line( cpu, PixelBuffer::Render, y);
+                self.gen_OBJ_map_line( cpu, PixelBuffer::Render, y);
+            }
         } else {
             for y in 0..144 {
                 for x in 0..160 {
@@ -493,35 +999,137 @@ impl<'a> Render<'a> {
         }
     }
 
-    pub fn render_screen(&mut self) {
+    pub fn get_frame_size(&self) -> (usize, usize) {
+        if self.sgb { (sgb::WIDTH, sgb::HEIGHT) } else { (160, 144) }
+    }
+
+    // Displayed frame, shared by the window, the screenshots and the recordings
+    pub fn get_frame(&mut self) -> Vec<u32> {
         let mut buf = vec![0x00; 160*144];
         for y in 0..144 {
             for x in 0..160 {
                 buf[x+y*160] = self.buffer_render[x+y*256];
             }
         }
-        self.render_window.update_with_buffer(&mut buf, 160, 144).unwrap();
+        for (p, &o) in buf.iter_mut().zip(self.overlay.iter()) {
+            if o != 0 {
+                *p = o & 0xFFFFFF;
+            }
+        }
+        if !self.sgb {
+            return buf;
+        }
 
-        if self.recording {
+        // SGB screen mask
+        match self.sgb_mask {
+            1 if !self.last_frame.is_empty() => return self.last_frame.clone(),
+            2 => buf = vec![0x000000; 160*144],
+            3 => buf = vec![self.sgb_backdrop; 160*144],
+            _ => {},
+        }
+        // Screen in the middle of the border, which is drawn over it
+        let mut frame = vec![self.sgb_backdrop; sgb::WIDTH*sgb::HEIGHT];
+        for y in 0..144 {
+            for x in 0..160 {
+                frame[sgb::SCREEN_X+x + (sgb::SCREEN_Y+y)*sgb::WIDTH] = buf[x+y*160];
+            }
+        }
+        for (i, c) in self.sgb_border.iter().enumerate() {
+            if let Some(c) = *c {
+                frame[i] = c;
+            }
+        }
+        self.last_frame = frame.clone();
+        frame
+    }
 
-            let mut rgba8 = vec![0 as u8; 160*144*4];
-            let mut offset = 0;
-            for y in 0..144 {
-                for x in 0..160 {
-                    let pixel = self.buffer_render[x+y*256];
-                    rgba8[offset]   = ((pixel&0x00FF0000)>>16) as u8;
-                    rgba8[offset+1] = ((pixel&0x0000FF00)>>8) as u8;
-                    rgba8[offset+2] = ((pixel&0x000000FF)) as u8;
-                    rgba8[offset+3] = 0xFF as u8;
-                    offset+=4;
+    // Save the displayed frame as a RGB PNG
+    pub fn save_png(&mut self, path: &str) -> image::ImageResult<()> {
+        let (w, h) = self.get_frame_size();
+        let mut buffer = vec![0x00_u8; w*h*3];
+        let mut offset = 0;
+        for b in self.get_frame() {
+            buffer[offset]   = ((b&0x00FF0000)>>16) as u8;
+            buffer[offset+1] = ((b&0x0000FF00)>>8)  as u8;
+            buffer[offset+2] = (b&0x000000FF)       as u8;
+            offset+=3;
+        }
+        image::save_buffer(path, buffer.as_slice(), w as u32, h as u32, image::ColorType::Rgb8)
+    }
 
+    // FNV-1a of the 160x144 screen, without the SGB border or script overlays,
+    // and the same whatever DMG palette is selected
+    pub fn frame_hash(&self) -> u32 {
+        let mut hash: u32 = 0x811C9DC5;
+        for y in 0..144 {
+            for &p in self.buffer_shades[y*256..y*256+160].iter() {
+                for &b in p.to_le_bytes().iter() {
+                    hash = (hash ^ b as u32).wrapping_mul(0x01000193);
                 }
             }
+        }
+        hash
+    }
+
+    pub fn set_overlay(&mut self, overlay: &[u32]) {
+        self.overlay = overlay.to_vec();
+    }
+
+    pub fn get_frame_count(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn render_screen(&mut self) {
+        self.frames += 1;
+        let mut buf = self.get_frame();
+        let (w, h) = self.get_frame_size();
+        if let Some(ref mut window) = self.render_window {
+            window.update_with_buffer(&mut buf, w, h).unwrap();
+        }
+
+        if self.recording && self.record_size != (w, h) {
+            // The encoder only takes frames of its own size
+            println!("Frame size changed, recording stopped");
+            self.stop_recording();
+        }
+        if self.recording {
+            let mut rgba8 = vec![0 as u8; w*h*4];
+            let mut offset = 0;
+            for pixel in buf {
+                rgba8[offset]   = ((pixel&0x00FF0000)>>16) as u8;
+                rgba8[offset+1] = ((pixel&0x0000FF00)>>8) as u8;
+                rgba8[offset+2] = ((pixel&0x000000FF)) as u8;
+                rgba8[offset+3] = 0xFF as u8;
+                offset+=4;
+            }
             self.webp_encoder.add_frame(rgba8.as_slice(), self.webp_timestamp).unwrap();
             self.webp_timestamp += 16;
         }
     }
 
+    // Screen area covered by the window, in green over the BG map
+    pub fn display_window_overlay(&mut self, cpu: &mut Cpu<'a>, buf: PixelBuffer) {
+        let lcdc = cpu.mem.peek8(0xFF40);
+        if lcdc&0b0010_0000 == 0 {
+            return;
+        }
+        let wx = (cpu.mem.lcd.get_wx() as usize).saturating_sub(7);
+        let wy = cpu.mem.lcd.get_wy() as usize;
+        if wx >= 160 || wy >= 144 {
+            return;
+        }
+        let scx = cpu.mem.lcd.get_scx() as usize;
+        let scy = cpu.mem.lcd.get_scy() as usize;
+        for x in wx..160 {
+            self.put_pixel24(buf, (scx+x)&0xFF, (scy+wy)&0xFF, 0, 255, 0);
+            self.put_pixel24(buf, (scx+x)&0xFF, (scy+143)&0xFF, 0, 255, 0);
+        }
+        for y in wy..144 {
+            self.put_pixel24(buf, (scx+wx)&0xFF, (scy+y)&0xFF, 0, 255, 0);
+            self.put_pixel24(buf, (scx+159)&0xFF, (scy+y)&0xFF, 0, 255, 0);
+        }
+    }
+
     pub fn display_scroll_window(&mut self, cpu: &mut Cpu<'a>, buf: PixelBuffer) {
         let SCY  = cpu.mem.lcd.get_scy() as usize;
         let SCX  = cpu.mem.lcd.get_scx() as usize;
@@ -51,6 +51,14 @@ impl<'a> ROM<'a> {
         let cgb = self.buffer[0x143];
         cgb
     }
+    // 0x80: CGB enhanced, 0xC0: CGB only
+    pub fn is_cgb(&self) -> bool {
+        self.get_cgb_flag()&0x80 != 0
+    }
+    // SGB functions are only enabled with the SGB flag and the new licensee code
+    pub fn is_sgb(&self) -> bool {
+        self.buffer[0x146] == 0x03 && self.buffer[0x14B] == 0x33
+    }
     pub fn get_cartridge_type(&mut self) -> u8 {
         let t = self.buffer[0x147];
         match t {
@@ -101,13 +109,40 @@ impl<'a> ROM<'a> {
         let t = 32<<self.buffer[0x148];
         t
     }
+    // Cartridge RAM size from the header code
     pub fn get_ram_size_kb(&self) -> u32 {
-        let t = 32<<self.buffer[0x149];
-        t
+        match self.buffer[0x149] {
+            0x01 => 2,
+            0x02 => 8,
+            0x03 => 32,
+            0x04 => 128,
+            0x05 => 64,
+            _    => 0,
+        }
     }
     pub fn get_name(&self) -> String {
         String::from_utf8(self.buffer[0x0134..0x0143].to_vec()).unwrap()
     }
+    // Sum of the 16 title bytes, used by the CGB boot ROM to pick a palette
+    pub fn get_title_checksum(&self) -> u8 {
+        let mut sum: u8 = 0;
+        for i in self.buffer[0x134..=0x143].iter() {
+            sum = sum.wrapping_add(*i);
+        }
+        sum
+    }
+    // Big endian sum of the whole ROM, stored at 0x14E
+    pub fn get_global_checksum(&self) -> u16 {
+        ((self.buffer[0x14E] as u16)<<8) | (self.buffer[0x14F] as u16)
+    }
+    pub fn is_nintendo_licensee(&self) -> bool {
+        match self.buffer[0x14B] {
+            0x01 => true,
+            // New licensee code
+            0x33 => self.buffer[0x144] == b'0' && self.buffer[0x145] == b'1',
+            _ => false,
+        }
+    }
     pub fn get_destination_code(&self) -> String {
         let mut ret: String = String::from("Unknown");
 
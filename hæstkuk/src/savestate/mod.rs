@@ -0,0 +1,137 @@
+// Save states
+//
+// Each component writes its fields in a fixed order with a Writer, and reads
+// them back in the same order with a Reader. No attempt is made to load states
+// from other versions.
+#![allow(dead_code)]
+use std::fs;
+use lr35902::Cpu;
+
+const MAGIC: &[u8; 4] = b"HKST";
+const VERSION: u8 = 1;
+
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Writer {
+        Writer {
+            buf: vec![],
+        }
+    }
+    pub fn u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+    pub fn bool(&mut self, v: bool) {
+        self.buf.push(v as u8);
+    }
+    pub fn u16(&mut self, v: u16) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+    pub fn u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+    pub fn u64(&mut self, v: u64) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+    // Length prefixed
+    pub fn bytes(&mut self, v: &[u8]) {
+        self.u32(v.len() as u32);
+        self.buf.extend_from_slice(v);
+    }
+    pub fn into_vec(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+pub struct Reader<'b> {
+    buf: &'b [u8],
+    pos: usize,
+}
+
+impl<'b> Reader<'b> {
+    pub fn new(buf: &'b [u8]) -> Reader<'b> {
+        Reader {
+            buf: buf,
+            pos: 0,
+        }
+    }
+    pub fn take(&mut self, n: usize) -> Result<&'b [u8], String> {
+        if self.pos + n > self.buf.len() {
+            return Err(String::from("truncated save state"));
+        }
+        let s = &self.buf[self.pos..self.pos+n];
+        self.pos += n;
+        Ok(s)
+    }
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+    pub fn bool(&mut self) -> Result<bool, String> {
+        Ok(self.u8()? != 0)
+    }
+    pub fn u16(&mut self) -> Result<u16, String> {
+        let b = self.take(2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+    pub fn u32(&mut self) -> Result<u32, String> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+    pub fn u64(&mut self) -> Result<u64, String> {
+        let b = self.take(8)?;
+        let mut v = [0_u8; 8];
+        v.copy_from_slice(b);
+        Ok(u64::from_le_bytes(v))
+    }
+    pub fn bytes(&mut self) -> Result<Vec<u8>, String> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+    // Length prefixed bytes into an existing buffer, which keeps its size
+    pub fn bytes_into(&mut self, dst: &mut [u8]) -> Result<(), String> {
+        let v = self.bytes()?;
+        if v.len() != dst.len() {
+            return Err(format!("expected {} bytes, got {}", dst.len(), v.len()));
+        }
+        dst.copy_from_slice(&v);
+        Ok(())
+    }
+}
+
+// Whole machine state, the cartridge being identified by its global checksum
+pub fn save_to_vec(cpu: &Cpu) -> Vec<u8> {
+    let mut w = Writer::new();
+    for &b in MAGIC.iter() {
+        w.u8(b);
+    }
+    w.u8(VERSION);
+    w.u16(cpu.mem.get_rom().get_global_checksum());
+    cpu.save_state(&mut w);
+    w.into_vec()
+}
+
+pub fn load_from_slice(cpu: &mut Cpu, data: &[u8]) -> Result<(), String> {
+    let mut r = Reader::new(data);
+    if r.take(4)? != &MAGIC[..] {
+        return Err(String::from("not a save state"));
+    }
+    let version = r.u8()?;
+    if version != VERSION {
+        return Err(format!("unsupported save state version {}", version));
+    }
+    if r.u16()? != cpu.mem.get_rom().get_global_checksum() {
+        return Err(String::from("save state is for another cartridge"));
+    }
+    cpu.load_state(&mut r)
+}
+
+pub fn save(cpu: &Cpu, path: &str) -> Result<(), String> {
+    fs::write(path, save_to_vec(cpu)).map_err(|e| format!("can't write {}: {}", path, e))
+}
+
+pub fn load(cpu: &mut Cpu, path: &str) -> Result<(), String> {
+    let data = fs::read(path).map_err(|e| format!("can't read {}: {}", path, e))?;
+    load_from_slice(cpu, &data)
+}
@@ -0,0 +1,271 @@
+// Lua scripts, to drive the emulator for automated playthroughs:
+//
+// on_frame(function() ... end)              at the end of each frame
+// on_pc(0x0150, function() ... end)         before running the instruction at an address
+// on_write(0xC0A0, function(addr, v) ... end)  after the CPU writes to an address
+// read8(addr) read16(addr) write8(addr, v) write16(addr, v)
+// reg("hl") set_reg("a", 3)
+// press("start", frames)                    holds a button, for 1 frame by default
+// draw_pixel(x, y, color) draw_rect(x, y, w, h, color) draw_text(x, y, text, color)
+//                                           overlay on the screen, redrawn each frame
+// screenshot("out.png") frame() quit([passed])
+#![allow(dead_code)]
+extern crate mlua;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::rc::Rc;
+use self::mlua::{Function, Lua, RegistryKey};
+use lr35902::Cpu;
+use render::Render;
+use font;
+
+const BUTTONS: [&str; 8] = ["a", "b", "select", "start", "right", "left", "up", "down"];
+
+// Shared with the functions the script calls
+#[derive(Default)]
+struct State {
+    frame_hooks: Vec<RegistryKey>,
+    pc_hooks: HashMap<u16, Vec<RegistryKey>>,
+    write_hooks: HashMap<u16, Vec<RegistryKey>>,
+    write_hooks_changed: bool,
+    // Frames left for each button held
+    held: [u32; 8],
+    // 160x144, 0xFFRRGGBB pixels over the screen, 0 for none
+    overlay: Vec<u32>,
+    screenshots: Vec<String>,
+    frames: u64,
+    quit: Option<bool>,
+}
+
+pub struct Script {
+    lua: Lua,
+    state: Rc<RefCell<State>>,
+    // Stopped by an error
+    failed: bool,
+}
+
+fn error(text: String) -> mlua::Error {
+    mlua::Error::RuntimeError(text)
+}
+
+impl Script {
+    // Run the script once, it usually registers its hooks
+    pub fn load<'a>(path: &str, cpu: &mut Cpu<'a>) -> Result<Script, String> {
+        let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let script = Script {
+            lua: Lua::new(),
+            state: Rc::new(RefCell::new(State { overlay: vec![0; 160*144], ..Default::default() })),
+            failed: false,
+        };
+        script.register().map_err(|e| format!("{}: {}", path, e))?;
+        let ok = {
+            let lua = &script.lua;
+            Script::call(lua, &script.state, cpu, || lua.load(&source).set_name(path).exec())
+        };
+        if !ok {
+            return Err(format!("{} failed", path));
+        }
+        Ok(script)
+    }
+
+    // Functions that don't need the CPU
+    fn register(&self) -> mlua::Result<()> {
+        let g = self.lua.globals();
+
+        let state = self.state.clone();
+        g.set("on_frame", self.lua.create_function(move |lua, f: Function| {
+            state.borrow_mut().frame_hooks.push(lua.create_registry_value(f)?);
+            Ok(())
+        })?)?;
+        let state = self.state.clone();
+        g.set("on_pc", self.lua.create_function(move |lua, (addr, f): (u16, Function)| {
+            let key = lua.create_registry_value(f)?;
+            state.borrow_mut().pc_hooks.entry(addr).or_insert_with(Vec::new).push(key);
+            Ok(())
+        })?)?;
+        let state = self.state.clone();
+        g.set("on_write", self.lua.create_function(move |lua, (addr, f): (u16, Function)| {
+            let key = lua.create_registry_value(f)?;
+            let mut s = state.borrow_mut();
+            s.write_hooks.entry(addr).or_insert_with(Vec::new).push(key);
+            s.write_hooks_changed = true;
+            Ok(())
+        })?)?;
+
+        let state = self.state.clone();
+        g.set("press", self.lua.create_function(move |_, (button, frames): (String, Option<u32>)| {
+            let i = BUTTONS.iter().position(|&b| b == button.to_lowercase())
+                .ok_or_else(|| error(format!("unknown button '{}'", button)))?;
+            state.borrow_mut().held[i] = frames.unwrap_or(1);
+            Ok(())
+        })?)?;
+
+        let state = self.state.clone();
+        g.set("draw_pixel", self.lua.create_function(move |_, (x, y, color): (usize, usize, u32)| {
+            if x < 160 && y < 144 {
+                state.borrow_mut().overlay[x + y*160] = 0xFF000000 | color;
+            }
+            Ok(())
+        })?)?;
+        let state = self.state.clone();
+        g.set("draw_rect", self.lua.create_function(move |_, (x, y, w, h, color): (usize, usize, usize, usize, u32)| {
+            let mut s = state.borrow_mut();
+            for py in y..(y + h).min(144) {
+                for px in x..(x + w).min(160) {
+                    s.overlay[px + py*160] = 0xFF000000 | color;
+                }
+            }
+            Ok(())
+        })?)?;
+        let state = self.state.clone();
+        g.set("draw_text", self.lua.create_function(move |_, (x, y, text, color): (usize, usize, String, u32)| {
+            font::draw_text(&mut state.borrow_mut().overlay, 160, x, y, &text, 0xFF000000 | color);
+            Ok(())
+        })?)?;
+
+        let state = self.state.clone();
+        g.set("screenshot", self.lua.create_function(move |_, path: String| {
+            state.borrow_mut().screenshots.push(path);
+            Ok(())
+        })?)?;
+        let state = self.state.clone();
+        g.set("frame", self.lua.create_function(move |_, ()| Ok(state.borrow().frames))?)?;
+        let state = self.state.clone();
+        g.set("quit", self.lua.create_function(move |_, passed: Option<bool>| {
+            state.borrow_mut().quit = Some(passed.unwrap_or(true));
+            Ok(())
+        })?)?;
+        Ok(())
+    }
+
+    // Run f with the CPU functions available, returns false on errors
+    fn call<'a, F>(lua: &Lua, state: &Rc<RefCell<State>>, cpu: &mut Cpu<'a>, f: F) -> bool
+        where F: FnOnce() -> mlua::Result<()> {
+        let result = {
+            let cpu = RefCell::new(&mut *cpu);
+            let cpu = &cpu;
+            lua.scope(|scope| {
+                let g = lua.globals();
+                g.set("read8", scope.create_function(move |_, addr: u16| {
+                    Ok(cpu.borrow_mut().mem.peek8(addr))
+                })?)?;
+                g.set("read16", scope.create_function(move |_, addr: u16| {
+                    let mut cpu = cpu.borrow_mut();
+                    Ok(cpu.mem.peek8(addr) as u16 | (cpu.mem.peek8(addr.wrapping_add(1)) as u16) << 8)
+                })?)?;
+                g.set("write8", scope.create_function(move |_, (addr, v): (u16, u8)| {
+                    cpu.borrow_mut().mem.poke_bank8(None, addr, v);
+                    Ok(())
+                })?)?;
+                g.set("write16", scope.create_function(move |_, (addr, v): (u16, u16)| {
+                    let mut cpu = cpu.borrow_mut();
+                    cpu.mem.poke_bank8(None, addr, v as u8);
+                    cpu.mem.poke_bank8(None, addr.wrapping_add(1), (v >> 8) as u8);
+                    Ok(())
+                })?)?;
+                g.set("reg", scope.create_function(move |_, name: String| {
+                    cpu.borrow().get_reg(&name).ok_or_else(|| error(format!("unknown register '{}'", name)))
+                })?)?;
+                g.set("set_reg", scope.create_function(move |_, (name, v): (String, u16)| {
+                    if cpu.borrow_mut().set_reg(&name, v) { Ok(()) } else { Err(error(format!("unknown register '{}'", name))) }
+                })?)?;
+                f()
+            })
+        };
+        // New on_write hooks, and buttons pressed
+        let mut s = state.borrow_mut();
+        if s.write_hooks_changed {
+            s.write_hooks_changed = false;
+            cpu.mem.set_write_hooks(s.write_hooks.keys().cloned().collect());
+        }
+        // A stopped script doesn't count its buttons down anymore, so they're released
+        let held = s.held.iter().enumerate().fold(0, |mask, (i, &n)| if n > 0 { mask | 1 << i } else { mask });
+        cpu.mem.joypad.set_held(if result.is_ok() { held } else { 0 });
+        match result {
+            Ok(_) => true,
+            Err(e) => {
+                println!("Script error: {}\nScript stopped", e);
+                false
+            },
+        }
+    }
+
+    fn run_hooks<'a, A: Clone>(&self, cpu: &mut Cpu<'a>, keys: Vec<Function>, args: A) -> bool
+        where A: for<'l> mlua::IntoLuaMulti<'l> {
+        Script::call(&self.lua, &self.state, cpu, || {
+            for f in keys {
+                f.call::<_, ()>(args.clone())?;
+            }
+            Ok(())
+        })
+    }
+
+    fn functions(&self, keys: &[RegistryKey]) -> Vec<Function<'_>> {
+        keys.iter().filter_map(|k| self.lua.registry_value(k).ok()).collect()
+    }
+
+    // on_pc hooks for the instruction about to run
+    pub fn before_step<'a>(&mut self, cpu: &mut Cpu<'a>) {
+        if self.failed {
+            return;
+        }
+        let hooks = match self.state.borrow().pc_hooks.get(&cpu.get_pc()) {
+            Some(keys) => self.functions(keys),
+            None => return,
+        };
+        self.failed = !self.run_hooks(cpu, hooks, ());
+    }
+
+    // on_write hooks for the writes of the last instruction
+    pub fn after_step<'a>(&mut self, cpu: &mut Cpu<'a>) {
+        if self.failed {
+            return;
+        }
+        for (addr, v) in cpu.mem.take_write_hits() {
+            let hooks = match self.state.borrow().write_hooks.get(&addr) {
+                Some(keys) => self.functions(keys),
+                None => continue,
+            };
+            if !self.run_hooks(cpu, hooks, (addr, v)) {
+                self.failed = true;
+                return;
+            }
+        }
+    }
+
+    // Before the frame is displayed: on_frame hooks, then their overlay
+    pub fn frame_end<'a>(&mut self, cpu: &mut Cpu<'a>, render: &mut Render<'a>) {
+        if self.failed {
+            return;
+        }
+        let hooks = {
+            let mut s = self.state.borrow_mut();
+            s.frames = render.get_frame_count() + 1;
+            for n in s.held.iter_mut() {
+                *n = n.saturating_sub(1);
+            }
+            for p in s.overlay.iter_mut() {
+                *p = 0;
+            }
+            self.functions(&s.frame_hooks)
+        };
+        self.failed = !self.run_hooks(cpu, hooks, ());
+        render.set_overlay(&self.state.borrow().overlay);
+    }
+
+    // Once the frame is displayed
+    pub fn take_screenshots<'a>(&mut self, render: &mut Render<'a>) {
+        let paths: Vec<String> = self.state.borrow_mut().screenshots.drain(..).collect();
+        for path in paths {
+            if let Err(e) = render.save_png(&path) {
+                println!("Can't save {}: {}", path, e);
+            }
+        }
+    }
+
+    // Some(passed) once the script called quit()
+    pub fn get_quit(&self) -> Option<bool> {
+        self.state.borrow().quit
+    }
+}
@@ -0,0 +1,142 @@
+// RAM search: snapshot the work RAM, HRAM and cartridge RAM, then narrow the
+// candidates down frame after frame by comparing them to their last values,
+// to find where a game keeps its lives, timer or position.
+#![allow(dead_code)]
+use mem::Mem;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Filter {
+    // Same as at the last search
+    Equal,
+    Changed,
+    Increased,
+    Decreased,
+    Value(i32),
+}
+
+impl Filter {
+    // "eq", "ne", "inc", "dec" (or =, !=, >, <) or a hex value, negative for the signed view
+    pub fn parse(s: &str) -> Result<Filter, String> {
+        match s {
+            "eq" | "="   => Ok(Filter::Equal),
+            "ne" | "!="  => Ok(Filter::Changed),
+            "inc" | ">"  => Ok(Filter::Increased),
+            "dec" | "<"  => Ok(Filter::Decreased),
+            _ => {
+                let (negative, hex) = if s.starts_with('-') { (true, &s[1..]) } else { (false, s) };
+                let v = i32::from_str_radix(hex.trim_start_matches('$').trim_start_matches("0x"), 16)
+                    .map_err(|_| format!("invalid search '{}'", s))?;
+                Ok(Filter::Value(if negative { -v } else { v }))
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Candidate {
+    pub bank: Option<u16>,
+    pub addr: u16,
+    // Value at the last search
+    pub value: i32,
+}
+
+pub struct RamSearch {
+    // 16-bit little endian values instead of bytes
+    word: bool,
+    signed: bool,
+    candidates: Vec<Candidate>,
+}
+
+// Searched areas: start, end and the banks to go through, None for the mapped one
+fn areas(mem: &Mem) -> Vec<(u16, u16, Option<u16>)> {
+    let mut areas = vec![(0xC000, 0xCFFF, None)];
+    if mem.is_cgb() {
+        areas.extend((1..8).map(|b| (0xD000, 0xDFFF, Some(b))));
+    } else {
+        areas.push((0xD000, 0xDFFF, None));
+    }
+    let ram_kb = mem.get_rom().get_ram_size_kb() as u16;
+    if ram_kb > 0 {
+        // Read by bank, straight from the RAM whether the MBC enabled it or not
+        let end = 0xA000 + (ram_kb.min(8) * 1024 - 1);
+        let banks = (ram_kb / 8).max(1).min(mem.get_bank_count(0xA000));
+        areas.extend((0..banks).map(|b| (0xA000, end, Some(b))));
+    }
+    areas.push((0xFF80, 0xFFFE, None));
+    areas
+}
+
+impl RamSearch {
+    // Every address is a candidate, with its current value
+    pub fn new(mem: &mut Mem, word: bool, signed: bool) -> RamSearch {
+        let mut search = RamSearch { word: word, signed: signed, candidates: vec![] };
+        for (start, end, bank) in areas(mem) {
+            let end = if word { end - 1 } else { end };
+            for addr in start..=end {
+                let value = search.read(mem, bank, addr);
+                search.candidates.push(Candidate { bank: bank, addr: addr, value: value });
+            }
+        }
+        search
+    }
+
+    fn read(&self, mem: &mut Mem, bank: Option<u16>, addr: u16) -> i32 {
+        let lo = mem.peek_bank8(bank, addr);
+        match (self.word, self.signed) {
+            (false, false) => lo as i32,
+            (false, true)  => lo as i8 as i32,
+            (true, signed) => {
+                let v = (mem.peek_bank8(bank, addr + 1) as u16) << 8 | lo as u16;
+                if signed { v as i16 as i32 } else { v as i32 }
+            },
+        }
+    }
+
+    // Keep the candidates matching, and remember their current value.
+    // Returns how many are left.
+    pub fn filter(&mut self, mem: &mut Mem, filter: Filter) -> usize {
+        let mut candidates = vec![];
+        for c in self.candidates.iter() {
+            let v = self.read(mem, c.bank, c.addr);
+            let keep = match filter {
+                Filter::Equal     => v == c.value,
+                Filter::Changed   => v != c.value,
+                Filter::Increased => v > c.value,
+                Filter::Decreased => v < c.value,
+                Filter::Value(n)  => v == n,
+            };
+            if keep {
+                candidates.push(Candidate { value: v, ..*c });
+            }
+        }
+        self.candidates = candidates;
+        self.candidates.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.candidates.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.candidates.is_empty()
+    }
+    pub fn candidates(&self) -> &[Candidate] {
+        &self.candidates
+    }
+
+    // The first max candidates, with their value now and at the last search
+    pub fn format(&self, mem: &mut Mem, max: usize) -> String {
+        let mut out = format!("{} candidates, {}-bit {}\n", self.candidates.len(),
+                              if self.word { 16 } else { 8 }, if self.signed { "signed" } else { "unsigned" });
+        let width = if self.word { 4 } else { 2 };
+        for c in self.candidates.iter().take(max) {
+            let v = self.read(mem, c.bank, c.addr);
+            let bank = c.bank.unwrap_or(mem.get_bank(c.addr));
+            let hex = |v: i32| format!("{:01$X}", v as u32 & if self.word { 0xFFFF } else { 0xFF }, width);
+            out += &format!("{:02X}:{:04X}  {} ({:6})  was {} ({:6})\n", bank, c.addr, hex(v), v, hex(c.value), c.value);
+        }
+        if self.candidates.len() > max {
+            out += "...\n";
+        }
+        out
+    }
+}
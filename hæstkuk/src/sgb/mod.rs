@@ -0,0 +1,513 @@
+// Super Game Boy
+//
+// Command packets are sent bit by bit through the joypad register:
+// P14 and P15 low is a reset pulse, then P14 low sends a 0 and P15 low a 1,
+// each bit being followed by both lines high. A packet is 16 bytes, LSB first.
+#![allow(dead_code)]
+use std::marker::PhantomData;
+use savestate;
+
+// Screen position inside the 256x224 SGB output
+pub const WIDTH: usize    = 256;
+pub const HEIGHT: usize   = 224;
+pub const SCREEN_X: usize = 48;
+pub const SCREEN_Y: usize = 40;
+
+const CELLS_W: usize = 20;
+const CELLS_H: usize = 18;
+const ATTR_FILE_SIZE: usize = 90;
+
+// Commands needing 4KB of data shown on the screen, see Mem::sgb_vram_data
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transfer {
+    Palettes,
+    Tiles(u8),
+    Border,
+    Attributes,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Sgb<'a> {
+    phantom: PhantomData<&'a u8>,
+    enabled: bool,
+    // Packet reception
+    receiving: bool,
+    bit: usize,
+    packet: Vec<u8>,
+    command: Vec<u8>,
+    last_lines: u8,
+    // Palettes 0-3 for the screen, RGB555
+    palettes: [[u16; 4]; 4],
+    system_palettes: Vec<u16>,
+    // Palette of each 8x8 cell of the screen
+    attr_map: Vec<u8>,
+    attr_files: Vec<u8>,
+    // Border: 256 4bpp tiles, 32x32 map and palettes 4-7
+    border_tiles: Vec<u8>,
+    border_map: Vec<u8>,
+    border_palettes: Vec<u16>,
+    border_changed: bool,
+    // Multiplayer
+    players: u8,
+    player: u8,
+    mask: u8,
+    transfer: Option<Transfer>,
+}
+
+fn rgb555_to_rgb888(c: u16) -> u32 {
+    let convert = |v: u16| -> u32 { (((v&0x1F) << 3) | ((v&0x1F) >> 2)) as u32 };
+    (convert(c) << 16) | (convert(c >> 5) << 8) | convert(c >> 10)
+}
+
+impl<'a> Sgb<'a> {
+    pub fn new() -> Sgb<'a> {
+        // Default SGB palette
+        let default = [0x67BF, 0x265B, 0x10B5, 0x2866];
+        Sgb {
+            phantom: PhantomData,
+            enabled: false,
+            receiving: false,
+            bit: 0,
+            packet: vec![0x00; 16],
+            command: vec![],
+            last_lines: 0x30,
+            palettes: [default; 4],
+            system_palettes: vec![0x0000; 512*4],
+            attr_map: vec![0x00; CELLS_W*CELLS_H],
+            attr_files: vec![0x00; 45*ATTR_FILE_SIZE],
+            border_tiles: vec![0x00; 256*32],
+            border_map: vec![0x00; 32*32*2],
+            border_palettes: vec![0x0000; 4*16],
+            border_changed: true,
+            players: 1,
+            player: 0,
+            mask: 0,
+            transfer: None,
+        }
+    }
+
+    pub fn save_state(&self, w: &mut savestate::Writer) {
+        w.bool(self.enabled);
+        w.bool(self.receiving);
+        w.u64(self.bit as u64);
+        w.bytes(&self.packet);
+        w.bytes(&self.command);
+        w.u8(self.last_lines);
+        for p in self.palettes.iter() {
+            for &c in p.iter() {
+                w.u16(c);
+            }
+        }
+        for &c in self.system_palettes.iter() {
+            w.u16(c);
+        }
+        w.bytes(&self.attr_map);
+        w.bytes(&self.attr_files);
+        w.bytes(&self.border_tiles);
+        w.bytes(&self.border_map);
+        for &c in self.border_palettes.iter() {
+            w.u16(c);
+        }
+        w.u8(self.players);
+        w.u8(self.player);
+        w.u8(self.mask);
+        match self.transfer {
+            None                         => w.u8(0),
+            Some(Transfer::Palettes)     => w.u8(1),
+            Some(Transfer::Tiles(n))     => { w.u8(2); w.u8(n) },
+            Some(Transfer::Border)       => w.u8(3),
+            Some(Transfer::Attributes)   => w.u8(4),
+        }
+    }
+    pub fn load_state(&mut self, r: &mut savestate::Reader) -> Result<(), String> {
+        self.enabled    = r.bool()?;
+        self.receiving  = r.bool()?;
+        self.bit        = r.u64()? as usize;
+        r.bytes_into(&mut self.packet)?;
+        self.command    = r.bytes()?;
+        self.last_lines = r.u8()?;
+        for p in self.palettes.iter_mut() {
+            for c in p.iter_mut() {
+                *c = r.u16()?;
+            }
+        }
+        for c in self.system_palettes.iter_mut() {
+            *c = r.u16()?;
+        }
+        r.bytes_into(&mut self.attr_map)?;
+        r.bytes_into(&mut self.attr_files)?;
+        r.bytes_into(&mut self.border_tiles)?;
+        r.bytes_into(&mut self.border_map)?;
+        for c in self.border_palettes.iter_mut() {
+            *c = r.u16()?;
+        }
+        self.players  = r.u8()?;
+        self.player   = r.u8()?;
+        self.mask     = r.u8()?;
+        self.transfer = match r.u8()? {
+            0 => None,
+            1 => Some(Transfer::Palettes),
+            2 => Some(Transfer::Tiles(r.u8()?)),
+            3 => Some(Transfer::Border),
+            4 => Some(Transfer::Attributes),
+            t => return Err(format!("invalid SGB transfer {}", t)),
+        };
+        self.border_changed = true;
+        Ok(())
+    }
+
+    pub fn enable(&mut self) {
+        self.enabled = true;
+    }
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    // Joypad register write, bits 4 (P14) and 5 (P15)
+    pub fn write_joypad(&mut self, v: u8) {
+        let lines = v & 0x30;
+        match lines {
+            0x00 => {
+                self.receiving = true;
+                self.bit = 0;
+                for b in self.packet.iter_mut() {
+                    *b = 0;
+                }
+            },
+            0x10 | 0x20 if self.receiving && self.last_lines == 0x30 => {
+                if lines == 0x10 {
+                    self.packet[self.bit/8] |= 1 << (self.bit%8);
+                }
+                self.bit += 1;
+                if self.bit == 128 {
+                    // The stop bit that follows is ignored
+                    self.receiving = false;
+                    self.packet_received();
+                }
+            },
+            0x30 => {
+                // Next controller once the buttons have been read
+                if self.players > 1 && !self.receiving && self.last_lines&0x20 == 0 {
+                    self.player = (self.player + 1) % self.players;
+                }
+            },
+            _ => {},
+        }
+        self.last_lines = lines;
+    }
+
+    // Joypad register read, with both lines high the current controller ID is returned
+    pub fn read_joypad(&self, v: u8) -> u8 {
+        if self.players == 1 {
+            return v;
+        }
+        if v&0x30 == 0x30 {
+            (v&0xF0) | (0x0F - self.player)
+        } else if self.player != 0 {
+            // Only the first controller is connected
+            v | 0x0F
+        } else {
+            v
+        }
+    }
+
+    fn packet_received(&mut self) {
+        if self.command.is_empty() && self.packet[0] & 0x07 == 0 {
+            // Packet count of 0, not a command
+            return;
+        }
+        self.command.extend_from_slice(&self.packet);
+        let count = (self.command[0] & 0x07) as usize;
+        if self.command.len() >= count*16 {
+            let command = self.command.clone();
+            self.command.clear();
+            self.execute(&command);
+        }
+    }
+
+    fn execute(&mut self, data: &[u8]) {
+        let cmd = data[0] >> 3;
+        debug!("SGB command {:02X}", cmd);
+        match cmd {
+            0x00 => self.set_palettes(data, 0, 1),
+            0x01 => self.set_palettes(data, 2, 3),
+            0x02 => self.set_palettes(data, 0, 3),
+            0x03 => self.set_palettes(data, 1, 2),
+            0x04 => self.attr_blk(data),
+            0x05 => self.attr_lin(data),
+            0x06 => self.attr_div(data),
+            0x07 => self.attr_chr(data),
+            0x0A => self.pal_set(data),
+            0x0B => self.transfer = Some(Transfer::Palettes),
+            0x11 => {
+                self.players = match data[1] & 0x03 {
+                    0x01 => 2,
+                    0x03 => 4,
+                    _    => 1,
+                };
+                self.player = 0;
+                println!("SGB {} player(s)", self.players);
+            },
+            0x13 => self.transfer = Some(Transfer::Tiles(data[1] & 0x01)),
+            0x14 => self.transfer = Some(Transfer::Border),
+            0x15 => self.transfer = Some(Transfer::Attributes),
+            0x16 => {
+                self.apply_attr_file(data[1] & 0x3F);
+                if data[1] & 0x40 != 0 {
+                    self.mask = 0;
+                }
+            },
+            0x17 => self.mask = data[1] & 0x03,
+            _ => debug!("SGB command {:02X} not supported", cmd),
+        }
+    }
+
+    fn color(data: &[u8], offset: usize) -> u16 {
+        (data[offset] as u16) | ((data[offset+1] as u16) << 8)
+    }
+
+    // PAL01, PAL23, PAL03, PAL12: shared color 0, then colors 1-3 of both palettes
+    fn set_palettes(&mut self, data: &[u8], a: usize, b: usize) {
+        let c0 = Sgb::color(data, 1);
+        for p in self.palettes.iter_mut() {
+            p[0] = c0;
+        }
+        for i in 0..3 {
+            self.palettes[a][i+1] = Sgb::color(data, 3+i*2);
+            self.palettes[b][i+1] = Sgb::color(data, 9+i*2);
+        }
+    }
+
+    fn attr_blk(&mut self, data: &[u8]) {
+        let count = data[1] as usize;
+        for i in 0..count {
+            let set = 2 + i*6;
+            if set+6 > data.len() {
+                break;
+            }
+            let control = data[set];
+            let inside  = data[set+1] & 0x03;
+            let border  = (data[set+1] >> 2) & 0x03;
+            let outside = (data[set+1] >> 4) & 0x03;
+            let (x1, y1) = (data[set+2] as usize, data[set+3] as usize);
+            let (x2, y2) = (data[set+4] as usize, data[set+5] as usize);
+            // With only inside or outside set, the border takes the same palette
+            let border = match control & 0x07 {
+                0x01 => Some(inside),
+                0x04 => Some(outside),
+                c if c & 0x02 != 0 => Some(border),
+                _ => None,
+            };
+            for y in 0..CELLS_H {
+                for x in 0..CELLS_W {
+                    let on_border = (x == x1 || x == x2) && y >= y1 && y <= y2 ||
+                        (y == y1 || y == y2) && x >= x1 && x <= x2;
+                    let is_inside = x > x1 && x < x2 && y > y1 && y < y2;
+                    let pal = if on_border {
+                        border
+                    } else if is_inside {
+                        if control & 0x01 != 0 { Some(inside) } else { None }
+                    } else if control & 0x04 != 0 {
+                        Some(outside)
+                    } else {
+                        None
+                    };
+                    if let Some(p) = pal {
+                        self.attr_map[x+y*CELLS_W] = p;
+                    }
+                }
+            }
+        }
+    }
+
+    fn attr_lin(&mut self, data: &[u8]) {
+        let count = data[1] as usize;
+        for i in 0..count {
+            if 2+i >= data.len() {
+                break;
+            }
+            let v = data[2+i];
+            let line = (v & 0x1F) as usize;
+            let pal = (v >> 5) & 0x03;
+            if v & 0x80 != 0 {
+                // Horizontal line
+                if line < CELLS_H {
+                    for x in 0..CELLS_W {
+                        self.attr_map[x+line*CELLS_W] = pal;
+                    }
+                }
+            } else if line < CELLS_W {
+                for y in 0..CELLS_H {
+                    self.attr_map[line+y*CELLS_W] = pal;
+                }
+            }
+        }
+    }
+
+    fn attr_div(&mut self, data: &[u8]) {
+        let after  = data[1] & 0x03;
+        let before = (data[1] >> 2) & 0x03;
+        let on     = (data[1] >> 4) & 0x03;
+        let horizontal = data[1] & 0x40 != 0;
+        let at = data[2] as usize;
+        for y in 0..CELLS_H {
+            for x in 0..CELLS_W {
+                let pos = if horizontal { y } else { x };
+                self.attr_map[x+y*CELLS_W] = if pos < at {
+                    before
+                } else if pos == at {
+                    on
+                } else {
+                    after
+                };
+            }
+        }
+    }
+
+    fn attr_chr(&mut self, data: &[u8]) {
+        let mut x = data[1] as usize;
+        let mut y = data[2] as usize;
+        let count = (data[3] as usize) | ((data[4] as usize) << 8);
+        let vertical = data[5] & 0x01 != 0;
+        for i in 0..count.min(CELLS_W*CELLS_H) {
+            let offset = 6 + i/4;
+            if offset >= data.len() || x >= CELLS_W || y >= CELLS_H {
+                break;
+            }
+            self.attr_map[x+y*CELLS_W] = (data[offset] >> (6 - (i%4)*2)) & 0x03;
+            if vertical {
+                y += 1;
+                if y == CELLS_H { y = 0; x += 1; }
+            } else {
+                x += 1;
+                if x == CELLS_W { x = 0; y += 1; }
+            }
+        }
+    }
+
+    // PAL_SET: palettes 0-3 from the system palettes, optionally with an ATTR file
+    fn pal_set(&mut self, data: &[u8]) {
+        for p in 0..4 {
+            let id = (Sgb::color(data, 1+p*2) & 0x01FF) as usize;
+            for c in 0..4 {
+                self.palettes[p][c] = self.system_palettes[id*4+c];
+            }
+        }
+        // Color 0 is shared by all palettes
+        let c0 = self.palettes[0][0];
+        for p in self.palettes.iter_mut() {
+            p[0] = c0;
+        }
+        if data[9] & 0x80 != 0 {
+            self.apply_attr_file(data[9] & 0x3F);
+        }
+        if data[9] & 0x40 != 0 {
+            self.mask = 0;
+        }
+    }
+
+    fn apply_attr_file(&mut self, file: u8) {
+        if file >= 45 {
+            return;
+        }
+        let start = file as usize*ATTR_FILE_SIZE;
+        for i in 0..CELLS_W*CELLS_H {
+            let v = self.attr_files[start + i/4];
+            self.attr_map[i] = (v >> (6 - (i%4)*2)) & 0x03;
+        }
+    }
+
+    pub fn get_pending_transfer(&self) -> Option<Transfer> {
+        self.transfer
+    }
+
+    // Receive the 4KB of a VRAM transfer
+    pub fn vram_transfer(&mut self, data: &[u8]) {
+        match self.transfer {
+            Some(Transfer::Palettes) => {
+                for i in 0..512*4 {
+                    self.system_palettes[i] = Sgb::color(data, i*2);
+                }
+            },
+            Some(Transfer::Tiles(half)) => {
+                let start = half as usize*0x1000;
+                self.border_tiles[start..start+0x1000].copy_from_slice(&data[..0x1000]);
+                self.border_changed = true;
+            },
+            Some(Transfer::Border) => {
+                self.border_map.copy_from_slice(&data[..0x800]);
+                for i in 0..4*16 {
+                    self.border_palettes[i] = Sgb::color(data, 0x800+i*2);
+                }
+                self.border_changed = true;
+            },
+            Some(Transfer::Attributes) => {
+                self.attr_files.copy_from_slice(&data[..45*ATTR_FILE_SIZE]);
+            },
+            None => {},
+        }
+        self.transfer = None;
+    }
+
+    // Screen colors of the 20 cells of a line, RGB888 from the lightest to the darkest
+    pub fn get_line_colors(&self, line: usize) -> Vec<[u32; 4]> {
+        let y = (line/8).min(CELLS_H-1);
+        (0..CELLS_W).map(|x| {
+            let p = &self.palettes[self.attr_map[x+y*CELLS_W] as usize];
+            [rgb555_to_rgb888(p[0]), rgb555_to_rgb888(p[1]), rgb555_to_rgb888(p[2]), rgb555_to_rgb888(p[3])]
+        }).collect()
+    }
+
+    // MASK_EN: 0 none, 1 freeze, 2 black, 3 color 0
+    pub fn get_mask(&self) -> u8 {
+        self.mask
+    }
+    pub fn get_backdrop(&self) -> u32 {
+        rgb555_to_rgb888(self.palettes[0][0])
+    }
+
+    pub fn border_changed(&mut self) -> bool {
+        if self.border_changed {
+            self.border_changed = false;
+            true
+        } else {
+            false
+        }
+    }
+
+    // 256x224 border, None where transparent
+    pub fn render_border(&self) -> Vec<Option<u32>> {
+        let mut buf = vec![None; WIDTH*HEIGHT];
+        for ty in 0..28 {
+            for tx in 0..32 {
+                let entry = (self.border_map[(tx+ty*32)*2] as usize) |
+                    ((self.border_map[(tx+ty*32)*2+1] as usize) << 8);
+                let tile    = entry & 0xFF;
+                let palette = ((entry >> 10) & 0x07) as usize;
+                let xflip   = entry & 0x4000 != 0;
+                let yflip   = entry & 0x8000 != 0;
+                if palette < 4 {
+                    continue;
+                }
+                for y in 0..8 {
+                    let row = if yflip { 7-y } else { y };
+                    let planes = [
+                        self.border_tiles[tile*32 + row*2],
+                        self.border_tiles[tile*32 + row*2 + 1],
+                        self.border_tiles[tile*32 + 16 + row*2],
+                        self.border_tiles[tile*32 + 16 + row*2 + 1],
+                    ];
+                    for x in 0..8 {
+                        let bit = if xflip { x } else { 7-x };
+                        let c = (0..4).fold(0, |c, p| c | (((planes[p] >> bit) & 0x01) << p)) as usize;
+                        if c != 0 {
+                            let color = self.border_palettes[(palette-4)*16 + c];
+                            buf[tx*8+x + (ty*8+y)*WIDTH] = Some(rgb555_to_rgb888(color));
+                        }
+                    }
+                }
+            }
+        }
+        buf
+    }
+}
@@ -0,0 +1,121 @@
+// Symbol files, RGBDS and no$gmb format:
+//
+// ; comment
+// 00:0150 Main
+// 03:4A20 Main.loop
+#![allow(dead_code)]
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader};
+
+pub struct Symbols {
+    // Label at (bank, address)
+    labels: BTreeMap<(u16, u16), String>,
+    addresses: HashMap<String, (u16, u16)>,
+}
+
+// Labels only describe addresses of the same area, ROM0 ones don't cover ROMX
+fn area_start(addr: u16) -> u16 {
+    match addr {
+        0x0000..=0x3FFF => 0x0000,
+        0x4000..=0x7FFF => 0x4000,
+        _ => addr & 0xE000,
+    }
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Symbols {
+            labels: BTreeMap::new(),
+            addresses: HashMap::new(),
+        }
+    }
+
+    pub fn load(path: &str) -> io::Result<Symbols> {
+        let mut symbols = Symbols::new();
+        let file = BufReader::new(File::open(path)?);
+        for line in file.lines() {
+            let line = line?;
+            let line = match line.find(';') {
+                Some(i) => &line[..i],
+                None => &line[..],
+            };
+            let mut parts = line.split_whitespace();
+            let (location, name) = match (parts.next(), parts.next()) {
+                (Some(l), Some(n)) => (l, n),
+                _ => continue,
+            };
+            let mut location = location.splitn(2, ':');
+            let bank = location.next().and_then(|b| u16::from_str_radix(b, 16).ok());
+            let addr = location.next().and_then(|a| u16::from_str_radix(a, 16).ok());
+            if let (Some(bank), Some(addr)) = (bank, addr) {
+                symbols.add(bank, addr, name);
+            }
+        }
+        Ok(symbols)
+    }
+
+    pub fn add(&mut self, bank: u16, addr: u16, name: &str) {
+        // First label at an address wins, the others are still usable by name
+        self.labels.entry((bank, addr)).or_insert_with(|| name.to_string());
+        self.addresses.insert(name.to_string(), (bank, addr));
+    }
+
+    pub fn len(&self) -> usize {
+        self.addresses.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty()
+    }
+
+    // Bank, address and label, sorted
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = (u16, u16, &'a str)> + 'a {
+        self.labels.iter().map(|(k, name)| (k.0, k.1, &name[..]))
+    }
+
+    // Bank and address of a label
+    pub fn lookup(&self, name: &str) -> Option<(u16, u16)> {
+        self.addresses.get(name).cloned()
+    }
+
+    // Label exactly at addr. Bank is None outside of the banked areas.
+    pub fn label(&self, bank: Option<u16>, addr: u16) -> Option<&str> {
+        match bank {
+            Some(b) => self.labels.get(&(b, addr)).map(|s| &s[..]),
+            None => self.labels.iter().find(|&(k, _)| k.1 == addr).map(|(_, s)| &s[..]),
+        }
+    }
+
+    // Closest label at or before addr in the same area, and the offset to it
+    pub fn nearest(&self, bank: Option<u16>, addr: u16) -> Option<(&str, u16)> {
+        let start = area_start(addr);
+        let mut best: Option<(&str, u16)> = None;
+        let banks: Vec<u16> = match bank {
+            Some(b) => vec![b],
+            None => {
+                let mut banks: Vec<u16> = self.labels.keys().map(|k| k.0).collect();
+                banks.dedup();
+                banks
+            },
+        };
+        for b in banks {
+            if let Some((k, name)) = self.labels.range((b, start)..=(b, addr)).next_back() {
+                if best.map_or(true, |(_, offset)| addr - k.1 < offset) {
+                    best = Some((&name[..], addr - k.1));
+                }
+            }
+        }
+        best
+    }
+
+    // "label" or "label+offset"
+    pub fn describe(&self, bank: Option<u16>, addr: u16) -> Option<String> {
+        match self.nearest(bank, addr) {
+            Some((name, 0)) => Some(name.to_string()),
+            Some((name, offset)) => Some(format!("{}+{:X}", name, offset)),
+            None => None,
+        }
+    }
+}
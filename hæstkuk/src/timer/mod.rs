@@ -1,5 +1,6 @@
 #![allow(dead_code)]
 use std::marker::PhantomData;
+use savestate;
 
 // Timer
 #[derive(Clone, Debug, Default)]
@@ -37,6 +38,30 @@ impl<'a> Timer<'a>{
         }
     }
 
+    pub fn save_state(&self, w: &mut savestate::Writer) {
+        w.u8(self.div);
+        w.u8(self.tima);
+        w.u8(self.tma);
+        w.u8(self.tac);
+        w.u64(self.div_cycle);
+        w.u64(self.tima_cycle);
+        w.bool(self.timer_enable);
+        w.u64(self.tima_freq);
+        w.bool(self.interrupt);
+    }
+    pub fn load_state(&mut self, r: &mut savestate::Reader) -> Result<(), String> {
+        self.div          = r.u8()?;
+        self.tima         = r.u8()?;
+        self.tma          = r.u8()?;
+        self.tac          = r.u8()?;
+        self.div_cycle    = r.u64()?;
+        self.tima_cycle   = r.u64()?;
+        self.timer_enable = r.bool()?;
+        self.tima_freq    = r.u64()?;
+        self.interrupt    = r.bool()?;
+        Ok(())
+    }
+
     pub fn update(&mut self, cycles: u64) {
         self.div_cycle+=cycles;
 
@@ -0,0 +1,107 @@
+// Execution trace, one line per instruction before it runs:
+//
+// A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:00:0100 (00 C3 13 02)
+//
+// Close to the gameboy-doctor logs, to diff runs against reference ones.
+#![allow(dead_code)]
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use lr35902::Cpu;
+use debugger::Address;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Condition {
+    // From the start
+    Always,
+    // Once the boot ROM is unmapped
+    AfterBoot,
+    // PC reaches the address
+    Pc(Address),
+    // After this many instructions
+    Instructions(u64),
+}
+
+impl Condition {
+    // "boot", "pc:<addr>" (bank-aware, "pc:03:4A20") or an instruction count
+    pub fn parse(s: &str) -> Result<Condition, String> {
+        if s == "boot" {
+            Ok(Condition::AfterBoot)
+        } else if s.starts_with("pc:") {
+            Ok(Condition::Pc(Address::parse(&s[3..])?))
+        } else {
+            s.parse().map(Condition::Instructions).map_err(|_| format!("invalid trace condition '{}'", s))
+        }
+    }
+
+    fn is_met(&self, cpu: &mut Cpu, count: u64) -> bool {
+        match *self {
+            Condition::Always          => true,
+            Condition::AfterBoot       => !cpu.mem.is_bootrom_enabled(),
+            Condition::Pc(addr)        => addr.matches(cpu, cpu.get_pc()),
+            Condition::Instructions(n) => count >= n,
+        }
+    }
+}
+
+pub struct Trace {
+    out: BufWriter<File>,
+    start: Condition,
+    // None to trace until the end
+    stop: Option<Condition>,
+    started: bool,
+    stopped: bool,
+    // Instructions run since the emulation started
+    count: u64,
+}
+
+impl Trace {
+    pub fn create(path: &str, start: Condition, stop: Option<Condition>) -> io::Result<Trace> {
+        Ok(Trace {
+            out: BufWriter::new(File::create(path)?),
+            start: start,
+            stop: stop,
+            started: false,
+            stopped: false,
+            count: 0,
+        })
+    }
+
+    // Called before each instruction
+    pub fn record(&mut self, cpu: &mut Cpu) {
+        self.count += 1;
+        if self.stopped {
+            return;
+        }
+        if !self.started {
+            if !self.start.is_met(cpu, self.count - 1) {
+                return;
+            }
+            self.started = true;
+        }
+        if self.stop.map_or(false, |c| c.is_met(cpu, self.count - 1)) {
+            self.stopped = true;
+            self.flush();
+            return;
+        }
+        if writeln!(self.out, "{}", format_line(cpu)).is_err() {
+            println!("Can't write the trace, stopping it");
+            self.stopped = true;
+        }
+    }
+
+    pub fn flush(&mut self) {
+        self.out.flush().ok();
+    }
+}
+
+// Registers, banked PC and the 4 bytes at PC
+pub fn format_line(cpu: &mut Cpu) -> String {
+    let pc = cpu.get_pc();
+    let r = |name| cpu.get_reg(name).unwrap();
+    let line = format!("A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:02X}:{:04X}",
+                       r("A"), r("F"), r("B"), r("C"), r("D"), r("E"), r("H"), r("L"), r("SP"),
+                       cpu.mem.get_bank(pc), pc);
+    let bytes: Vec<String> = (0..4).map(|i| format!("{:02X}", cpu.mem.peek8(pc.wrapping_add(i)))).collect();
+    format!("{} ({})", line, bytes.join(" "))
+}